@@ -4,10 +4,8 @@ use std::path::{Path, PathBuf};
 use std::process::Command;
 
 fn main() {
-    let mut crates_to_build: Vec<(String, String)> = Vec::new();
-
     // Always build the wndhok
-    crates_to_build.push(("wndhok".to_string(), "hooker".to_string()));
+    let crates_to_build: Vec<(String, String)> = vec![("wndhok".to_string(), "hooker".to_string())];
 
     if cfg!(unix) {
         // On Unix, we will need to build the helper program to set up CBT hooks in Wine.
@@ -0,0 +1,115 @@
+use std::path::PathBuf;
+
+use clap::{parser::ValueSource, CommandFactory, FromArgMatches, Parser, Subcommand};
+
+use crate::server::listener::ListenAddress;
+
+#[derive(Parser, Debug, Clone)]
+#[command(version, about = "Netease Cloud Music Status Monitor")]
+pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Address to listen on [default: 127.0.0.1]
+    #[arg(long, env = "HOST", value_parser = parse_host)]
+    pub host: Option<String>,
+
+    /// Port to listen on [default: 3574]
+    #[arg(long, env = "PORT")]
+    pub port: Option<u16>,
+
+    /// Address to listen on, `host:port` or `unix:/path/to/socket`, can be given multiple times.
+    /// Replaces --host and --port
+    #[arg(long, value_name = "ADDR")]
+    pub listen: Vec<ListenAddress>,
+
+    /// Serve over HTTPS and WSS, with a self-signed certificate unless one is configured
+    #[arg(long)]
+    pub tls: bool,
+
+    /// Run without the text UI, logging to stdout instead
+    #[arg(long)]
+    pub no_tui: bool,
+
+    /// Run as a service: no text UI, no banner and plain log lines
+    #[arg(long)]
+    pub daemon: bool,
+
+    /// Write the pid to this file while running
+    #[arg(long, value_name = "PATH")]
+    pub pidfile: Option<PathBuf>,
+
+    /// Path to webdb.dat of Netease Cloud Music, instead of finding it automatically
+    #[arg(long, value_name = "PATH")]
+    pub webdb: Option<PathBuf>,
+
+    /// Wine prefix Netease Cloud Music runs in, instead of reading it from the process
+    #[cfg(unix)]
+    #[arg(long, value_name = "PATH")]
+    pub wineprefix: Option<PathBuf>,
+
+    /// Only attach to the process with this pid
+    #[arg(long)]
+    pub pid: Option<u32>,
+
+    /// Log level of the watcher (off, error, warn, info, debug, trace) [default: debug]
+    #[arg(long)]
+    pub log_level: Option<log::LevelFilter>,
+
+    /// Also write logs to this file, rotated as it grows
+    #[arg(long, value_name = "PATH")]
+    pub log_file: Option<PathBuf>,
+
+    /// Path to the configuration file [default: config.toml in the netease-watcher config directory]
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// --host was taken from the environment rather than the command line
+    #[arg(skip)]
+    pub host_from_env: bool,
+
+    /// --port was taken from the environment rather than the command line
+    #[arg(skip)]
+    pub port_from_env: bool,
+}
+
+fn parse_host(host: &str) -> Result<String, String> {
+    ListenAddress::from_host_port(host, 0)?;
+    Ok(host.to_string())
+}
+
+impl Args {
+    /// Parses the command line, keeping track of which values came from the environment.
+    pub fn load() -> Self {
+        Self::load_from(std::env::args_os())
+    }
+
+    pub fn load_from<I, T>(args: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<std::ffi::OsString> + Clone,
+    {
+        let matches = Self::command().get_matches_from(args);
+        let mut args = Self::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+        args.host_from_env = matches.value_source("host") == Some(ValueSource::EnvVariable);
+        args.port_from_env = matches.value_source("port") == Some(ValueSource::EnvVariable);
+        args
+    }
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Generate the self-signed certificate used by --tls and print its path
+    GenCert {
+        /// Replace the existing certificate
+        #[arg(long)]
+        force: bool,
+    },
+    /// Check every step of attaching to Netease Cloud Music and suggest fixes
+    Doctor,
+    /// Check whether a cloudmusic.dll matches the signatures, without running it
+    Scan {
+        /// Path to cloudmusic.dll
+        dll: PathBuf,
+    },
+}
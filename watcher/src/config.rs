@@ -0,0 +1,696 @@
+//! Configuration file, reloaded automatically when it changes.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc},
+    time::Duration,
+};
+
+use lazy_static::lazy_static;
+use notify::{
+    event::{AccessKind, AccessMode},
+    EventKind, RecursiveMode, Watcher,
+};
+use serde::Deserialize;
+use tokio::sync::watch;
+
+use crate::{
+    cli::Args,
+    cover,
+    netease::{FIND_RETRY_SECS, RESCAN_SECS},
+    server::{auth::Scope, listener::ListenAddress},
+};
+
+pub const DEFAULT_HOST: &str = "127.0.0.1";
+pub const DEFAULT_PORT: u16 = 3574;
+
+/// Lower bounds of the intervals, anything lower would keep a core busy.
+const MIN_RETRY_INTERVAL: u64 = 1;
+const MIN_POLL_INTERVAL: u64 = 10;
+
+lazy_static! {
+    static ref CONFIG: watch::Sender<Arc<Config>> = watch::Sender::new(Arc::new(Config::default()));
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub watcher: WatcherConfig,
+    pub log: LogConfig,
+    pub integrations: IntegrationsConfig,
+    pub overlay: OverlayConfig,
+    pub cover: CoverConfig,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+    /// addresses to listen on, replaces host and port if not empty
+    pub listen: Vec<ListenAddress>,
+    /// permissions of the Unix domain sockets
+    pub socket_mode: u32,
+    pub tls: TlsConfig,
+    pub auth: AuthConfig,
+    pub cors: CorsConfig,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain, TLS is enabled if set
+    pub cert: Option<PathBuf>,
+    /// PEM private key of the certificate
+    pub key: Option<PathBuf>,
+    /// generate a self-signed certificate if no certificate is given
+    pub self_signed: bool,
+    /// extra host names of the self-signed certificate
+    pub hostnames: Vec<String>,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// tokens allowed to access the server, anyone can if empty
+    pub tokens: Vec<TokenConfig>,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TokenConfig {
+    pub token: String,
+    #[serde(default)]
+    pub scope: Scope,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    /// origins allowed to read the responses from a browser, `*` for any
+    pub allow_origins: Vec<String>,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct WatcherConfig {
+    /// seconds to wait before trying to find Netease Cloud Music again
+    pub retry_interval: u64,
+    /// seconds between looking for Netease Cloud Music when it's also noticed starting (Linux)
+    pub rescan_interval: u64,
+    /// milliseconds between each read of the playback time
+    pub poll_interval: u64,
+    pub webdb: Option<PathBuf>,
+    pub wineprefix: Option<PathBuf>,
+    pub pid: Option<u32>,
+    /// files of signatures taking precedence over the built-in ones
+    pub signatures: Vec<PathBuf>,
+    /// pid or Wine prefix of the instance the endpoints follow when several are running
+    pub primary: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub level: log::LevelFilter,
+    /// levels of modules by path, e.g. `"netease_watcher::server" = "info"`
+    pub modules: BTreeMap<String, log::LevelFilter>,
+    pub file: LogFileConfig,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogFileConfig {
+    /// logs are also written to this file if set
+    pub path: Option<PathBuf>,
+    pub format: LogFormat,
+    /// size in MiB the file is rotated at
+    pub max_size: u64,
+    /// number of rotated files to keep
+    pub max_files: u32,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// one JSON object per line
+    Json,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct IntegrationsConfig {
+    pub overlay: bool,
+    pub cover: bool,
+    pub palette: bool,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct OverlayConfig {
+    /// directory of custom overlay templates, `<theme>.html`
+    pub templates: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CoverConfig {
+    /// size limit of the cover cache in MiB
+    pub cache_size: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            host: DEFAULT_HOST.to_string(),
+            port: DEFAULT_PORT,
+            listen: Vec::new(),
+            socket_mode: 0o660,
+            tls: TlsConfig::default(),
+            auth: AuthConfig::default(),
+            cors: CorsConfig::default(),
+        }
+    }
+}
+
+impl Default for WatcherConfig {
+    fn default() -> Self {
+        WatcherConfig {
+            retry_interval: FIND_RETRY_SECS,
+            rescan_interval: RESCAN_SECS,
+            poll_interval: 50,
+            webdb: None,
+            wineprefix: None,
+            pid: None,
+            signatures: Vec::new(),
+            primary: None,
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: log::LevelFilter::Debug,
+            modules: BTreeMap::new(),
+            file: LogFileConfig::default(),
+        }
+    }
+}
+
+impl Default for LogFileConfig {
+    fn default() -> Self {
+        LogFileConfig {
+            path: None,
+            format: LogFormat::Text,
+            max_size: 10,
+            max_files: 5,
+        }
+    }
+}
+
+impl Default for IntegrationsConfig {
+    fn default() -> Self {
+        IntegrationsConfig {
+            overlay: true,
+            cover: true,
+            palette: true,
+        }
+    }
+}
+
+impl Default for CoverConfig {
+    fn default() -> Self {
+        CoverConfig {
+            cache_size: cover::DEFAULT_MAX_SIZE / 1024 / 1024,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn listen_addresses(&self) -> Result<Vec<ListenAddress>, String> {
+        if !self.listen.is_empty() {
+            return Ok(self.listen.clone());
+        }
+        Ok(vec![ListenAddress::from_host_port(&self.host, self.port)?])
+    }
+}
+
+impl WatcherConfig {
+    pub fn retry_interval(&self) -> Duration {
+        Duration::from_secs(self.retry_interval)
+    }
+
+    #[cfg(unix)]
+    pub fn rescan_interval(&self) -> Duration {
+        Duration::from_secs(self.rescan_interval)
+    }
+
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_millis(self.poll_interval)
+    }
+}
+
+impl Config {
+    /// Command-line arguments take precedence over the file.
+    ///
+    /// `HOST` and `PORT` from the environment don't replace `server.listen` of the file,
+    /// only --host and --port given on the command line do.
+    fn apply_args(&mut self, args: &Args) {
+        if let Some(host) = &args.host {
+            self.server.host = host.clone();
+            if !args.host_from_env {
+                self.server.listen.clear();
+            }
+        }
+        if let Some(port) = args.port {
+            self.server.port = port;
+            if !args.port_from_env {
+                self.server.listen.clear();
+            }
+        }
+        if !args.listen.is_empty() {
+            self.server.listen = args.listen.clone();
+        }
+        if let Some(webdb) = &args.webdb {
+            self.watcher.webdb = Some(webdb.clone());
+        }
+        #[cfg(unix)]
+        if let Some(wineprefix) = &args.wineprefix {
+            self.watcher.wineprefix = Some(wineprefix.clone());
+        }
+        if let Some(pid) = args.pid {
+            self.watcher.pid = Some(pid);
+        }
+        if args.tls {
+            self.server.tls.self_signed = true;
+        }
+        if let Some(level) = args.log_level {
+            self.log.level = level;
+        }
+        if let Some(path) = &args.log_file {
+            self.log.file.path = Some(path.clone());
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        self.server.listen_addresses()?;
+        if self.server.auth.tokens.iter().any(|x| x.token.is_empty()) {
+            return Err("server.auth.tokens must not contain empty tokens".to_string());
+        }
+        if self.log.file.max_size == 0 {
+            return Err("log.file.max_size must be at least 1 MiB".to_string());
+        }
+        if self.watcher.retry_interval < MIN_RETRY_INTERVAL {
+            return Err(format!(
+                "watcher.retry_interval must be at least {} second",
+                MIN_RETRY_INTERVAL
+            ));
+        }
+        if self.watcher.rescan_interval < MIN_RETRY_INTERVAL {
+            return Err(format!(
+                "watcher.rescan_interval must be at least {} second",
+                MIN_RETRY_INTERVAL
+            ));
+        }
+        if self.watcher.poll_interval < MIN_POLL_INTERVAL {
+            return Err(format!(
+                "watcher.poll_interval must be at least {} milliseconds",
+                MIN_POLL_INTERVAL
+            ));
+        }
+        Ok(())
+    }
+
+    /// Names of the options that differ and are only read on startup.
+    fn restart_required_changes(&self, other: &Config) -> Vec<&'static str> {
+        let mut changes = Vec::new();
+        let mut check = |name, changed| {
+            if changed {
+                changes.push(name);
+            }
+        };
+        check("server.host", self.server.host != other.server.host);
+        check("server.port", self.server.port != other.server.port);
+        check("server.listen", self.server.listen != other.server.listen);
+        check(
+            "server.socket_mode",
+            self.server.socket_mode != other.server.socket_mode,
+        );
+        check("server.tls", self.server.tls != other.server.tls);
+        check("log.file", self.log.file != other.log.file);
+        check("watcher.webdb", self.watcher.webdb != other.watcher.webdb);
+        check(
+            "watcher.wineprefix",
+            self.watcher.wineprefix != other.watcher.wineprefix,
+        );
+        check("watcher.pid", self.watcher.pid != other.watcher.pid);
+        check(
+            "watcher.signatures",
+            self.watcher.signatures != other.watcher.signatures,
+        );
+        check(
+            "watcher.primary",
+            self.watcher.primary != other.watcher.primary,
+        );
+        check(
+            "cover.cache_size",
+            self.cover.cache_size != other.cover.cache_size,
+        );
+        changes
+    }
+}
+
+pub fn default_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|x| x.join("netease-watcher").join("config.toml"))
+}
+
+/// The configuration currently in effect.
+pub fn get() -> Arc<Config> {
+    CONFIG.borrow().clone()
+}
+
+/// Receives the configuration whenever it's reloaded.
+pub fn subscribe() -> watch::Receiver<Arc<Config>> {
+    CONFIG.subscribe()
+}
+
+fn parse(content: &str, args: &Args) -> Result<Config, String> {
+    let mut config: Config = toml::from_str(content).map_err(|x| x.to_string())?;
+    config.apply_args(args);
+    config.validate()?;
+    Ok(config)
+}
+
+/// A missing file is only fine at the default path, a path given with --config must exist.
+fn load(path: Option<&Path>, args: &Args) -> Result<Config, String> {
+    let content = match path {
+        Some(path) => match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound && args.config.is_none() => {
+                String::new()
+            }
+            Err(err) => return Err(err.to_string()),
+        },
+        None => String::new(),
+    };
+    parse(&content, args)
+}
+
+/// Loads the configuration for the first time, falls back to the defaults if the file at the
+/// default path is invalid. A file given with --config must load, it might hold the tokens.
+pub fn init(args: &Args) -> Result<Option<PathBuf>, String> {
+    let path = args.config.clone().or_else(default_path);
+    let config = match load(path.as_deref(), args) {
+        Ok(config) => config,
+        Err(err) if args.config.is_some() => {
+            return Err(format!(
+                "Unable to load the configuration file {}: {}",
+                path.as_deref().unwrap_or(Path::new("")).display(),
+                err
+            ));
+        }
+        Err(err) => {
+            // the logger is not set up yet, as it depends on the config.
+            eprintln!(
+                "Unable to load the configuration file {}, using the defaults: {}",
+                path.as_deref().unwrap_or(Path::new("")).display(),
+                err
+            );
+            let mut config = Config::default();
+            config.apply_args(args);
+            config
+        }
+    };
+    CONFIG.send_replace(Arc::new(config));
+    Ok(path)
+}
+
+/// Watches the configuration file and reloads it on changes.
+///
+/// The returned watcher must be kept alive.
+pub fn watch(path: &Path, args: Args) -> notify::Result<notify::RecommendedWatcher> {
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    // editors often replace the file instead of modifying it, so watch the directory instead.
+    let dir = path.parent().unwrap_or(Path::new("."));
+    std::fs::create_dir_all(dir)?;
+    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+
+    let path = path.to_path_buf();
+    std::thread::spawn(move || {
+        while let Ok(event) = rx.recv() {
+            let Ok(event) = event else {
+                continue;
+            };
+            // reading the file ourselves generates access events, ignore them.
+            if !matches!(
+                event.kind,
+                EventKind::Create(_)
+                    | EventKind::Modify(_)
+                    | EventKind::Access(AccessKind::Close(AccessMode::Write))
+            ) || !event
+                .paths
+                .iter()
+                .any(|x| x.file_name() == path.file_name())
+            {
+                continue;
+            }
+            // wait for the writes to settle
+            std::thread::sleep(Duration::from_millis(100));
+            while rx.try_recv().is_ok() {}
+
+            let config = match load(Some(&path), &args) {
+                Ok(config) => config,
+                Err(err) => {
+                    log::error!(
+                        "Unable to reload the configuration, keeping the current one: {}",
+                        err
+                    );
+                    continue;
+                }
+            };
+            let current = get();
+            if *current == config {
+                continue;
+            }
+            let restart_required = current.restart_required_changes(&config);
+            if !restart_required.is_empty() {
+                log::warn!(
+                    "The following options only take effect after a restart: {}",
+                    restart_required.join(", ")
+                );
+            }
+            log::info!("Configuration reloaded.");
+            CONFIG.send_replace(Arc::new(config));
+        }
+    });
+
+    Ok(watcher)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(argv: &[&str]) -> Args {
+        let mut args =
+            Args::load_from(std::iter::once("netease-watcher").chain(argv.iter().copied()));
+        // don't let HOST and PORT of the test environment leak in
+        if args.host_from_env {
+            args.host = None;
+        }
+        if args.port_from_env {
+            args.port = None;
+        }
+        args
+    }
+
+    #[test]
+    fn parses_defaults() {
+        assert_eq!(parse("", &args(&[])).unwrap(), Config::default());
+    }
+
+    #[test]
+    fn parses_file() {
+        let config = parse(
+            r#"
+            [server]
+            port = 1234
+            listen = ["127.0.0.1:1234", "[::1]:1234", "unix:/tmp/watcher.sock"]
+            socket_mode = 0o660
+
+            [server.tls]
+            self_signed = true
+
+            [server.auth]
+            tokens = [{ token = "a" }, { token = "b", scope = "admin" }]
+
+            [server.cors]
+            allow_origins = ["https://example.com"]
+
+            [watcher]
+            poll_interval = 100
+
+            [log]
+            level = "info"
+            modules = { "netease_watcher::server" = "warn", reqwest = "debug" }
+
+            [log.file]
+            path = "/tmp/watcher.log"
+            format = "json"
+
+            [integrations]
+            palette = false
+            "#,
+            &args(&[]),
+        )
+        .unwrap();
+        assert_eq!(config.server.port, 1234);
+        assert_eq!(
+            config.server.listen,
+            vec![
+                ListenAddress::Tcp("127.0.0.1:1234".to_string()),
+                ListenAddress::Tcp("[::1]:1234".to_string()),
+                ListenAddress::Unix(PathBuf::from("/tmp/watcher.sock")),
+            ]
+        );
+        assert_eq!(config.server.socket_mode, 0o660);
+        assert!(config.server.tls.self_signed);
+        assert_eq!(config.server.auth.tokens[0].scope, Scope::Read);
+        assert_eq!(config.server.auth.tokens[1].scope, Scope::Admin);
+        assert_eq!(
+            config.server.cors.allow_origins,
+            vec!["https://example.com"]
+        );
+        assert_eq!(config.watcher.poll_interval, 100);
+        assert_eq!(config.watcher.retry_interval, FIND_RETRY_SECS);
+        assert_eq!(config.log.level, log::LevelFilter::Info);
+        assert_eq!(
+            config.log.modules.get("netease_watcher::server"),
+            Some(&log::LevelFilter::Warn)
+        );
+        assert_eq!(
+            config.log.file.path,
+            Some(PathBuf::from("/tmp/watcher.log"))
+        );
+        assert_eq!(config.log.file.format, LogFormat::Json);
+        assert_eq!(config.log.file.max_files, 5);
+        assert!(!config.integrations.palette);
+        assert!(config.integrations.cover);
+    }
+
+    #[test]
+    fn rejects_invalid_files() {
+        for content in [
+            "unknown = 1",
+            "[server]\nunknown = 1",
+            "[server.tls]\ncertificate = \"a.pem\"",
+            "[server]\nport = \"3574\"",
+            "[server]\nlisten = [\"localhost\"]",
+            "[log]\nlevel = \"verbose\"",
+            "[log.file]\nformat = \"xml\"",
+            "[log.file]\nmax_size = 0",
+            "[watcher]\npoll_interval = 0",
+            "[server.auth]\ntokens = [{ token = \"\" }]",
+            "[server.auth]\ntokens = [{ token = \"a\", scope = \"write\" }]",
+            "[watcher]\nretry_interval = 0",
+            "[watcher]\nrescan_interval = 0",
+        ] {
+            assert!(parse(content, &args(&[])).is_err(), "{}", content);
+        }
+    }
+
+    #[test]
+    fn args_take_precedence() {
+        let file = "[server]\nhost = \"0.0.0.0\"\nport = 1\nlisten = [\"127.0.0.1:2\"]";
+        let from_env = |host: &str| {
+            let mut args = args(&[]);
+            args.host = Some(host.to_string());
+            args.host_from_env = true;
+            args
+        };
+
+        // (args, host, port, listen)
+        let cases = [
+            (args(&[]), "0.0.0.0", 1, vec!["127.0.0.1:2"]),
+            (args(&["--host", "::1"]), "::1", 1, vec!["[::1]:1"]),
+            (args(&["--port", "3"]), "0.0.0.0", 3, vec!["0.0.0.0:3"]),
+            (
+                args(&["--listen", "127.0.0.1:4", "--listen", "[::1]:4"]),
+                "0.0.0.0",
+                1,
+                vec!["127.0.0.1:4", "[::1]:4"],
+            ),
+            (from_env("10.0.0.1"), "10.0.0.1", 1, vec!["127.0.0.1:2"]),
+        ];
+        for (args, host, port, listen) in cases {
+            let config = parse(file, &args).unwrap();
+            assert_eq!(config.server.host, host, "{:?}", args);
+            assert_eq!(config.server.port, port, "{:?}", args);
+            let listen: Vec<_> = listen.into_iter().map(|x| x.parse().unwrap()).collect();
+            assert_eq!(
+                config.server.listen_addresses().unwrap(),
+                listen,
+                "{:?}",
+                args
+            );
+        }
+
+        let config = parse("[log]\nlevel = \"info\"", &args(&["--log-level", "trace"])).unwrap();
+        assert_eq!(config.log.level, log::LevelFilter::Trace);
+    }
+
+    #[test]
+    fn requires_given_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("config.toml");
+        let missing_str = missing.to_str().unwrap();
+
+        assert_eq!(load(Some(&missing), &args(&[])), Ok(Config::default()));
+        assert!(load(Some(&missing), &args(&["--config", missing_str])).is_err());
+        assert!(init(&args(&["--config", missing_str])).is_err());
+    }
+
+    #[test]
+    fn lists_restart_required_changes() {
+        let current = Config::default();
+        let cases = [
+            ("", vec![]),
+            ("[log]\nlevel = \"warn\"", vec![]),
+            ("[log.modules]\nreqwest = \"trace\"", vec![]),
+            ("[log.file]\npath = \"a.log\"", vec!["log.file"]),
+            ("[watcher]\npoll_interval = 20", vec![]),
+            (
+                "[watcher]\nsignatures = [\"a.toml\"]",
+                vec!["watcher.signatures"],
+            ),
+            ("[watcher]\nprimary = \"1234\"", vec!["watcher.primary"]),
+            ("[integrations]\noverlay = false", vec![]),
+            ("[server.auth]\ntokens = [{ token = \"a\" }]", vec![]),
+            ("[server]\nport = 1", vec!["server.port"]),
+            (
+                "[server]\nhost = \"::1\"\nsocket_mode = 0o600",
+                vec!["server.host", "server.socket_mode"],
+            ),
+            ("[server.tls]\nself_signed = true", vec!["server.tls"]),
+            (
+                "[watcher]\npid = 1\n[cover]\ncache_size = 1",
+                vec!["watcher.pid", "cover.cache_size"],
+            ),
+        ];
+        for (content, expected) in cases {
+            let config = parse(content, &args(&[])).unwrap();
+            assert_eq!(
+                current.restart_required_changes(&config),
+                expected,
+                "{}",
+                content
+            );
+        }
+    }
+}
@@ -0,0 +1,503 @@
+//! Album art fetching and on-disk caching.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::{util::to_hex, Music};
+
+pub const DEFAULT_MAX_SIZE: u64 = 64 * 1024 * 1024;
+
+/// The largest size we allow to be requested via `?param=`.
+const MAX_DIMENSION: u32 = 4096;
+
+/// Art is a few hundred KiB at most, anything much larger isn't worth keeping.
+const MAX_DOWNLOAD: usize = 8 * 1024 * 1024;
+
+/// Tracks whose art can be requested by id, the least recently seen ones are forgotten first.
+const MAX_URLS: usize = 1024;
+
+pub struct Cover {
+    pub data: Vec<u8>,
+    pub content_type: &'static str,
+    pub etag: String,
+}
+
+#[derive(Debug)]
+pub enum CoverError {
+    /// We don't know where the art of the track is.
+    NotFound,
+    Upstream(String),
+    Io(std::io::Error),
+}
+
+impl From<std::io::Error> for CoverError {
+    fn from(value: std::io::Error) -> Self {
+        CoverError::Io(value)
+    }
+}
+
+struct CacheEntry {
+    size: u64,
+    last_access: SystemTime,
+}
+
+struct UrlEntry {
+    url: String,
+    last_access: SystemTime,
+}
+
+pub struct CoverCache {
+    dir: PathBuf,
+    max_size: u64,
+    client: reqwest::Client,
+    /// file name -> entry
+    entries: Mutex<HashMap<String, CacheEntry>>,
+    /// track id -> thumbnail url of the tracks we have seen lately
+    urls: Mutex<HashMap<i64, UrlEntry>>,
+    /// per file lock to avoid fetching the same art multiple times at once
+    fetching: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+/// Parses NetEase's resizing parameter, `200y200`, also accepts `200x200`.
+pub fn parse_size_param(param: &str) -> Option<(u32, u32)> {
+    let (w, h) = param.split_once(['y', 'x'])?;
+    let (w, h) = (w.parse().ok()?, h.parse().ok()?);
+    if w == 0 || h == 0 || w > MAX_DIMENSION || h > MAX_DIMENSION {
+        return None;
+    }
+    Some((w, h))
+}
+
+/// Only NetEase's servers resize with `?param=`, other art is fetched as it is.
+fn sized_url(url: &str, size: Option<(u32, u32)>) -> String {
+    let Some((w, h)) = size else {
+        return url.to_string();
+    };
+    match reqwest::Url::parse(url) {
+        Ok(parsed)
+            if parsed.query().is_none()
+                && parsed
+                    .host_str()
+                    .is_some_and(|x| x == "music.126.net" || x.ends_with(".music.126.net")) =>
+        {
+            format!("{}?param={}y{}", url, w, h)
+        }
+        _ => url.to_string(),
+    }
+}
+
+fn detect_content_type(data: &[u8]) -> &'static str {
+    if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        "image/jpeg"
+    } else if data.starts_with(b"\x89PNG") {
+        "image/png"
+    } else if data.starts_with(b"GIF8") {
+        "image/gif"
+    } else if data.len() >= 12 && &data[0..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        "image/webp"
+    } else {
+        "application/octet-stream"
+    }
+}
+
+impl CoverCache {
+    pub fn new(dir: PathBuf, max_size: u64) -> Self {
+        if let Err(err) = fs::create_dir_all(&dir) {
+            log::warn!(
+                "Unable to create cover cache directory {}: {}",
+                dir.display(),
+                err
+            );
+        }
+        let mut entries = HashMap::new();
+        if let Ok(read_dir) = fs::read_dir(&dir) {
+            for entry in read_dir.filter_map(|x| x.ok()) {
+                let Ok(metadata) = entry.metadata() else {
+                    continue;
+                };
+                if !metadata.is_file() {
+                    continue;
+                }
+                entries.insert(
+                    entry.file_name().to_string_lossy().to_string(),
+                    CacheEntry {
+                        size: metadata.len(),
+                        // we touch the files on access, so mtime is the last access time.
+                        last_access: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                    },
+                );
+            }
+        }
+        CoverCache {
+            dir,
+            max_size,
+            client: reqwest::Client::new(),
+            entries: Mutex::new(entries),
+            urls: Mutex::new(HashMap::new()),
+            fetching: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn default_dir() -> PathBuf {
+        dirs::cache_dir()
+            .unwrap_or_else(std::env::temp_dir)
+            .join("netease-watcher")
+            .join("covers")
+    }
+
+    /// Remembers where the art of the track is, so it can be requested by id later.
+    pub fn remember(&self, music: &Music) {
+        // native clients might not tell
+        if music.thumbnail.is_empty() {
+            return;
+        }
+        let mut urls = self.urls.lock().unwrap();
+        urls.insert(
+            music.id,
+            UrlEntry {
+                url: music.thumbnail.clone(),
+                last_access: SystemTime::now(),
+            },
+        );
+        if urls.len() > MAX_URLS {
+            let oldest = urls
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_access)
+                .map(|(id, _)| *id);
+            if let Some(id) = oldest {
+                urls.remove(&id);
+            }
+        }
+    }
+
+    fn url_of(&self, id: i64) -> Option<String> {
+        let mut urls = self.urls.lock().unwrap();
+        let entry = urls.get_mut(&id)?;
+        entry.last_access = SystemTime::now();
+        Some(entry.url.clone())
+    }
+
+    pub async fn get(&self, id: i64, size: Option<(u32, u32)>) -> Result<Cover, CoverError> {
+        let file_name = match size {
+            Some((w, h)) => format!("{}_{}y{}", id, w, h),
+            None => id.to_string(),
+        };
+
+        let lock = self
+            .fetching
+            .lock()
+            .unwrap()
+            .entry(file_name.clone())
+            .or_default()
+            .clone();
+        let result = {
+            let _guard = lock.lock().await;
+            match self.read_cached(&file_name).await {
+                Some(data) => Ok(data),
+                None => self.fetch(id, size, &file_name).await,
+            }
+        };
+        {
+            let mut fetching = self.fetching.lock().unwrap();
+            // the map and the local variable are the only holders, nobody is waiting for it.
+            if Arc::strong_count(&lock) == 2 {
+                fetching.remove(&file_name);
+            }
+        }
+
+        let data = result?;
+        Ok(Cover {
+            content_type: detect_content_type(&data),
+            etag: format!("\"{}\"", to_hex(&Sha256::digest(&data))),
+            data,
+        })
+    }
+
+    async fn read_cached(&self, file_name: &str) -> Option<Vec<u8>> {
+        if !self.entries.lock().unwrap().contains_key(file_name) {
+            return None;
+        }
+        let path = self.dir.join(file_name);
+        let Ok(data) = tokio::fs::read(&path).await else {
+            // removed by someone else
+            self.entries.lock().unwrap().remove(file_name);
+            return None;
+        };
+        let now = SystemTime::now();
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(file_name) {
+            entry.last_access = now;
+        }
+        if let Ok(file) = tokio::fs::File::options().write(true).open(&path).await {
+            let _ = file.into_std().await.set_modified(now);
+        }
+        Some(data)
+    }
+
+    async fn fetch(
+        &self,
+        id: i64,
+        size: Option<(u32, u32)>,
+        file_name: &str,
+    ) -> Result<Vec<u8>, CoverError> {
+        let Some(url) = self.url_of(id) else {
+            return Err(CoverError::NotFound);
+        };
+        // native clients may point at art on the disk, which needs no caching
+        if let Some(path) = reqwest::Url::parse(&url)
+            .ok()
+            .filter(|x| x.scheme() == "file")
+            .and_then(|x| x.to_file_path().ok())
+        {
+            if tokio::fs::metadata(&path).await?.len() > MAX_DOWNLOAD as u64 {
+                return Err(CoverError::Upstream(format!(
+                    "the cover is larger than {} bytes",
+                    MAX_DOWNLOAD
+                )));
+            }
+            let data = tokio::fs::read(&path).await?;
+            // anything on the bus may claim to be a player, don't serve other files
+            if detect_content_type(&data) == "application/octet-stream" {
+                return Err(CoverError::Upstream(format!(
+                    "{} is not an image",
+                    path.display()
+                )));
+            }
+            return Ok(data);
+        }
+        let url = sized_url(&url, size);
+
+        log::debug!("Fetching cover {}", url);
+        let mut response = self
+            .client
+            .get(&url)
+            .send()
+            .await
+            .and_then(|x| x.error_for_status())
+            .map_err(|x| CoverError::Upstream(x.to_string()))?;
+        let mut data = Vec::new();
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .map_err(|x| CoverError::Upstream(x.to_string()))?
+        {
+            if data.len() + chunk.len() > MAX_DOWNLOAD {
+                return Err(CoverError::Upstream(format!(
+                    "the cover is larger than {} bytes",
+                    MAX_DOWNLOAD
+                )));
+            }
+            data.extend_from_slice(&chunk);
+        }
+
+        tokio::fs::write(self.dir.join(file_name), &data).await?;
+        self.entries.lock().unwrap().insert(
+            file_name.to_string(),
+            CacheEntry {
+                size: data.len() as u64,
+                last_access: SystemTime::now(),
+            },
+        );
+        self.evict();
+
+        Ok(data)
+    }
+
+    /// Removes the least recently used files until the cache fits in the size limit.
+    fn evict(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        let mut total: u64 = entries.values().map(|x| x.size).sum();
+        if total <= self.max_size {
+            return;
+        }
+        let mut by_access: Vec<_> = entries
+            .iter()
+            .map(|(name, entry)| (entry.last_access, name.clone()))
+            .collect();
+        by_access.sort();
+        for (_, name) in by_access {
+            if total <= self.max_size {
+                break;
+            }
+            if let Err(err) = fs::remove_file(self.dir.join(&name)) {
+                if err.kind() != std::io::ErrorKind::NotFound {
+                    log::warn!("Unable to remove cached cover {}: {}", name, err);
+                    continue;
+                }
+            }
+            if let Some(entry) = entries.remove(&name) {
+                total -= entry.size;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn parses_size_param() {
+        assert_eq!(parse_size_param("200y200"), Some((200, 200)));
+        assert_eq!(parse_size_param("64x128"), Some((64, 128)));
+        assert_eq!(parse_size_param("4096y4096"), Some((4096, 4096)));
+        assert_eq!(parse_size_param("0y200"), None);
+        assert_eq!(parse_size_param("4097y200"), None);
+        assert_eq!(parse_size_param("93.75y93.75"), None);
+        assert_eq!(parse_size_param("200"), None);
+        assert_eq!(parse_size_param("-1y200"), None);
+        assert_eq!(parse_size_param(""), None);
+    }
+
+    #[test]
+    fn only_resizes_netease_art() {
+        let size = Some((64, 64));
+        assert_eq!(
+            sized_url("https://p1.music.126.net/a/1.jpg", size),
+            "https://p1.music.126.net/a/1.jpg?param=64y64"
+        );
+        assert_eq!(
+            sized_url("https://p1.music.126.net/a/1.jpg", None),
+            "https://p1.music.126.net/a/1.jpg"
+        );
+        assert_eq!(
+            sized_url("https://p1.music.126.net/a/1.jpg?param=512y512", size),
+            "https://p1.music.126.net/a/1.jpg?param=512y512"
+        );
+        assert_eq!(
+            sized_url("https://example.com/1.jpg", size),
+            "https://example.com/1.jpg"
+        );
+        assert_eq!(sized_url("file:///tmp/1.jpg", size), "file:///tmp/1.jpg");
+    }
+
+    #[test]
+    fn detects_content_type() {
+        assert_eq!(detect_content_type(&[0xFF, 0xD8, 0xFF, 0xE0]), "image/jpeg");
+        assert_eq!(detect_content_type(b"\x89PNG\r\n\x1a\n"), "image/png");
+        assert_eq!(detect_content_type(b"GIF89a"), "image/gif");
+        assert_eq!(detect_content_type(b"RIFF\0\0\0\0WEBPVP8 "), "image/webp");
+        assert_eq!(
+            detect_content_type(b"RIFF\0\0\0\0WAVE"),
+            "application/octet-stream"
+        );
+        assert_eq!(detect_content_type(b""), "application/octet-stream");
+    }
+
+    fn write_cached(dir: &std::path::Path, name: &str, size: usize, age_secs: u64) {
+        let path = dir.join(name);
+        fs::write(&path, vec![0u8; size]).unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(age_secs))
+            .unwrap();
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let dir = tempfile::tempdir().unwrap();
+        write_cached(dir.path(), "1", 4, 300);
+        write_cached(dir.path(), "2", 4, 200);
+        write_cached(dir.path(), "3", 4, 100);
+
+        let cache = CoverCache::new(dir.path().to_path_buf(), 8);
+        cache.evict();
+
+        assert!(!dir.path().join("1").exists());
+        assert!(dir.path().join("2").exists());
+        assert!(dir.path().join("3").exists());
+        assert_eq!(cache.entries.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn keeps_everything_within_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        write_cached(dir.path(), "1", 4, 200);
+        write_cached(dir.path(), "2", 4, 100);
+
+        let cache = CoverCache::new(dir.path().to_path_buf(), 8);
+        cache.evict();
+
+        assert!(dir.path().join("1").exists());
+        assert!(dir.path().join("2").exists());
+    }
+
+    #[tokio::test]
+    async fn serves_cached_cover_without_fetching() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("42_64y64"), b"\x89PNG data").unwrap();
+
+        let cache = CoverCache::new(dir.path().to_path_buf(), DEFAULT_MAX_SIZE);
+        let cover = cache.get(42, Some((64, 64))).await.unwrap();
+        assert_eq!(cover.data, b"\x89PNG data");
+        assert_eq!(cover.content_type, "image/png");
+        assert!(cover.etag.starts_with('"') && cover.etag.ends_with('"'));
+
+        // never seen the track, and not cached in this size
+        assert!(matches!(
+            cache.get(42, None).await,
+            Err(CoverError::NotFound)
+        ));
+    }
+
+    #[test]
+    fn forgets_least_recently_seen_urls() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = CoverCache::new(dir.path().to_path_buf(), DEFAULT_MAX_SIZE);
+        let music = |id| Music {
+            id,
+            aliases: None,
+            thumbnail: format!("https://p1.music.126.net/{}.jpg", id),
+            album: String::new(),
+            artists: Vec::new(),
+            duration: 0,
+            name: String::new(),
+        };
+        for id in 0..MAX_URLS as i64 {
+            cache.remember(&music(id));
+        }
+        // seen again, so it's kept over the next oldest
+        assert!(cache.url_of(0).is_some());
+        cache.remember(&music(MAX_URLS as i64));
+
+        let urls = cache.urls.lock().unwrap();
+        assert_eq!(urls.len(), MAX_URLS);
+        assert!(urls.contains_key(&0));
+        assert!(!urls.contains_key(&1));
+    }
+
+    #[tokio::test]
+    async fn reads_local_art() {
+        let dir = tempfile::tempdir().unwrap();
+        let art = dir.path().join("art 1.png");
+        fs::write(&art, b"\x89PNG local").unwrap();
+        let cache = CoverCache::new(dir.path().join("covers"), DEFAULT_MAX_SIZE);
+        cache.urls.lock().unwrap().insert(
+            7,
+            UrlEntry {
+                url: reqwest::Url::from_file_path(&art).unwrap().to_string(),
+                last_access: SystemTime::now(),
+            },
+        );
+
+        let cover = cache.get(7, Some((64, 64))).await.unwrap();
+        assert_eq!(cover.data, b"\x89PNG local");
+        assert_eq!(cover.content_type, "image/png");
+
+        fs::write(&art, b"secret").unwrap();
+        assert!(matches!(
+            cache.get(7, None).await,
+            Err(CoverError::Upstream(_))
+        ));
+    }
+}
@@ -0,0 +1,215 @@
+//! Running as a service: signals, systemd notifications and the pidfile.
+
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Waits for SIGINT or SIGTERM, or Ctrl-C on Windows.
+pub async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let (Ok(mut interrupt), Ok(mut terminate)) = (
+            signal(SignalKind::interrupt()),
+            signal(SignalKind::terminate()),
+        ) else {
+            log::error!("Unable to listen for signals.");
+            return std::future::pending().await;
+        };
+        tokio::select! {
+            _ = interrupt.recv() => log::info!("Received SIGINT, shutting down."),
+            _ = terminate.recv() => log::info!("Received SIGTERM, shutting down."),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        if tokio::signal::ctrl_c().await.is_err() {
+            log::error!("Unable to listen for Ctrl-C.");
+            return std::future::pending().await;
+        }
+        log::info!("Received Ctrl-C, shutting down.");
+    }
+}
+
+/// Sends a state to systemd, does nothing when not started by systemd with `Type=notify`.
+///
+/// See sd_notify(3).
+pub fn notify(state: &str) {
+    #[cfg(unix)]
+    {
+        use std::os::unix::{ffi::OsStrExt, net::UnixDatagram};
+
+        let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+            return;
+        };
+        let result =
+            UnixDatagram::unbound().and_then(|socket| match path.as_bytes().strip_prefix(b"@") {
+                #[cfg(target_os = "linux")]
+                Some(name) => {
+                    use std::os::linux::net::SocketAddrExt;
+
+                    let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+                    socket.send_to_addr(state.as_bytes(), &addr)
+                }
+                #[cfg(not(target_os = "linux"))]
+                Some(_) => Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    "Abstract sockets are not supported on this platform",
+                )),
+                None => socket.send_to(state.as_bytes(), &path),
+            });
+        if let Err(err) = result {
+            log::warn!("Unable to notify systemd: {}", err);
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = state;
+    }
+}
+
+/// The interval systemd expects the watchdog to be pinged in, if it's enabled for us.
+fn watchdog_interval() -> Option<Duration> {
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(std::process::id()) {
+            return None;
+        }
+    }
+    // ping twice as often as required, as recommended.
+    Some(Duration::from_micros(usec) / 2)
+}
+
+/// Pings the systemd watchdog for as long as the runtime is responsive.
+pub fn spawn_watchdog() {
+    let Some(interval) = watchdog_interval() else {
+        return;
+    };
+    log::debug!("Pinging systemd watchdog every {:?}", interval);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            notify("WATCHDOG=1");
+        }
+    });
+}
+
+/// A file containing our pid, removed when dropped.
+pub struct Pidfile(PathBuf);
+
+impl Pidfile {
+    /// Writes the pidfile, unless it belongs to another running instance.
+    pub fn create(path: &Path) -> std::io::Result<Self> {
+        if let Some(pid) = std::fs::read_to_string(path)
+            .ok()
+            .and_then(|x| x.trim().parse::<u32>().ok())
+        {
+            if pid != std::process::id() && is_running(pid) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AlreadyExists,
+                    format!("{} belongs to running process {}", path.display(), pid),
+                ));
+            }
+        }
+        if let Some(dir) = path.parent().filter(|x| !x.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, format!("{}\n", std::process::id()))?;
+        Ok(Pidfile(path.to_path_buf()))
+    }
+}
+
+impl Drop for Pidfile {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.0) {
+            log::warn!("Unable to remove pidfile {}: {}", self.0.display(), err);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    procfs::process::Process::new(pid as i32).is_ok()
+}
+
+#[cfg(windows)]
+fn is_running(pid: u32) -> bool {
+    use windows::Win32::{
+        Foundation::CloseHandle,
+        System::Threading::{OpenProcess, PROCESS_QUERY_LIMITED_INFORMATION},
+    };
+
+    match unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, pid) } {
+        Ok(handle) => {
+            let _ = unsafe { CloseHandle(handle) };
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_and_removes_pidfile() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("run").join("watcher.pid");
+
+        let pidfile = Pidfile::create(&path).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("{}\n", std::process::id())
+        );
+        drop(pidfile);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn replaces_stale_pidfile() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("watcher.pid");
+        // pids are far below this
+        std::fs::write(&path, "999999999\n").unwrap();
+
+        let _pidfile = Pidfile::create(&path).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("{}\n", std::process::id())
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_pidfile_of_running_process() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("watcher.pid");
+        // init is always running
+        std::fs::write(&path, "1\n").unwrap();
+
+        let err = Pidfile::create(&path).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "1\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn notifies_systemd() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify.sock");
+        let socket = std::os::unix::net::UnixDatagram::bind(&path).unwrap();
+
+        // no other test uses NOTIFY_SOCKET
+        std::env::set_var("NOTIFY_SOCKET", &path);
+        notify("READY=1");
+        std::env::remove_var("NOTIFY_SOCKET");
+
+        let mut buf = [0; 16];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+    }
+}
@@ -0,0 +1,285 @@
+//! The `doctor` subcommand, goes through every step of attaching to Netease Cloud Music and
+//! suggests how to fix the ones failing.
+
+use std::{fmt::Display, path::Path};
+
+use rusqlite::{Connection, OpenFlags};
+use tokio::sync::oneshot;
+
+use crate::{
+    netease::{
+        self,
+        player::PlayerReader,
+        probation::{self, Candidate, Probation, Verdict},
+        WatcherOptions,
+    },
+    signature::{Signature, SignatureDb, Variable},
+};
+
+pub const SUGGEST_SIGNATURES: &str = "This version of Netease Cloud Music is not supported by the built-in signatures. Run `netease-watcher scan <path to cloudmusic.dll>` to check it, and open an issue with its version or add working signatures with `watcher.signatures`";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Status {
+    Ok,
+    Warn,
+    Fail,
+}
+
+/// Printed as the checks go, so a hanging step is still visible.
+#[derive(Default)]
+pub struct Report {
+    warnings: usize,
+    failures: usize,
+    suggestions: Vec<String>,
+}
+
+impl Report {
+    pub fn section(&self, title: impl Display) {
+        println!("\n{}", title);
+    }
+
+    fn check(&mut self, status: Status, message: impl Display) {
+        let tag = match status {
+            Status::Ok => " OK ",
+            Status::Warn => "WARN",
+            Status::Fail => "FAIL",
+        };
+        match status {
+            Status::Ok => {}
+            Status::Warn => self.warnings += 1,
+            Status::Fail => self.failures += 1,
+        }
+        println!("  [{}] {}", tag, message);
+    }
+
+    pub fn ok(&mut self, message: impl Display) {
+        self.check(Status::Ok, message);
+    }
+
+    pub fn warn(&mut self, message: impl Display) {
+        self.check(Status::Warn, message);
+    }
+
+    pub fn fail(&mut self, message: impl Display) {
+        self.check(Status::Fail, message);
+    }
+
+    /// Details, indented under the checks.
+    pub fn detail(&self, message: impl Display) {
+        println!("         {}", message);
+    }
+
+    /// Added once, however many checks run into the same problem.
+    pub fn suggest(&mut self, suggestion: impl Into<String>) {
+        let suggestion = suggestion.into();
+        if !self.suggestions.contains(&suggestion) {
+            self.suggestions.push(suggestion);
+        }
+    }
+
+    /// Prints the summary, returns the exit code.
+    fn finish(self) -> i32 {
+        println!();
+        if self.failures == 0 && self.warnings == 0 {
+            println!("Everything looks fine.");
+        } else {
+            println!("{} failed, {} warning(s).", self.failures, self.warnings);
+        }
+        if !self.suggestions.is_empty() {
+            println!("\nSuggestions:");
+            for (index, suggestion) in self.suggestions.iter().enumerate() {
+                println!("  {}. {}", index + 1, suggestion);
+            }
+        }
+        if self.failures == 0 {
+            0
+        } else {
+            1
+        }
+    }
+}
+
+/// Checks that webdb.dat exists and can be read.
+pub fn check_webdb(report: &mut Report, path: &Path) {
+    if !path.exists() {
+        report.fail(format!("{} does not exist", path.display()));
+        report.suggest(
+            "Play a song in Netease Cloud Music to create webdb.dat, or pass its path with --webdb",
+        );
+        return;
+    }
+    let result =
+        Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).and_then(|conn| {
+            conn.query_row("SELECT COUNT(*) FROM historyTracks", [], |row| {
+                row.get::<_, i64>(0)
+            })
+        });
+    match result {
+        Ok(count) => report.ok(format!(
+            "{} opens, {} track(s) in the history",
+            path.display(),
+            count
+        )),
+        Err(err) => {
+            report.fail(format!("Unable to read {}: {}", path.display(), err));
+            report.suggest(
+                "Check that webdb.dat is readable by this user and is the one of Netease Cloud Music, or pass the right one with --webdb",
+            );
+        }
+    }
+}
+
+/// Loads the signatures, checking the files of the user.
+pub fn load_signatures(options: &WatcherOptions, report: &mut Report) -> SignatureDb {
+    let (db, errors) = SignatureDb::load(&options.signatures);
+    if !options.signatures.is_empty() {
+        report.section("Signatures");
+        for err in &errors {
+            report.fail(err);
+        }
+        if errors.is_empty() {
+            report.ok(format!(
+                "Loaded {} signature file(s)",
+                options.signatures.len()
+            ));
+        } else {
+            report.suggest("Fix the signature files in watcher.signatures");
+        }
+    }
+    db
+}
+
+/// Watches the candidates of the playback time like the watcher does, and picks one.
+pub fn check_candidates<'a>(
+    report: &mut Report,
+    mut probation: Probation<'a>,
+    read: impl FnMut(usize) -> f64,
+) -> Option<Candidate<'a>> {
+    report.ok(format!(
+        "Found {} playback time candidate(s), watching them for {}s",
+        probation.len(),
+        probation::WINDOW.as_secs()
+    ));
+    let (_stop_signal, mut stop_rx) = oneshot::channel();
+    probation.run(read, None, &mut stop_rx);
+    for (candidate, verdict) in probation.verdicts() {
+        report.detail(format!(
+            "{:#x} of signature {}: {}",
+            candidate.addr,
+            candidate.signature.name,
+            match verdict {
+                Verdict::Clock => "advanced like a playback clock".to_string(),
+                Verdict::Still => "didn't change".to_string(),
+                Verdict::Rejected(reason) => format!("rejected, {}", reason),
+            }
+        ));
+    }
+    let Some((picked, verdict)) = probation.pick() else {
+        report.fail("None of the candidates behaved like a playback clock");
+        report.suggest(SUGGEST_SIGNATURES);
+        return None;
+    };
+    report.ok(format!(
+        "Signature {} picked, playback time at {:#x}",
+        picked.signature.name, picked.addr
+    ));
+    if verdict != Verdict::Clock {
+        report.warn("None of the candidates advanced, the pick might be wrong");
+        report.suggest(
+            "Play a song in Netease Cloud Music while running the doctor, so the playback time can be told apart",
+        );
+    }
+    Some(picked)
+}
+
+/// Locates the other variables of the player like the watcher does, and reads them once.
+pub fn check_player(
+    report: &mut Report,
+    signatures: &[&Signature],
+    candidates: Vec<Candidate>,
+    read: impl Fn(usize, usize) -> Option<Vec<u8>>,
+) {
+    let reader = PlayerReader::new(candidates);
+    for variable in Variable::ALL {
+        if !signatures.iter().any(|x| x.variable == variable) {
+            continue;
+        }
+        match reader
+            .variables()
+            .iter()
+            .find(|x| x.signature.variable == variable)
+        {
+            Some(found) => report.ok(format!(
+                "Signature {} matched, {} at {:#x}",
+                found.signature.name, variable, found.addr
+            )),
+            None => report.warn(format!("None of the signatures of {} matched", variable)),
+        }
+    }
+    if !reader.is_empty() {
+        report.ok(format!(
+            "Player state is {}",
+            serde_json::to_string(&reader.read(read)).unwrap()
+        ));
+    }
+}
+
+pub fn run(options: &WatcherOptions) -> i32 {
+    println!("Checking how to attach to Netease Cloud Music...");
+    let mut report = Report::default();
+    netease::diagnose(options, &mut report);
+    report.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exits_with_failures_only() {
+        let mut report = Report::default();
+        report.ok("fine");
+        report.warn("odd");
+        assert_eq!(report.finish(), 0);
+
+        let mut report = Report::default();
+        report.fail("broken");
+        assert_eq!(report.finish(), 1);
+    }
+
+    #[test]
+    fn deduplicates_suggestions() {
+        let mut report = Report::default();
+        report.suggest("a");
+        report.suggest("b");
+        report.suggest("a");
+        assert_eq!(report.suggestions, ["a", "b"]);
+    }
+
+    #[test]
+    fn checks_webdb() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let mut report = Report::default();
+        check_webdb(&mut report, &dir.path().join("missing.dat"));
+        assert_eq!(report.failures, 1);
+
+        let path = dir.path().join("webdb.dat");
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE historyTracks (id INTEGER, jsonStr TEXT, playtime INTEGER);
+             INSERT INTO historyTracks VALUES (1, '{}', 0);",
+        )
+        .unwrap();
+        drop(conn);
+        let mut report = Report::default();
+        check_webdb(&mut report, &path);
+        assert_eq!(report.failures, 0);
+
+        std::fs::write(&path, "not a database").unwrap();
+        let mut report = Report::default();
+        check_webdb(&mut report, &path);
+        assert_eq!(report.failures, 1);
+        assert_eq!(report.suggestions.len(), 1);
+    }
+}
@@ -1,35 +1,228 @@
-use std::panic;
+use std::{
+    fs::File,
+    io::Write,
+    panic,
+    path::{Path, PathBuf},
+};
 
 use fern::colors::{Color, ColoredLevelConfig};
+use log::kv::{Key, Value, VisitSource};
 
-pub fn setup_logger() -> Result<(), fern::InitError> {
+use crate::config::{self, LogConfig, LogFormat};
+
+/// Only logs from this crate are shown below this level.
+const DEPENDENCY_LEVEL: log::LevelFilter = log::LevelFilter::Warn;
+
+/// The level logs of the target are shown at.
+///
+/// The most specific entry of `log.modules` applies, then the global level.
+fn level_for(config: &LogConfig, target: &str) -> log::LevelFilter {
+    let module = config
+        .modules
+        .iter()
+        .filter(|(module, _)| {
+            target == module.as_str()
+                || target
+                    .strip_prefix(module.as_str())
+                    .is_some_and(|x| x.starts_with("::"))
+        })
+        .max_by_key(|(module, _)| module.len());
+    match module {
+        Some((_, level)) => *level,
+        None if target.starts_with("netease_watcher") => config.level,
+        None => config.level.min(DEPENDENCY_LEVEL),
+    }
+}
+
+/// The most verbose level of any target, so `log` can skip the rest early.
+fn max_level(config: &LogConfig) -> log::LevelFilter {
+    config
+        .modules
+        .values()
+        .copied()
+        .fold(config.level.max(DEPENDENCY_LEVEL), |a, b| a.max(b))
+}
+
+/// Collects the structured fields of a record, like `log::info!(track_id = 1; "...")`.
+struct JsonFields(serde_json::Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        let value = if let Some(x) = value.to_i64() {
+            x.into()
+        } else if let Some(x) = value.to_u64() {
+            x.into()
+        } else if let Some(x) = value.to_f64() {
+            x.into()
+        } else if let Some(x) = value.to_bool() {
+            x.into()
+        } else {
+            value.to_string().into()
+        };
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+fn format_json(record: &log::Record, message: &std::fmt::Arguments) -> String {
+    let mut fields = JsonFields(serde_json::Map::new());
+    let _ = record.key_values().visit(&mut fields);
+    let mut line = serde_json::Map::new();
+    line.insert("time".to_string(), chrono::Local::now().to_rfc3339().into());
+    line.insert("level".to_string(), record.level().as_str().into());
+    line.insert("target".to_string(), record.target().into());
+    // the pid of the client is a field of its own
+    line.insert("watcher_pid".to_string(), std::process::id().into());
+    line.insert("message".to_string(), message.to_string().into());
+    // fields can't replace the ones above
+    for (key, value) in fields.0 {
+        line.entry(key).or_insert(value);
+    }
+    serde_json::Value::Object(line).to_string()
+}
+
+/// A log file that is renamed to `<name>.1` when it grows too large, `<name>.1` to `<name>.2` and so on.
+struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    /// number of rotated files to keep
+    max_files: u32,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_size: u64, max_files: u32) -> std::io::Result<Self> {
+        if let Some(dir) = path.parent().filter(|x| !x.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = File::options().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            max_size,
+            max_files,
+            file,
+            size,
+        })
+    }
+
+    fn rotated_path(&self, index: u32) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        if self.max_files == 0 {
+            self.file.set_len(0)?;
+        } else {
+            let _ = std::fs::remove_file(self.rotated_path(self.max_files));
+            for index in (1..self.max_files).rev() {
+                let _ = std::fs::rename(self.rotated_path(index), self.rotated_path(index + 1));
+            }
+            std::fs::rename(&self.path, self.rotated_path(1))?;
+            self.file = File::options().create(true).append(true).open(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    /// fern flushes after every record, rotate here so that a record never gets split.
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+        if self.size >= self.max_size {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+}
+
+fn file_dispatch(config: &LogConfig) -> Result<Option<fern::Dispatch>, fern::InitError> {
+    let Some(path) = &config.file.path else {
+        return Ok(None);
+    };
+    let file = RotatingFile::open(
+        path,
+        config.file.max_size.saturating_mul(1024 * 1024),
+        config.file.max_files,
+    )?;
+    let format = config.file.format;
+    Ok(Some(
+        fern::Dispatch::new()
+            .format(move |out, message, record| match format {
+                LogFormat::Text => out.finish(format_args!(
+                    "[{} {}] [{}] {}",
+                    chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
+                    record.level(),
+                    record.target(),
+                    message
+                )),
+                LogFormat::Json => out.finish(format_args!("{}", format_json(record, message))),
+            })
+            .chain(Box::new(file) as Box<dyn Write + Send>),
+    ))
+}
+
+pub fn setup_logger(tui: bool, color: bool) -> Result<(), fern::InitError> {
+    let config = config::get();
     let colors = ColoredLevelConfig::new().info(Color::BrightBlue);
-    fern::Dispatch::new()
-        .format(move |out, message, record| {
-            let time = chrono::Local::now();
+    let console = fern::Dispatch::new().format(move |out, message, record| {
+        let time = chrono::Local::now();
+        if color {
             out.finish(format_args!(
                 "[{} {}] {}",
                 time.format("%H:%M:%S"),
                 colors.color(record.level()),
                 message
             ));
-        })
-        .chain({
-            let dispatcher = fern::Dispatch::new()
-                .level(log::LevelFilter::Warn)
-                .level_for("netease_watcher", log::LevelFilter::Debug);
-            #[cfg(feature = "tui")]
-            {
-                dispatcher.chain(Into::<fern::Output>::into(
-                    Box::new(crate::tui::logger::TuiLogger) as Box<dyn Send + std::io::Write>,
-                ))
-            }
-            #[cfg(not(feature = "tui"))]
-            {
-                dispatcher.chain(std::io::stdout())
-            }
-        })
-        .apply()?;
+        } else {
+            out.finish(format_args!(
+                "[{} {}] {}",
+                time.format("%H:%M:%S"),
+                record.level(),
+                message
+            ));
+        }
+    });
+    #[cfg(feature = "tui")]
+    let console = if tui {
+        console.chain(Into::<fern::Output>::into(
+            Box::new(crate::tui::logger::TuiLogger) as Box<dyn Send + std::io::Write>,
+        ))
+    } else {
+        console.chain(std::io::stdout())
+    };
+    #[cfg(not(feature = "tui"))]
+    let console = {
+        let _ = tui;
+        console.chain(std::io::stdout())
+    };
+
+    let mut dispatch = fern::Dispatch::new()
+        // the levels can be changed by reloading the config, so filter dynamically.
+        .filter(|metadata| metadata.level() <= level_for(&config::get().log, metadata.target()))
+        .chain(console);
+    if let Some(file) = file_dispatch(&config.log)? {
+        dispatch = dispatch.chain(file);
+    }
+    dispatch.apply()?;
+
+    let mut config_rx = config::subscribe();
+    log::set_max_level(max_level(&config_rx.borrow_and_update().log));
+    tokio::spawn(async move {
+        while config_rx.changed().await.is_ok() {
+            log::set_max_level(max_level(&config_rx.borrow_and_update().log));
+        }
+    });
     Ok(())
 }
 
@@ -62,3 +255,122 @@ pub fn setup_panic_logger_hook() {
         default_hook(info);
     }));
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    fn log_config(level: log::LevelFilter, modules: &[(&str, log::LevelFilter)]) -> LogConfig {
+        LogConfig {
+            level,
+            modules: modules
+                .iter()
+                .map(|(module, level)| (module.to_string(), *level))
+                .collect::<BTreeMap<_, _>>(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn picks_most_specific_level() {
+        use log::LevelFilter::*;
+
+        let config = log_config(
+            Debug,
+            &[
+                ("netease_watcher::server", Warn),
+                ("netease_watcher::server::cover", Trace),
+                ("reqwest", Debug),
+            ],
+        );
+        assert_eq!(level_for(&config, "netease_watcher"), Debug);
+        assert_eq!(level_for(&config, "netease_watcher::netease"), Debug);
+        assert_eq!(level_for(&config, "netease_watcher::server"), Warn);
+        assert_eq!(level_for(&config, "netease_watcher::server::tls"), Warn);
+        assert_eq!(level_for(&config, "netease_watcher::server::cover"), Trace);
+        // only whole path segments match
+        assert_eq!(level_for(&config, "netease_watcher::serverless"), Debug);
+        assert_eq!(level_for(&config, "reqwest::connect"), Debug);
+        assert_eq!(level_for(&config, "hyper"), Warn);
+
+        let quiet = log_config(Error, &[]);
+        assert_eq!(level_for(&quiet, "hyper"), Error);
+    }
+
+    #[test]
+    fn computes_max_level() {
+        use log::LevelFilter::*;
+
+        assert_eq!(max_level(&log_config(Info, &[])), Info);
+        assert_eq!(max_level(&log_config(Error, &[])), Warn);
+        assert_eq!(max_level(&log_config(Info, &[("reqwest", Trace)])), Trace);
+    }
+
+    #[test]
+    fn formats_json_lines() {
+        let fields: &[(&str, Value)] = &[
+            ("event", Value::from("music_change")),
+            ("track_id", Value::from(42u64)),
+            ("pid", Value::from(1u32)),
+            ("message", Value::from("ignored")),
+        ];
+        let message = format_args!("Music changed to {}", "song");
+        let record = log::Record::builder()
+            .level(log::Level::Info)
+            .target("netease_watcher::netease")
+            .key_values(&fields)
+            .args(message)
+            .build();
+
+        let line: serde_json::Value =
+            serde_json::from_str(&format_json(&record, record.args())).unwrap();
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["target"], "netease_watcher::netease");
+        assert_eq!(line["message"], "Music changed to song");
+        assert_eq!(line["event"], "music_change");
+        assert_eq!(line["track_id"], 42);
+        assert_eq!(line["pid"], 1);
+        assert_eq!(line["watcher_pid"], std::process::id());
+        assert!(line["time"].is_string());
+    }
+
+    #[test]
+    fn rotates_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("logs").join("watcher.log");
+        let mut file = RotatingFile::open(&path, 10, 2).unwrap();
+
+        for line in ["first line\n", "second line\n", "third line\n", "fourth\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+            file.flush().unwrap();
+        }
+        let read = |path: &Path| std::fs::read_to_string(path).unwrap();
+        assert_eq!(read(&path), "fourth\n");
+        assert_eq!(read(&file.rotated_path(1)), "third line\n");
+        assert_eq!(read(&file.rotated_path(2)), "second line\n");
+        // only two rotated files are kept
+        assert!(!file.rotated_path(3).exists());
+
+        // the size is picked up again when reopened
+        let mut file = RotatingFile::open(&path, 10, 2).unwrap();
+        file.write_all(b"fifth\n").unwrap();
+        file.flush().unwrap();
+        assert_eq!(read(&path), "");
+        assert_eq!(read(&file.rotated_path(1)), "fourth\nfifth\n");
+    }
+
+    #[test]
+    fn truncates_without_rotated_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("watcher.log");
+        let mut file = RotatingFile::open(&path, 4, 0).unwrap();
+        file.write_all(b"hello\n").unwrap();
+        file.flush().unwrap();
+        file.write_all(b"hi\n").unwrap();
+        file.flush().unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "hi\n");
+        assert!(!file.rotated_path(1).exists());
+    }
+}
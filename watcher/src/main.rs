@@ -1,13 +1,29 @@
-use axum::{routing::get, Router};
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use axum::{middleware, routing::get, Router};
 use logging::{setup_logger, setup_panic_logger_hook};
-use serde::Serialize;
-use tokio::sync::watch;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::watch, task::JoinSet};
 
-use crate::netease::NeteaseWatcher;
+use crate::{
+    cover::CoverCache,
+    netease::{instances::Instance, NeteaseWatcher, WatcherOptions},
+    palette::Palette,
+    server::listener,
+};
 
+mod cli;
+mod config;
+mod cover;
+mod daemon;
+mod doctor;
 mod logging;
 mod netease;
+mod palette;
+mod pe;
+mod scan;
 mod server;
+mod signature;
 #[cfg(feature = "tui")]
 mod tui;
 mod util;
@@ -23,36 +39,145 @@ pub struct Music {
     name: String,
 }
 
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum PlayMode {
+    /// plays the list once
+    Order,
+    /// repeats the list
+    Loop,
+    /// repeats the track
+    Single,
+    Shuffle,
+}
+
+/// The state of the player besides the playback time, `None` if it isn't known.
+#[derive(Clone, Serialize, PartialEq, Debug, Default)]
+pub struct Player {
+    /// from 0 to 1
+    volume: Option<f64>,
+    muted: Option<bool>,
+    play_mode: Option<PlayMode>,
+    paused: Option<bool>,
+}
+
 #[derive(Clone)]
-pub struct State(watch::Receiver<f64>, watch::Receiver<Option<Music>>);
+pub struct State {
+    time: watch::Receiver<f64>,
+    music: watch::Receiver<Option<Music>>,
+    player: watch::Receiver<Player>,
+    /// every attached client, the channels above follow the primary one
+    instances: watch::Receiver<Vec<Instance>>,
+    palette: watch::Receiver<Option<Palette>>,
+    covers: Arc<CoverCache>,
+    /// becomes true when shutting down
+    shutdown: watch::Receiver<bool>,
+    /// number of open WebSocket connections
+    connections: Arc<watch::Sender<usize>>,
+}
+
+/// How long to wait for connections to close when shutting down.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[tokio::main]
 async fn main() {
-    println!(
-        "Netease Cloud Music Status Monitor v{}",
-        env!("CARGO_PKG_VERSION")
-    );
-    println!("by YUCLing");
-    println!("= cheers! =");
-
-    setup_logger().unwrap();
-    setup_panic_logger_hook();
+    let args = cli::Args::load();
 
-    let host = std::env::var("HOST").unwrap_or("127.0.0.1".to_string());
+    #[cfg(feature = "tui")]
+    let use_tui = !args.no_tui && !args.daemon;
+    #[cfg(not(feature = "tui"))]
+    let use_tui = false;
 
-    let port: i32 = std::env::var("PORT")
-        .ok()
-        .and_then(|x| x.parse().ok())
-        .unwrap_or(3574);
+    if !args.daemon {
+        println!(
+            "Netease Cloud Music Status Monitor v{}",
+            env!("CARGO_PKG_VERSION")
+        );
+        println!("by YUCLing");
+        println!("= cheers! =");
+    }
 
-    let endpoint = format!("{}:{}", host, port);
+    let config_path = match config::init(&args) {
+        Ok(path) => path,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    let config = config::get();
 
-    let mut watcher = NeteaseWatcher::new();
+    if let Some(cli::Command::GenCert { force }) = &args.command {
+        match server::tls::ensure_self_signed(&config.server.tls.hostnames, *force) {
+            Ok((cert, key)) => {
+                println!("Certificate: {}", cert.display());
+                println!("Private key: {}", key.display());
+            }
+            Err(err) => {
+                eprintln!("Unable to generate the certificate: {}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    match &args.command {
+        Some(cli::Command::Doctor) => {
+            std::process::exit(doctor::run(&WatcherOptions::from_config(&config.watcher)))
+        }
+        Some(cli::Command::Scan { dll }) => {
+            std::process::exit(scan::run(dll, &config.watcher.signatures))
+        }
+        _ => {}
+    }
+
+    setup_logger(use_tui, !args.daemon).unwrap();
+    setup_panic_logger_hook();
+
+    let _config_watcher = config_path.and_then(|path| {
+        log::info!("Using configuration file {}", path.display());
+        config::watch(&path, args.clone())
+            .map_err(|err| {
+                log::warn!(
+                    "Unable to watch the configuration file, changes won't be applied until restart: {}",
+                    err
+                )
+            })
+            .ok()
+    });
+
+    let mut watcher = NeteaseWatcher::new(WatcherOptions::from_config(&config.watcher));
 
     watcher.start();
 
-    #[cfg(feature = "tui")]
+    let covers = Arc::new(CoverCache::new(
+        CoverCache::default_dir(),
+        config.cover.cache_size.saturating_mul(1024 * 1024),
+    ));
+
     {
+        let covers = covers.clone();
+        let mut instances_rx = watcher.instances();
+        // keep track of the art of every track of every instance, so they can be served by id.
+        tokio::spawn(async move {
+            let mut remembering = HashSet::new();
+            loop {
+                let instances = instances_rx.borrow_and_update().clone();
+                remembering.retain(|pid| instances.iter().any(|x| x.pid == *pid));
+                for instance in instances {
+                    if remembering.insert(instance.pid) {
+                        remember_covers(covers.clone(), instance.music);
+                    }
+                }
+                if instances_rx.changed().await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    let palette = palette::spawn_palette_task(covers.clone(), watcher.music());
+
+    #[cfg(feature = "tui")]
+    if use_tui {
         let mut time_rx = watcher.time();
         let mut music_rx = watcher.music();
         let mut next_find_time_rx = watcher.next_find_time();
@@ -75,7 +200,7 @@ async fn main() {
                         crate::tui::TUI_NOTIFY.notify_one();
                     }
                     _ = next_find_time_rx.changed() => {
-                        let next_find_time = next_find_time_rx.borrow().clone();
+                        let next_find_time = *next_find_time_rx.borrow();
                         if next_find_time.is_some() {
                             // process is gone, clear music info
                             *crate::tui::TUI_MUSIC.lock().unwrap() = None;
@@ -88,24 +213,143 @@ async fn main() {
         });
     }
 
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let connections = Arc::new(watch::Sender::new(0usize));
+    {
+        let shutdown_tx = shutdown_tx.clone();
+        tokio::spawn(async move {
+            daemon::wait_for_signal().await;
+            shutdown_tx.send_replace(true);
+        });
+    }
+
+    let mut endpoints = Vec::new();
+    let mut servers = JoinSet::new();
     {
         let app = Router::new()
             .route("/ws", get(server::ws_handler))
+            .route("/instances", get(server::instances_handler))
+            .route("/overlay/{theme}", get(server::overlay::overlay_handler))
+            .route("/cover/current", get(server::cover::current_cover_handler))
+            .route("/cover/{id}", get(server::cover::cover_handler))
             .fallback(get(server::http_handler))
-            .with_state(State(watcher.time(), watcher.music()));
+            .with_state(State {
+                time: watcher.time(),
+                music: watcher.music(),
+                player: watcher.player(),
+                instances: watcher.instances(),
+                palette,
+                covers,
+                shutdown: shutdown_rx.clone(),
+                connections: connections.clone(),
+            })
+            .layer(middleware::from_fn_with_state(
+                server::auth::Scope::Read,
+                server::auth::authorize,
+            ))
+            // outside of authorization, as preflight requests carry no token
+            .layer(server::cors::layer());
 
-        log::info!("Starting HTTP server at {}", endpoint);
-        let listener = tokio::net::TcpListener::bind(&endpoint).await.unwrap();
+        let tls = match server::tls::load_acceptor(&config.server.tls) {
+            Ok(tls) => tls,
+            Err(err) => {
+                log::error!("Unable to set up TLS, exiting: {}", err);
+                std::process::exit(1);
+            }
+        };
 
-        #[cfg(feature = "tui")]
-        tokio::spawn(async { axum::serve(listener, app).await.unwrap() });
+        let mut listeners = Vec::new();
+        let addresses = config.server.listen_addresses().unwrap_or_else(|err| {
+            log::error!("Invalid listen address: {}", err);
+            Vec::new()
+        });
+        for address in addresses {
+            let bound = listener::bind(&address, config.server.socket_mode)
+                .await
+                .and_then(|bound| match &tls {
+                    Some(acceptor) => bound.into_iter().map(|x| x.with_tls(acceptor)).collect(),
+                    None => Ok(bound),
+                });
+            match bound {
+                Ok(bound) => listeners.extend(bound),
+                Err(err) => log::error!("Unable to listen on {}: {}", address, err),
+            }
+        }
+        if listeners.is_empty() {
+            log::error!("No address to listen on, exiting.");
+            std::process::exit(1);
+        }
 
-        #[cfg(not(feature = "tui"))]
-        axum::serve(listener, app).await.unwrap();
-    }
+        if config.server.auth.tokens.is_empty() && listeners.iter().any(|x| !x.is_local()) {
+            log::warn!(
+                "Listening on a network address without authentication, anyone on the network can see what you are listening to. Set server.auth.tokens to require a token."
+            );
+        }
 
-    #[cfg(feature = "tui")]
-    tui::run(endpoint).await;
+        let _pidfile = args.pidfile.as_deref().map(|path| {
+            daemon::Pidfile::create(path).unwrap_or_else(|err| {
+                log::error!("Unable to write the pidfile, exiting: {}", err);
+                std::process::exit(1);
+            })
+        });
+
+        for listener in listeners {
+            let local_addr = listener.local_addr();
+            log::info!("Starting HTTP server at {}", local_addr);
+            endpoints.push(local_addr);
+            let mut shutdown_rx = shutdown_rx.clone();
+            servers.spawn(listener.serve(app.clone(), async move {
+                let _ = shutdown_rx.wait_for(|x| *x).await;
+            }));
+        }
+        daemon::notify("READY=1");
+        daemon::spawn_watchdog();
+
+        if use_tui {
+            #[cfg(feature = "tui")]
+            tui::run(endpoints.join(", "), shutdown_rx.clone()).await;
+        } else {
+            let mut shutdown_rx = shutdown_rx.clone();
+            tokio::select! {
+                _ = shutdown_rx.wait_for(|x| *x) => {}
+                _ = async {
+                    while let Some(result) = servers.join_next().await {
+                        if let Ok(Err(err)) = result {
+                            log::error!("HTTP server stopped: {}", err);
+                        }
+                    }
+                } => {
+                    log::error!("All HTTP servers stopped, exiting.");
+                }
+            }
+        }
 
-    watcher.stop().await.unwrap();
+        shutdown_tx.send_replace(true);
+        daemon::notify("STOPPING=1");
+        log::info!("Shutting down.");
+        let drained = tokio::time::timeout(SHUTDOWN_TIMEOUT, async {
+            while servers.join_next().await.is_some() {}
+            let _ = connections.subscribe().wait_for(|x| *x == 0).await;
+        })
+        .await;
+        if drained.is_err() {
+            log::warn!("Some connections didn't close in time.");
+        }
+
+        watcher.stop().await.unwrap();
+    }
+}
+
+/// Remembers the art of every track of the instance until it's detached.
+fn remember_covers(covers: Arc<CoverCache>, mut music_rx: watch::Receiver<Option<Music>>) {
+    tokio::spawn(async move {
+        loop {
+            if let Some(music) = music_rx.borrow_and_update().as_ref() {
+                covers.remember(music);
+            }
+            if music_rx.changed().await.is_err() {
+                break;
+            }
+        }
+    });
 }
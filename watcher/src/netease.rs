@@ -1,18 +1,17 @@
-use std::{
-    any::Any,
-    path::Path,
-    sync::mpsc,
-    time::{Duration, Instant},
-};
+use std::{any::Any, path::PathBuf};
 
-use notify::{Config, Watcher};
+mod changes;
+mod database;
+pub mod instances;
+mod offsets;
+pub mod player;
+pub mod probation;
 
 #[cfg(windows)]
 mod windows;
 
-use rusqlite::Connection;
 use serde_json::Value;
-use tokio::sync::{oneshot, watch};
+use tokio::sync::watch;
 #[cfg(windows)]
 pub use windows::NeteaseWatcherWindows as NeteaseWatcher;
 
@@ -22,38 +21,60 @@ mod unix;
 #[cfg(unix)]
 pub use unix::NeteaseWatcherUnix as NeteaseWatcher;
 
-use crate::Music;
+use crate::{
+    config::WatcherConfig,
+    doctor::Report,
+    netease::{database::Database, instances::Instance},
+    Music, Player,
+};
 
 pub const FIND_RETRY_SECS: u64 = 5;
+pub const RESCAN_SECS: u64 = 60;
+
+/// Options to override what the watcher finds automatically.
+#[derive(Clone, Debug, Default)]
+pub struct WatcherOptions {
+    pub webdb: Option<PathBuf>,
+    #[cfg(unix)]
+    pub wineprefix: Option<PathBuf>,
+    pub pid: Option<u32>,
+    pub signatures: Vec<PathBuf>,
+    /// the pid or Wine prefix of the instance to follow when several are running
+    pub primary: Option<String>,
+}
 
-pub(self) fn create_file_watcher(
-    file: &Path,
-) -> notify::Result<(
-    notify::RecommendedWatcher,
-    mpsc::Receiver<notify::Result<notify::Event>>,
-)> {
-    let (tx, rx) = mpsc::channel();
-
-    let mut watcher = notify::recommended_watcher(tx)?;
-
-    watcher.configure(Config::default().with_poll_interval(Duration::from_secs(1)))?;
-
-    watcher.watch(file, notify::RecursiveMode::NonRecursive)?;
+impl WatcherOptions {
+    pub fn from_config(config: &WatcherConfig) -> Self {
+        WatcherOptions {
+            webdb: config.webdb.clone(),
+            #[cfg(unix)]
+            wineprefix: config.wineprefix.clone(),
+            pid: config.pid,
+            signatures: config.signatures.clone(),
+            primary: config.primary.clone(),
+        }
+    }
+}
 
-    Ok((watcher, rx))
+/// Goes through the steps of attaching for the `doctor` subcommand.
+pub fn diagnose(options: &WatcherOptions, report: &mut Report) {
+    #[cfg(unix)]
+    unix::doctor::diagnose(options, report);
+    #[cfg(windows)]
+    windows::doctor::diagnose(options, report);
 }
 
-pub(self) fn update_music(conn: &Connection, music: &watch::Sender<Option<Music>>) {
-    let json_str: String = {
-        let Ok(json_str) = conn.query_row(
-            "SELECT jsonStr FROM historyTracks ORDER BY playtime DESC LIMIT 1",
-            [],
-            |row| row.get(0),
-        ) else {
-            log::error!("Unable to read the database.");
-            return;
-        };
-        json_str
+/// Returns false if the database couldn't be read, so it's tried again.
+fn update_music(db: &Database, music: &watch::Sender<Option<Music>>) -> bool {
+    let json_str: String = match db.query_row(
+        "SELECT jsonStr FROM historyTracks ORDER BY playtime DESC LIMIT 1",
+        |row| row.get(0),
+    ) {
+        Ok(json_str) => json_str,
+        Err(err) => {
+            log::error!("Unable to read the database: {}", err);
+            return false;
+        }
     };
 
     let json = serde_json::from_str::<Value>(&json_str);
@@ -88,17 +109,24 @@ pub(self) fn update_music(conn: &Connection, music: &watch::Sender<Option<Music>
             name,
         }
     });
+    set_music(music, new_val);
+    true
+}
+
+/// Publishes the music if it changed.
+fn set_music(music: &watch::Sender<Option<Music>>, new_val: Option<Music>) {
     if new_val != *music.borrow() {
         log::info!(
+            event = "music_change",
+            track_id = new_val.as_ref().map(|x| x.id);
             "Music changed to {}",
             if let Some(music) = new_val.as_ref() {
                 format!(
                     "{}{} - {} ({})",
                     music.name,
-                    if music.aliases.is_none() {
-                        "".to_string()
-                    } else {
-                        format!(" [{}]", music.aliases.as_ref().unwrap().join("/"))
+                    match music.aliases.as_ref() {
+                        Some(aliases) => format!(" [{}]", aliases.join("/")),
+                        None => "".to_string(),
                     },
                     music.artists.join(", "),
                     music.id
@@ -107,21 +135,24 @@ pub(self) fn update_music(conn: &Connection, music: &watch::Sender<Option<Music>
                 "*no music*".to_string()
             }
         );
-        let _ = music.send(new_val);
+        music.send_replace(new_val);
     }
 }
 
-pub(self) fn stoppable_sleep(duration: Duration, stop_signal: &mut oneshot::Receiver<()>) -> bool {
-    let sleep_interval = Duration::from_millis(100);
-    let mut elapsed = Duration::ZERO;
-    while elapsed < duration {
-        if stop_signal.try_recv().is_ok() {
-            return true;
+fn update_player(player: &watch::Sender<Player>, value: Player) {
+    player.send_if_modified(|x| {
+        if *x == value {
+            return false;
         }
-        std::thread::sleep(sleep_interval);
-        elapsed += sleep_interval;
-    }
-    false
+        log::debug!("Player changed to {:?}", value);
+        *x = value;
+        true
+    });
+}
+
+/// The duration of the playing track in seconds.
+fn track_duration(music: &watch::Sender<Option<Music>>) -> Option<f64> {
+    music.borrow().as_ref().map(|x| x.duration as f64 / 1000.)
 }
 
 impl NeteaseWatcher {
@@ -145,7 +176,31 @@ impl NeteaseWatcher {
         self.music.1.clone()
     }
 
-    pub fn next_find_time(&self) -> watch::Receiver<Option<Instant>> {
+    pub fn player(&self) -> watch::Receiver<Player> {
+        self.player.1.clone()
+    }
+
+    /// Every attached client.
+    pub fn instances(&self) -> watch::Receiver<Vec<Instance>> {
+        self.registry.subscribe()
+    }
+
+    #[cfg(feature = "tui")]
+    pub fn next_find_time(&self) -> watch::Receiver<Option<std::time::Instant>> {
         self.scheduled_find_time.1.clone()
     }
 }
+
+/// A webdb.dat in `dir` with a track in its history, as the client would write it.
+#[cfg(test)]
+fn create_webdb(dir: &std::path::Path, journal_mode: &str) -> rusqlite::Connection {
+    let conn = rusqlite::Connection::open(dir.join("webdb.dat")).unwrap();
+    conn.pragma_update(None, "journal_mode", journal_mode)
+        .unwrap();
+    conn.execute_batch(
+        "CREATE TABLE historyTracks (jsonStr TEXT, playtime INTEGER);
+        INSERT INTO historyTracks VALUES ('first', 1);",
+    )
+    .unwrap();
+    conn
+}
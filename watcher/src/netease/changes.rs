@@ -0,0 +1,202 @@
+//! Noticing the client writing webdb.dat, whatever its journal mode.
+
+use std::{
+    ffi::OsString,
+    path::Path,
+    sync::mpsc,
+    time::{Duration, Instant},
+};
+
+use notify::{
+    event::{AccessKind, AccessMode, ModifyKind},
+    Config, EventKind, RecommendedWatcher, RecursiveMode, Watcher,
+};
+use rusqlite::{Connection, OpenFlags};
+
+/// How often the version of the data is asked for, in case the events of the files are late or
+/// missing. Not on every poll, reading it takes a shared lock the client may be waiting for.
+const VERSION_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Writes to the database, noticed by the files changing and by `PRAGMA data_version`.
+pub struct Changes {
+    /// kept open, the version only changes for commits of other connections since it was opened
+    conn: Connection,
+    version: Option<i64>,
+    last_check: Instant,
+    _watcher: Option<RecommendedWatcher>,
+    rx: mpsc::Receiver<notify::Result<notify::Event>>,
+    /// the database and its journals, with WAL or a rollback journal
+    names: [OsString; 3],
+}
+
+impl Changes {
+    pub fn new(path: &Path) -> rusqlite::Result<Self> {
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        let (tx, rx) = mpsc::channel();
+        // the journals come and go, so the directory is watched instead of the files
+        let watcher = path
+            .parent()
+            .ok_or_else(|| notify::Error::generic("the database has no parent directory"))
+            .and_then(|dir| {
+                let mut watcher = notify::recommended_watcher(tx)?;
+                watcher.configure(Config::default().with_poll_interval(Duration::from_secs(1)))?;
+                watcher.watch(dir, RecursiveMode::NonRecursive)?;
+                Ok(watcher)
+            })
+            .inspect_err(|err| {
+                log::warn!(
+                    "Unable to watch {}, only checking it every {:?}: {}",
+                    path.display(),
+                    VERSION_INTERVAL,
+                    err
+                )
+            })
+            .ok();
+        let name = path.file_name().unwrap_or_default();
+        let with_suffix = |suffix: &str| {
+            let mut x = name.to_os_string();
+            x.push(suffix);
+            x
+        };
+        let mut changes = Changes {
+            conn,
+            version: None,
+            last_check: Instant::now(),
+            _watcher: watcher,
+            rx,
+            names: [
+                name.to_os_string(),
+                with_suffix("-wal"),
+                with_suffix("-journal"),
+            ],
+        };
+        changes.version = changes.data_version();
+        Ok(changes)
+    }
+
+    fn data_version(&self) -> Option<i64> {
+        self.conn
+            .query_row("PRAGMA data_version", [], |row| row.get(0))
+            .inspect_err(|err| log::debug!("Unable to read the version of the database: {}", err))
+            .ok()
+    }
+
+    /// Whether the event is about the database or a journal being written. Opening a connection
+    /// touches the metadata of the WAL, which isn't a write.
+    fn is_write(&self, event: &notify::Event) -> bool {
+        matches!(
+            event.kind,
+            EventKind::Create(_)
+                | EventKind::Modify(ModifyKind::Any | ModifyKind::Data(_) | ModifyKind::Name(_))
+                | EventKind::Remove(_)
+                | EventKind::Access(AccessKind::Close(AccessMode::Write))
+        ) && event.paths.iter().any(|x| {
+            x.file_name()
+                .is_some_and(|x| self.names.iter().any(|name| name == x))
+        })
+    }
+
+    /// Whether the database was written since the last call.
+    pub fn changed(&mut self) -> bool {
+        let mut written = false;
+        while let Ok(event) = self.rx.try_recv() {
+            written |= event.is_ok_and(|x| self.is_write(&x));
+        }
+        if !written && self.last_check.elapsed() < VERSION_INTERVAL {
+            return false;
+        }
+        self.last_check = Instant::now();
+        let version = self.data_version();
+        if version.is_some() && version != self.version {
+            self.version = version;
+            written = true;
+        }
+        written
+    }
+
+    /// Forgets the version, so the next check reports a change, e.g. when reading the change
+    /// failed and must be retried.
+    pub fn missed(&mut self) {
+        self.version = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::netease::create_webdb;
+
+    /// Polls like the watchers do, until a change is noticed.
+    fn notices(changes: &mut Changes) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            if changes.changed() {
+                return true;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        false
+    }
+
+    #[test]
+    fn notices_writes_in_every_journal_mode() {
+        for journal_mode in ["wal", "delete"] {
+            let dir = tempfile::tempdir().unwrap();
+            let client = create_webdb(dir.path(), journal_mode);
+            let mut changes = Changes::new(&dir.path().join("webdb.dat")).unwrap();
+            std::thread::sleep(VERSION_INTERVAL);
+            assert!(!changes.changed(), "{}", journal_mode);
+
+            client
+                .execute("INSERT INTO historyTracks VALUES ('{}', 1)", [])
+                .unwrap();
+            assert!(notices(&mut changes), "{}", journal_mode);
+            std::thread::sleep(VERSION_INTERVAL);
+            assert!(!changes.changed(), "{}", journal_mode);
+        }
+    }
+
+    #[test]
+    fn notices_writes_by_the_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let client = create_webdb(dir.path(), "wal");
+        let mut changes = Changes::new(&dir.path().join("webdb.dat")).unwrap();
+        // as if the events were lost
+        changes._watcher = None;
+        while changes.rx.try_recv().is_ok() {}
+
+        client
+            .execute("INSERT INTO historyTracks VALUES ('{}', 1)", [])
+            .unwrap();
+        while changes.rx.try_recv().is_ok() {}
+        assert!(notices(&mut changes));
+        assert_eq!(changes.version, changes.data_version());
+    }
+
+    #[test]
+    fn retries_missed_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let _client = create_webdb(dir.path(), "wal");
+        let mut changes = Changes::new(&dir.path().join("webdb.dat")).unwrap();
+        std::thread::sleep(VERSION_INTERVAL);
+        assert!(!changes.changed());
+
+        changes.missed();
+        assert!(notices(&mut changes));
+        std::thread::sleep(VERSION_INTERVAL);
+        assert!(!changes.changed());
+    }
+
+    #[test]
+    fn ignores_other_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let _client = create_webdb(dir.path(), "wal");
+        let mut changes = Changes::new(&dir.path().join("webdb.dat")).unwrap();
+        std::thread::sleep(VERSION_INTERVAL);
+        assert!(!changes.changed());
+
+        std::fs::write(dir.path().join("cache.dat"), "").unwrap();
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(!changes.changed());
+    }
+}
@@ -0,0 +1,202 @@
+//! Reading webdb.dat without getting in the way of the client, which owns it.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use rusqlite::{Connection, ErrorCode, OpenFlags, Row};
+
+/// How long SQLite waits for the client to release a lock, blocking the poll loop meanwhile.
+const BUSY_TIMEOUT: Duration = Duration::from_millis(200);
+/// Reads tried before copying the database, each waiting up to `BUSY_TIMEOUT`.
+const RETRIES: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_millis(50);
+/// Copies tried, a copy is thrown away if the files changed meanwhile.
+const SNAPSHOT_ATTEMPTS: u32 = 3;
+
+/// The database and its journals, which make a consistent copy together.
+const SUFFIXES: [&str; 3] = ["", "-wal", "-journal"];
+
+/// webdb.dat, opened read-only.
+pub struct Database {
+    path: PathBuf,
+    conn: Connection,
+}
+
+impl Database {
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        Ok(Database {
+            path: path.to_path_buf(),
+            conn,
+        })
+    }
+
+    /// Runs a query returning a single row. While the client holds a lock for longer than a few
+    /// retries, or left a journal behind that only a writer could replay, reads a copy instead.
+    pub fn query_row<T>(
+        &self,
+        sql: &str,
+        f: impl Fn(&Row<'_>) -> rusqlite::Result<T>,
+    ) -> Result<T, String> {
+        let mut attempt = 1;
+        let err = loop {
+            match self.conn.query_row(sql, [], &f) {
+                Err(err) if is_locked(&err) && attempt < RETRIES => {
+                    attempt += 1;
+                    std::thread::sleep(RETRY_DELAY);
+                }
+                Err(err) if is_locked(&err) || needs_writer(&err) => break err,
+                result => return result.map_err(|err| err.to_string()),
+            }
+        };
+        log::debug!(
+            "Unable to read {}, reading a copy of it: {}",
+            self.path.display(),
+            err
+        );
+        let (conn, _dir) = self.snapshot()?;
+        conn.query_row(sql, [], &f).map_err(|err| err.to_string())
+    }
+
+    fn files(&self) -> Vec<PathBuf> {
+        SUFFIXES
+            .iter()
+            .map(|suffix| {
+                let mut x = self.path.clone().into_os_string();
+                x.push(suffix);
+                PathBuf::from(x)
+            })
+            .collect()
+    }
+
+    /// Copies the database with its journals to a temporary directory, where SQLite replays them
+    /// when opening it. The directory must outlive the connection.
+    fn snapshot(&self) -> Result<(Connection, tempfile::TempDir), String> {
+        let files = self.files();
+        for _ in 0..SNAPSHOT_ATTEMPTS {
+            let dir = tempfile::Builder::new()
+                .prefix("netease-watcher-")
+                .tempdir()
+                .map_err(|err| format!("unable to create a temporary directory: {}", err))?;
+            let before = stamps(&files);
+            for file in &files {
+                let Some(name) = file.file_name() else {
+                    continue;
+                };
+                match fs::copy(file, dir.path().join(name)) {
+                    Ok(_) => {}
+                    // the journals only exist at times
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                    Err(err) => return Err(format!("unable to copy {}: {}", file.display(), err)),
+                }
+            }
+            if stamps(&files) != before {
+                log::debug!("{} changed while being copied", self.path.display());
+                continue;
+            }
+            let copy = dir.path().join(files[0].file_name().unwrap_or_default());
+            let conn = Connection::open(copy).map_err(|err| err.to_string())?;
+            return Ok((conn, dir));
+        }
+        Err(format!(
+            "{} kept changing while being copied",
+            self.path.display()
+        ))
+    }
+}
+
+fn is_locked(err: &rusqlite::Error) -> bool {
+    matches!(
+        err.sqlite_error_code(),
+        Some(ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked)
+    )
+}
+
+/// Whether a read-only connection can't read the database as it is, e.g. a crash left a journal
+/// to roll back, or the WAL index is missing and can't be created.
+fn needs_writer(err: &rusqlite::Error) -> bool {
+    matches!(
+        err.sqlite_error_code(),
+        Some(ErrorCode::ReadOnly | ErrorCode::CannotOpen)
+    )
+}
+
+/// The size and modification time of each file, to tell whether they changed.
+fn stamps(files: &[PathBuf]) -> Vec<Option<(u64, SystemTime)>> {
+    files
+        .iter()
+        .map(|x| {
+            let metadata = fs::metadata(x).ok()?;
+            Some((metadata.len(), metadata.modified().ok()?))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::netease::create_webdb;
+
+    const LATEST: &str = "SELECT jsonStr FROM historyTracks ORDER BY playtime DESC LIMIT 1";
+
+    #[test]
+    fn reads_without_writing() {
+        let dir = tempfile::tempdir().unwrap();
+        drop(create_webdb(dir.path(), "delete"));
+        let db = Database::open(&dir.path().join("webdb.dat")).unwrap();
+
+        assert_eq!(
+            db.query_row(LATEST, |row| row.get(0)),
+            Ok("first".to_string())
+        );
+        assert!(db.conn.execute("DELETE FROM historyTracks", []).is_err());
+    }
+
+    #[test]
+    fn reads_a_copy_while_locked() {
+        let dir = tempfile::tempdir().unwrap();
+        let client = create_webdb(dir.path(), "delete");
+        let db = Database::open(&dir.path().join("webdb.dat")).unwrap();
+        // a write being committed, with the journal holding what the file had before
+        client
+            .execute_batch(
+                "PRAGMA locking_mode = EXCLUSIVE;
+                BEGIN EXCLUSIVE;
+                INSERT INTO historyTracks VALUES ('second', 2);",
+            )
+            .unwrap();
+        client.cache_flush().unwrap();
+
+        assert_eq!(
+            db.query_row(LATEST, |row| row.get(0)),
+            Ok("first".to_string())
+        );
+        client.execute_batch("COMMIT;").unwrap();
+        drop(client);
+        assert_eq!(
+            db.query_row(LATEST, |row| row.get(0)),
+            Ok("second".to_string())
+        );
+    }
+
+    #[test]
+    fn reads_a_copy_with_the_wal() {
+        let dir = tempfile::tempdir().unwrap();
+        let client = create_webdb(dir.path(), "wal");
+        client
+            .execute_batch(
+                "PRAGMA wal_autocheckpoint = 0;
+                INSERT INTO historyTracks VALUES ('second', 2);",
+            )
+            .unwrap();
+        let db = Database::open(&dir.path().join("webdb.dat")).unwrap();
+
+        let (conn, _dir) = db.snapshot().unwrap();
+        let latest: String = conn.query_row(LATEST, [], |row| row.get(0)).unwrap();
+        assert_eq!(latest, "second");
+    }
+}
@@ -0,0 +1,235 @@
+//! Keeps track of every attached client, and of the primary one the endpoints without an instance
+//! follow.
+
+use std::path::{Path, PathBuf};
+
+use tokio::sync::watch;
+
+use crate::{Music, Player};
+
+/// The state of a client, as sent by its watcher.
+#[derive(Clone)]
+pub struct Channels {
+    pub time: watch::Sender<f64>,
+    pub music: watch::Sender<Option<Music>>,
+    pub player: watch::Sender<Player>,
+}
+
+impl Default for Channels {
+    fn default() -> Self {
+        Channels {
+            time: watch::Sender::new(-1.),
+            music: watch::Sender::new(None),
+            player: watch::Sender::new(Player::default()),
+        }
+    }
+}
+
+impl Channels {
+    /// Back to nothing being attached.
+    fn reset(&self) {
+        self.time.send_replace(-1.);
+        self.music.send_replace(None);
+        self.player.send_replace(Player::default());
+    }
+
+    /// Takes the state of the instance, notifying only about what changed.
+    fn copy(&self, instance: &Instance) {
+        fn copy<T: Clone + PartialEq>(tx: &watch::Sender<T>, rx: &watch::Receiver<T>) {
+            let value = rx.borrow().clone();
+            tx.send_if_modified(|x| {
+                if *x == value {
+                    return false;
+                }
+                *x = value;
+                true
+            });
+        }
+        copy(&self.time, &instance.time);
+        copy(&self.music, &instance.music);
+        copy(&self.player, &instance.player);
+    }
+}
+
+/// An attached client.
+#[derive(Clone, Debug)]
+pub struct Instance {
+    pub pid: u32,
+    /// the Wine prefix it runs in
+    pub prefix: Option<PathBuf>,
+    pub primary: bool,
+    pub time: watch::Receiver<f64>,
+    pub music: watch::Receiver<Option<Music>>,
+    pub player: watch::Receiver<Player>,
+}
+
+impl Instance {
+    /// Whether `key`, a pid or a Wine prefix, refers to this instance.
+    pub fn matches(&self, key: &str) -> bool {
+        key.parse() == Ok(self.pid)
+            || self
+                .prefix
+                .as_deref()
+                .is_some_and(|x| x == Path::new(key.trim_end_matches('/')))
+    }
+}
+
+pub struct Registry {
+    primary: Channels,
+    /// a pid or Wine prefix of the instance to follow when it's attached
+    preferred: Option<String>,
+    instances: watch::Sender<Vec<Instance>>,
+}
+
+impl Registry {
+    pub fn new(primary: Channels, preferred: Option<String>) -> Self {
+        Registry {
+            primary,
+            preferred,
+            instances: watch::Sender::new(Vec::new()),
+        }
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<Vec<Instance>> {
+        self.instances.subscribe()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instances.borrow().is_empty()
+    }
+
+    pub fn add(&self, pid: u32, prefix: Option<PathBuf>, channels: &Channels) {
+        self.update(|x| {
+            x.push(Instance {
+                pid,
+                prefix,
+                primary: false,
+                time: channels.time.subscribe(),
+                music: channels.music.subscribe(),
+                player: channels.player.subscribe(),
+            })
+        });
+    }
+
+    pub fn remove(&self, pid: u32) {
+        self.update(|x| x.retain(|x| x.pid != pid));
+    }
+
+    /// Passes the state of the instance on to the primary channels, if it's the primary one.
+    pub fn sync(&self, pid: u32) {
+        let instances = self.instances.borrow();
+        if let Some(instance) = instances.iter().find(|x| x.pid == pid && x.primary) {
+            self.primary.copy(instance);
+        }
+    }
+
+    /// Changes the instances, then follows the preferred instance if it's attached, or else the
+    /// one attached first.
+    fn update(&self, change: impl FnOnce(&mut Vec<Instance>)) {
+        let mut changed = None;
+        self.instances.send_modify(|instances| {
+            let current = instances.iter().find(|x| x.primary).map(|x| x.pid);
+            change(instances);
+            let elected = self
+                .preferred
+                .as_deref()
+                .and_then(|key| instances.iter().position(|x| x.matches(key)))
+                .or(if instances.is_empty() { None } else { Some(0) });
+            for (i, instance) in instances.iter_mut().enumerate() {
+                instance.primary = Some(i) == elected;
+            }
+            if elected.map(|x| instances[x].pid) != current {
+                changed = Some(elected.map(|x| instances[x].clone()));
+            }
+        });
+        match changed {
+            Some(Some(instance)) => {
+                log::info!(
+                    event = "primary",
+                    pid = instance.pid;
+                    "Following the instance {}{}",
+                    instance.pid,
+                    match &instance.prefix {
+                        Some(prefix) => format!(" in {}", prefix.display()),
+                        None => "".to_string(),
+                    }
+                );
+                self.primary.copy(&instance);
+            }
+            Some(None) => self.primary.reset(),
+            None => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl Registry {
+        fn primary_pid(&self) -> Option<u32> {
+            self.subscribe()
+                .borrow()
+                .iter()
+                .find(|x| x.primary)
+                .map(|x| x.pid)
+        }
+    }
+
+    #[test]
+    fn follows_the_first_instance() {
+        let primary = Channels::default();
+        let registry = Registry::new(primary.clone(), None);
+        let (a, b) = (Channels::default(), Channels::default());
+        a.time.send_replace(1.);
+        b.time.send_replace(2.);
+
+        registry.add(10, None, &a);
+        registry.add(20, None, &b);
+        assert_eq!(registry.primary_pid(), Some(10));
+        assert_eq!(*primary.time.borrow(), 1.);
+
+        // only the primary one is passed on
+        a.time.send_replace(3.);
+        b.time.send_replace(4.);
+        registry.sync(20);
+        assert_eq!(*primary.time.borrow(), 1.);
+        registry.sync(10);
+        assert_eq!(*primary.time.borrow(), 3.);
+
+        registry.remove(10);
+        assert_eq!(registry.primary_pid(), Some(20));
+        assert_eq!(*primary.time.borrow(), 4.);
+
+        registry.remove(20);
+        assert_eq!(registry.primary_pid(), None);
+        assert_eq!(*primary.time.borrow(), -1.);
+        assert!(registry.subscribe().borrow().is_empty());
+    }
+
+    #[test]
+    fn follows_the_preferred_instance() {
+        let primary = Channels::default();
+        let registry = Registry::new(primary.clone(), Some("/home/bob/.wine/".to_string()));
+        let (a, b) = (Channels::default(), Channels::default());
+        b.time.send_replace(2.);
+
+        registry.add(10, Some(PathBuf::from("/home/alice/.wine")), &a);
+        assert_eq!(registry.primary_pid(), Some(10));
+        registry.add(20, Some(PathBuf::from("/home/bob/.wine")), &b);
+        assert_eq!(registry.primary_pid(), Some(20));
+        assert_eq!(*primary.time.borrow(), 2.);
+    }
+
+    #[test]
+    fn matches_pids_and_prefixes() {
+        let registry = Registry::new(Channels::default(), None);
+        registry.add(10, Some(PathBuf::from("/pfx")), &Channels::default());
+        let instance = registry.subscribe().borrow()[0].clone();
+        assert!(instance.matches("10"));
+        assert!(instance.matches("/pfx"));
+        assert!(instance.matches("/pfx/"));
+        assert!(!instance.matches("1"));
+        assert!(!instance.matches("/other"));
+    }
+}
@@ -0,0 +1,209 @@
+//! Remembers where the playback time of each build of cloudmusic.dll is, so attaching again
+//! doesn't need to scan the module and watch the candidates.
+
+use std::{collections::HashMap, fs, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{netease::probation::Candidate, signature::Signature};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub signature: String,
+    /// where the signature matched, relative to the module
+    pub instruction_rva: usize,
+    /// where the playback time is, relative to the module
+    pub rva: usize,
+}
+
+/// Entries are keyed by the fingerprint of the module headers, see [`crate::pe::fingerprint`].
+pub struct OffsetCache {
+    path: PathBuf,
+    entries: HashMap<String, Entry>,
+}
+
+impl OffsetCache {
+    pub fn default_path() -> PathBuf {
+        dirs::cache_dir()
+            .unwrap_or_else(std::env::temp_dir)
+            .join("netease-watcher")
+            .join("offsets.json")
+    }
+
+    /// Starts empty if the file is missing or broken.
+    pub fn load(path: PathBuf) -> Self {
+        let entries = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|err| {
+                log::warn!(
+                    "Unable to parse the offset cache {}: {}",
+                    path.display(),
+                    err
+                );
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+        OffsetCache { path, entries }
+    }
+
+    /// The cached playback time of the module at `base`, if it still checks out against the
+    /// memory. `read` reads the given number of bytes at an address.
+    pub fn get<'a>(
+        &self,
+        key: &str,
+        base: usize,
+        signatures: &[&'a Signature],
+        read: impl FnOnce(usize, usize) -> Option<Vec<u8>>,
+    ) -> Option<Candidate<'a>> {
+        let entry = self.entries.get(key)?;
+        let candidate = verify(entry, base, signatures, read);
+        if candidate.is_none() {
+            log::info!(
+                "The cached playback time of signature {} at RVA {:#x} doesn't check out, scanning again",
+                entry.signature,
+                entry.rva
+            );
+        }
+        candidate
+    }
+
+    /// Remembers the playback time of the module at `base`, and saves the cache.
+    pub fn insert(&mut self, key: String, base: usize, candidate: &Candidate) {
+        let entry = Entry {
+            signature: candidate.signature.name.clone(),
+            instruction_rva: candidate.instruction.wrapping_sub(base),
+            rva: candidate.addr.wrapping_sub(base),
+        };
+        if self.entries.get(&key) == Some(&entry) {
+            return;
+        }
+        self.entries.insert(key, entry);
+        if let Err(err) = self.save() {
+            log::warn!(
+                "Unable to save the offset cache {}: {}",
+                self.path.display(),
+                err
+            );
+        }
+    }
+
+    fn save(&self) -> std::io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&self.path, serde_json::to_string_pretty(&self.entries)?)
+    }
+}
+
+/// Checks that the signature of the entry still applies and matches at the instruction, and that
+/// it still refers to the playback time.
+fn verify<'a>(
+    entry: &Entry,
+    base: usize,
+    signatures: &[&'a Signature],
+    read: impl FnOnce(usize, usize) -> Option<Vec<u8>>,
+) -> Option<Candidate<'a>> {
+    let signature = *signatures.iter().find(|x| x.name == entry.signature)?;
+    let instruction = base.wrapping_add(entry.instruction_rva);
+    let addr = base.wrapping_add(entry.rva);
+    let buf = read(instruction, signature.pattern_len())?;
+    if signature.find(&buf).first() != Some(&0)
+        || signature.resolve(&buf, 0, instruction) != Some(addr)
+    {
+        return None;
+    }
+    Some(Candidate {
+        addr,
+        instruction,
+        signature,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signature::{Arch, SignatureDb};
+
+    const BASE: usize = 0x1_8000_0000;
+
+    /// movsd [rip+0x100], xmm7; movsd [rip+...], xmm6 at RVA 0x1010
+    fn read(addr: usize, len: usize) -> Option<Vec<u8>> {
+        let mut code = vec![0x90; 0x10];
+        code.extend_from_slice(&[0xf2, 0x0f, 0x11, 0x3d, 0x00, 0x01, 0x00, 0x00]);
+        code.extend_from_slice(&[0xf2, 0x0f, 0x11, 0x35, 0, 0, 0, 0]);
+        let start = addr.checked_sub(BASE + 0x1000)?;
+        code.get(start..start + len).map(|x| x.to_vec())
+    }
+
+    #[test]
+    fn reuses_checked_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache").join("offsets.json");
+        let db = SignatureDb::builtin();
+        let signatures = db.select(Arch::X64, None);
+
+        let mut cache = OffsetCache::load(path.clone());
+        assert!(cache.get("key", BASE, &signatures, read).is_none());
+        let candidate = Candidate {
+            addr: BASE + 0x1118,
+            instruction: BASE + 0x1010,
+            signature: signatures[0],
+        };
+        cache.insert("key".to_string(), BASE, &candidate);
+
+        // loaded again after a restart, at another base
+        let cache = OffsetCache::load(path);
+        let base = BASE + 0x10_0000;
+        let rebased = |addr: usize, len| read(addr - 0x10_0000, len);
+        let found = cache.get("key", base, &signatures, rebased).unwrap();
+        assert_eq!(found.addr, base + 0x1118);
+        assert_eq!(found.instruction, base + 0x1010);
+        assert_eq!(found.signature.name, "time-x64");
+        assert!(cache.get("other", base, &signatures, rebased).is_none());
+    }
+
+    #[test]
+    fn rejects_stale_entries() {
+        let db = SignatureDb::builtin();
+        let signatures = db.select(Arch::X64, None);
+        let entry = |signature: &str, instruction_rva, rva| Entry {
+            signature: signature.to_string(),
+            instruction_rva,
+            rva,
+        };
+
+        assert!(verify(&entry("time-x64", 0x1010, 0x1118), BASE, &signatures, read).is_some());
+        // the code moved
+        assert!(verify(&entry("time-x64", 0x1000, 0x1118), BASE, &signatures, read).is_none());
+        // the operand changed
+        assert!(verify(&entry("time-x64", 0x1010, 0x2000), BASE, &signatures, read).is_none());
+        // the signature doesn't apply anymore
+        assert!(verify(&entry("time-x86", 0x1010, 0x1118), BASE, &signatures, read).is_none());
+        // unreadable
+        assert!(verify(
+            &entry("time-x64", 0x1010, 0x1118),
+            BASE,
+            &signatures,
+            |_, _| None
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn replaces_broken_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("offsets.json");
+        fs::write(&path, "not json").unwrap();
+        let mut cache = OffsetCache::load(path.clone());
+        assert!(cache.entries.is_empty());
+
+        let db = SignatureDb::builtin();
+        let candidate = Candidate {
+            addr: BASE + 0x1118,
+            instruction: BASE + 0x1010,
+            signature: db.select(Arch::X64, None)[0],
+        };
+        cache.insert("key".to_string(), BASE, &candidate);
+        assert_eq!(OffsetCache::load(path).entries.len(), 1);
+    }
+}
@@ -0,0 +1,164 @@
+//! Reads the state of the player besides the playback time, from the variables located by the
+//! signatures of the other variables.
+
+use crate::{
+    netease::probation::Candidate,
+    signature::{Signature, Variable},
+    Player,
+};
+
+/// The located variables, read on every poll.
+#[derive(Default)]
+pub struct PlayerReader<'a> {
+    variables: Vec<Candidate<'a>>,
+}
+
+impl<'a> PlayerReader<'a> {
+    /// Takes the first candidate of each variable. The playback time is left out, it's picked by
+    /// the probation.
+    pub fn new(candidates: impl IntoIterator<Item = Candidate<'a>>) -> Self {
+        let mut variables: Vec<Candidate> = Vec::new();
+        for candidate in candidates {
+            let variable = candidate.signature.variable;
+            if variable != Variable::Time
+                && !variables.iter().any(|x| x.signature.variable == variable)
+            {
+                variables.push(candidate);
+            }
+        }
+        PlayerReader { variables }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.variables.is_empty()
+    }
+
+    pub fn variables(&self) -> &[Candidate<'a>] {
+        &self.variables
+    }
+
+    /// `read` reads the given number of bytes at an address.
+    pub fn read(&self, read: impl Fn(usize, usize) -> Option<Vec<u8>>) -> Player {
+        let mut player = Player::default();
+        for variable in &self.variables {
+            let signature = variable.signature;
+            let Some(value) = read(variable.addr, signature.value_type.size())
+                .and_then(|buf| signature.value_type.decode(&buf))
+            else {
+                continue;
+            };
+            apply(&mut player, signature, value);
+        }
+        player
+    }
+}
+
+/// Sets the variable of the signature, leaving it unknown if the value makes no sense for it.
+fn apply(player: &mut Player, signature: &Signature, value: f64) {
+    let flag = || match value {
+        0. => Some(false),
+        1. => Some(true),
+        _ => None,
+    };
+    match signature.variable {
+        Variable::Time => {}
+        Variable::Volume => {
+            let volume = value / signature.volume_max.unwrap_or(1.);
+            player.volume = (0. ..=1.).contains(&volume).then_some(volume);
+        }
+        Variable::Muted => player.muted = flag(),
+        Variable::Paused => player.paused = flag(),
+        Variable::PlayMode => {
+            player.play_mode = signature
+                .play_modes
+                .iter()
+                .find(|(_, x)| **x as f64 == value)
+                .map(|(mode, _)| *mode);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::{
+        signature::{Addressing, Arch, ValueType},
+        PlayMode,
+    };
+
+    fn signature(variable: Variable, value_type: ValueType) -> Signature {
+        Signature {
+            name: variable.to_string(),
+            arch: Arch::X64,
+            pattern: "00 00 00 00".to_string(),
+            operand_offset: 0,
+            addressing: Addressing::Absolute,
+            instruction_len: 0,
+            min_version: None,
+            max_version: None,
+            variable,
+            value_type,
+            volume_max: None,
+            play_modes: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn reads_variables() {
+        let volume = Signature {
+            volume_max: Some(100.),
+            ..signature(Variable::Volume, ValueType::I32)
+        };
+        let muted = signature(Variable::Muted, ValueType::U8);
+        let paused = signature(Variable::Paused, ValueType::U8);
+        let play_mode = Signature {
+            play_modes: HashMap::from([(PlayMode::Loop, 1), (PlayMode::Shuffle, 3)]),
+            ..signature(Variable::PlayMode, ValueType::I32)
+        };
+        let time = signature(Variable::Time, ValueType::F64);
+        let other_muted = signature(Variable::Muted, ValueType::U8);
+        let candidate = |addr, signature| Candidate {
+            addr,
+            instruction: 0,
+            signature,
+        };
+        let reader = PlayerReader::new([
+            candidate(0x10, &time),
+            candidate(0x20, &volume),
+            candidate(0x30, &muted),
+            candidate(0x40, &paused),
+            candidate(0x50, &play_mode),
+            candidate(0x60, &other_muted),
+        ]);
+        assert_eq!(reader.variables().len(), 4);
+
+        let memory = |values: [u8; 4]| {
+            move |addr: usize, len: usize| -> Option<Vec<u8>> {
+                let value = match addr {
+                    0x20 => values[0],
+                    0x30 => values[1],
+                    0x40 => values[2],
+                    0x50 => values[3],
+                    _ => return None,
+                };
+                let mut buf = vec![0; len];
+                buf[0] = value;
+                Some(buf)
+            }
+        };
+        assert_eq!(
+            reader.read(memory([50, 0, 1, 3])),
+            Player {
+                volume: Some(0.5),
+                muted: Some(false),
+                play_mode: Some(PlayMode::Shuffle),
+                paused: Some(true),
+            }
+        );
+        // values that make no sense are unknown
+        assert_eq!(reader.read(memory([200, 2, 7, 2])), Player::default());
+        assert_eq!(reader.read(|_, _| None), Player::default());
+    }
+}
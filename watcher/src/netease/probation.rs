@@ -0,0 +1,351 @@
+//! Picks the playback time among the addresses the signatures matched, by watching how their
+//! values change for a while.
+
+use std::time::{Duration, Instant};
+
+use tokio::sync::oneshot;
+
+use crate::{config, signature::Signature};
+
+/// How long the candidates are watched before one is picked.
+pub const WINDOW: Duration = Duration::from_secs(3);
+
+/// Samples are taken at least this often, even with a longer poll interval.
+const MAX_SAMPLE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The rate a playback clock advances at, in seconds per second.
+const MIN_RATE: f64 = 0.5;
+const MAX_RATE: f64 = 2.0;
+
+/// Allowed error of the playback time, it might be updated in steps.
+const SLACK: f64 = 0.5;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Candidate<'a> {
+    pub addr: usize,
+    /// where the signature matched
+    pub instruction: usize,
+    pub signature: &'a Signature,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Verdict {
+    /// Advanced like a playback clock.
+    Clock,
+    /// Didn't change, which is what the playback time does while paused.
+    Still,
+    Rejected(String),
+}
+
+struct Tracked<'a> {
+    candidate: Candidate<'a>,
+    /// the time since the start and the value of the first and the last sample
+    first: Option<(f64, f64)>,
+    last: Option<(f64, f64)>,
+    rejected: Option<String>,
+}
+
+impl Tracked<'_> {
+    fn sample(&mut self, at: f64, value: f64, duration: Option<f64>) {
+        if self.rejected.is_some() {
+            return;
+        }
+        self.rejected = self.check(at, value, duration);
+        if self.first.is_none() {
+            self.first = Some((at, value));
+        }
+        self.last = Some((at, value));
+    }
+
+    fn check(&self, at: f64, value: f64, duration: Option<f64>) -> Option<String> {
+        if !value.is_finite() || value < 0. {
+            return Some(format!("unreadable or negative value {}", value));
+        }
+        if let Some(duration) = duration.filter(|x| *x > 0.) {
+            if value > duration + SLACK {
+                return Some(format!(
+                    "{:.3}s is beyond the track duration {:.3}s",
+                    value, duration
+                ));
+            }
+        }
+        let (last_at, last_value) = self.last?;
+        if value < last_value {
+            return Some(format!(
+                "went backwards from {:.3}s to {:.3}s",
+                last_value, value
+            ));
+        }
+        if value - last_value > (at - last_at) * MAX_RATE + SLACK {
+            return Some(format!(
+                "jumped from {:.3}s to {:.3}s in {:.3}s",
+                last_value,
+                value,
+                at - last_at
+            ));
+        }
+        None
+    }
+
+    fn verdict(&self) -> Verdict {
+        if let Some(reason) = &self.rejected {
+            return Verdict::Rejected(reason.clone());
+        }
+        let (Some((first_at, first_value)), Some((last_at, last_value))) = (self.first, self.last)
+        else {
+            return Verdict::Rejected("never sampled".to_string());
+        };
+        let advanced = last_value - first_value;
+        if advanced == 0. {
+            return Verdict::Still;
+        }
+        let elapsed = last_at - first_at;
+        if advanced < elapsed * MIN_RATE - SLACK {
+            return Verdict::Rejected(format!("advanced only {:.3}s in {:.3}s", advanced, elapsed));
+        }
+        Verdict::Clock
+    }
+}
+
+/// Every match of the signatures in the memory at `base`, in the order of the signatures.
+pub fn find_candidates<'a>(
+    buf: &[u8],
+    base: usize,
+    signatures: &[&'a Signature],
+) -> Vec<Candidate<'a>> {
+    signatures
+        .iter()
+        .flat_map(|signature| {
+            signature.find(buf).into_iter().filter_map(|offset| {
+                Some(Candidate {
+                    addr: signature.resolve(buf, offset, base)?,
+                    instruction: base + offset,
+                    signature,
+                })
+            })
+        })
+        .collect()
+}
+
+/// The candidates under watch.
+pub struct Probation<'a> {
+    tracked: Vec<Tracked<'a>>,
+}
+
+impl<'a> Probation<'a> {
+    /// Candidates at the same address are only watched once, the first one is kept.
+    pub fn new(candidates: impl IntoIterator<Item = Candidate<'a>>) -> Self {
+        let mut tracked: Vec<Tracked> = Vec::new();
+        for candidate in candidates {
+            if tracked.iter().any(|x| x.candidate.addr == candidate.addr) {
+                continue;
+            }
+            tracked.push(Tracked {
+                candidate,
+                first: None,
+                last: None,
+                rejected: None,
+            });
+        }
+        Probation { tracked }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tracked.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tracked.len()
+    }
+
+    /// Reads every candidate, `at` is the time since the probation started in seconds and
+    /// `duration` the duration of the playing track in seconds.
+    pub fn sample(&mut self, at: f64, mut read: impl FnMut(usize) -> f64, duration: Option<f64>) {
+        for tracked in &mut self.tracked {
+            let value = read(tracked.candidate.addr);
+            tracked.sample(at, value, duration);
+        }
+    }
+
+    /// Samples the candidates until the end of the window, returns whether it's stopped.
+    pub fn run(
+        &mut self,
+        mut read: impl FnMut(usize) -> f64,
+        duration: Option<f64>,
+        stop_signal: &mut oneshot::Receiver<()>,
+    ) -> bool {
+        let start = Instant::now();
+        loop {
+            if stop_signal.try_recv().is_ok() {
+                return true;
+            }
+            let elapsed = start.elapsed();
+            self.sample(elapsed.as_secs_f64(), &mut read, duration);
+            if elapsed >= WINDOW {
+                return false;
+            }
+            let interval = config::get()
+                .watcher
+                .poll_interval()
+                .min(MAX_SAMPLE_INTERVAL);
+            std::thread::sleep(interval.min(WINDOW - elapsed));
+        }
+    }
+
+    pub fn verdicts(&self) -> impl Iterator<Item = (Candidate<'a>, Verdict)> + '_ {
+        self.tracked.iter().map(|x| (x.candidate, x.verdict()))
+    }
+
+    /// The first candidate advancing like a clock, or the first one that didn't change if none
+    /// did, e.g. while paused.
+    pub fn pick(&self) -> Option<(Candidate<'a>, Verdict)> {
+        let verdicts: Vec<_> = self.verdicts().collect();
+        [Verdict::Clock, Verdict::Still]
+            .into_iter()
+            .find_map(|wanted| {
+                verdicts
+                    .iter()
+                    .find(|(_, verdict)| *verdict == wanted)
+                    .map(|(candidate, _)| (*candidate, wanted))
+            })
+    }
+
+    /// Logs the rejected candidates and picks one.
+    pub fn finish(&self) -> Option<(Candidate<'a>, Verdict)> {
+        for (candidate, verdict) in self.verdicts() {
+            if let Verdict::Rejected(reason) = verdict {
+                log::info!(
+                    event = "reject_candidate";
+                    "Rejected the playback time candidate at {:#x} of signature {}: {}",
+                    candidate.addr,
+                    candidate.signature.name,
+                    reason
+                );
+            }
+        }
+        let picked = self.pick();
+        if let Some((candidate, Verdict::Still)) = &picked {
+            log::info!(
+                "None of the playback time candidates advanced, probably paused, using the one at {:#x}",
+                candidate.addr
+            );
+        }
+        picked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signature::{Arch, SignatureDb};
+
+    fn probation<'a>(db: &'a SignatureDb, addrs: &[usize]) -> Probation<'a> {
+        let signature = db.select(Arch::X64, None)[0];
+        Probation::new(addrs.iter().map(|addr| Candidate {
+            addr: *addr,
+            instruction: 0,
+            signature,
+        }))
+    }
+
+    /// Samples every 0.25s for 3s, `values` gives the value of each address at a time.
+    fn run(probation: &mut Probation, values: impl Fn(usize, f64) -> f64, duration: Option<f64>) {
+        for i in 0..=12 {
+            let at = i as f64 * 0.25;
+            probation.sample(at, |addr| values(addr, at), duration);
+        }
+    }
+
+    fn verdicts(probation: &Probation) -> Vec<(usize, Verdict)> {
+        probation
+            .verdicts()
+            .map(|(candidate, verdict)| (candidate.addr, verdict))
+            .collect()
+    }
+
+    #[test]
+    fn picks_the_clock() {
+        let db = SignatureDb::builtin();
+        let mut probation = probation(&db, &[0x10, 0x20, 0x30, 0x40, 0x50]);
+        run(
+            &mut probation,
+            |addr, at| match addr {
+                0x10 => 1e6,
+                0x20 => 42. - at,
+                0x30 => 7.,
+                // updated in steps of a second
+                0x40 => 100. + at.floor(),
+                _ => at * 100.,
+            },
+            Some(240.),
+        );
+        assert_eq!(
+            verdicts(&probation),
+            [
+                (
+                    0x10,
+                    Verdict::Rejected(
+                        "1000000.000s is beyond the track duration 240.000s".to_string()
+                    )
+                ),
+                (
+                    0x20,
+                    Verdict::Rejected("went backwards from 42.000s to 41.750s".to_string())
+                ),
+                (0x30, Verdict::Still),
+                (0x40, Verdict::Clock),
+                (
+                    0x50,
+                    Verdict::Rejected("jumped from 0.000s to 25.000s in 0.250s".to_string())
+                ),
+            ]
+        );
+        assert_eq!(probation.pick().map(|(x, _)| x.addr), Some(0x40));
+    }
+
+    #[test]
+    fn picks_a_still_candidate_while_paused() {
+        let db = SignatureDb::builtin();
+        let mut probation = probation(&db, &[0x10, 0x20, 0x30]);
+        run(
+            &mut probation,
+            |addr, _| if addr == 0x10 { -1. } else { 12.5 },
+            None,
+        );
+        assert_eq!(
+            verdicts(&probation),
+            [
+                (
+                    0x10,
+                    Verdict::Rejected("unreadable or negative value -1".to_string())
+                ),
+                (0x20, Verdict::Still),
+                (0x30, Verdict::Still),
+            ]
+        );
+        assert_eq!(probation.pick().map(|(x, _)| x.addr), Some(0x20));
+    }
+
+    #[test]
+    fn rejects_slow_counters() {
+        let db = SignatureDb::builtin();
+        let mut probation = probation(&db, &[0x10]);
+        run(&mut probation, |_, at| at * 0.1, None);
+        assert_eq!(
+            verdicts(&probation),
+            [(
+                0x10,
+                Verdict::Rejected("advanced only 0.300s in 3.000s".to_string())
+            )]
+        );
+        assert_eq!(probation.pick().map(|(x, _)| x.addr), None);
+    }
+
+    #[test]
+    fn deduplicates_addresses() {
+        let db = SignatureDb::builtin();
+        let probation = probation(&db, &[0x10, 0x20, 0x10]);
+        assert_eq!(probation.len(), 2);
+        assert!(Probation::new([]).is_empty());
+    }
+}
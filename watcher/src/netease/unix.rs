@@ -1,215 +1,464 @@
 use std::{
-    path::Path,
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    thread::JoinHandle,
     time::{Duration, Instant},
 };
 
-use notify::EventKind;
-use rusqlite::Connection;
+use procfs::process::Process;
 use tokio::sync::{
-    oneshot,
+    oneshot::{self, error::TryRecvError},
     watch::{self, Receiver, Sender},
 };
 
 use crate::{
+    config,
     netease::{
-        create_file_watcher, stoppable_sleep, unix::util::determine_is_64_bit, update_music,
-        FIND_RETRY_SECS,
+        changes::Changes,
+        database::Database,
+        instances::{Channels, Registry},
+        offsets::OffsetCache,
+        player::PlayerReader,
+        probation::{Candidate, Probation, Verdict},
+        track_duration,
+        unix::{
+            launcher::in_root,
+            lifecycle::{Activity, Exit},
+            mem::{Probe, Strategy},
+            native::Bus,
+            util::{Module, ModuleError},
+            webdb::Context,
+        },
+        update_music, update_player, WatcherOptions,
     },
-    Music,
+    pe,
+    signature::{self, Arch, SignatureDb, Variable},
+    Music, Player,
 };
 
+/// How soon a client that's found but not attached is tried again at first, e.g. cloudmusic.dll
+/// isn't loaded until the main window shows.
+const QUICK_RETRY: Duration = Duration::from_secs(1);
+
+pub(super) mod doctor;
+mod launcher;
+mod lifecycle;
 mod mem;
+mod native;
 mod util;
+mod webdb;
 
 pub struct NeteaseWatcherUnix {
     pub(super) time: (Sender<f64>, Receiver<f64>),
     pub(super) music: (Sender<Option<Music>>, Receiver<Option<Music>>),
+    pub(super) player: (Sender<Player>, Receiver<Player>),
+    pub(super) registry: Arc<Registry>,
     pub(super) scheduled_find_time: (Sender<Option<Instant>>, Receiver<Option<Instant>>),
-    pub(super) watch_thread: Option<(oneshot::Sender<()>, std::thread::JoinHandle<()>)>,
+    pub(super) watch_thread: Option<(oneshot::Sender<()>, JoinHandle<()>)>,
+    options: WatcherOptions,
+}
+
+/// What the threads watching each process share.
+struct Shared {
+    options: WatcherOptions,
+    signatures: SignatureDb,
+    offsets: Mutex<OffsetCache>,
+    registry: Arc<Registry>,
 }
 
 impl NeteaseWatcherUnix {
-    pub fn new() -> Self {
+    pub fn new(options: WatcherOptions) -> Self {
         let (time_tx, time_rx) = watch::channel(-1.0);
         let (music_tx, music_rx) = watch::channel(None);
+        let (player_tx, player_rx) = watch::channel(Player::default());
         let (scheduled_find_time_tx, scheduled_find_time_rx) = watch::channel(Some(Instant::now()));
+        let registry = Registry::new(
+            Channels {
+                time: time_tx.clone(),
+                music: music_tx.clone(),
+                player: player_tx.clone(),
+            },
+            options.primary.clone(),
+        );
         NeteaseWatcherUnix {
             time: (time_tx, time_rx),
             music: (music_tx, music_rx),
+            player: (player_tx, player_rx),
+            registry: Arc::new(registry),
             scheduled_find_time: (scheduled_find_time_tx, scheduled_find_time_rx),
             watch_thread: None,
+            options,
         }
     }
 
     pub fn start(&mut self) {
         let (stop_signal, mut stop_rx) = oneshot::channel();
-        let time_tx = self.time.0.clone();
-        let music_tx = self.music.0.clone();
         let scheduled_find_time_tx = self.scheduled_find_time.0.clone();
         scheduled_find_time_tx.send(Some(Instant::now())).unwrap();
-        let sleep_duration = Duration::from_secs(FIND_RETRY_SECS);
-        let join_handle = std::thread::spawn(move || 'watcher_loop: loop {
-            if stop_rx.try_recv().is_ok() {
-                break 'watcher_loop;
+        let (signatures, errors) = SignatureDb::load(&self.options.signatures);
+        for err in errors {
+            log::warn!("{}", err);
+        }
+        let shared = Arc::new(Shared {
+            options: self.options.clone(),
+            signatures,
+            offsets: Mutex::new(OffsetCache::load(OffsetCache::default_path())),
+            registry: self.registry.clone(),
+        });
+        let join_handle = std::thread::spawn(move || {
+            let mut activity = Activity::new();
+            let bus = Bus::connect();
+            if let Some(bus) = &bus {
+                bus.notify_players(activity.sender());
             }
-            'find_process: {
-                let Ok(processes) = procfs::process::all_processes() else {
-                    break 'find_process;
-                };
-                for process in processes {
-                    let Ok(process) = process else {
-                        continue;
-                    };
-                    let Ok(cmdline) = process.cmdline() else {
-                        continue;
-                    };
-                    let Some(executable) = cmdline.first() else {
-                        continue;
+            for prefix in shared
+                .options
+                .wineprefix
+                .iter()
+                .chain(&dirs::home_dir().map(|x| x.join(".wine")))
+            {
+                activity.watch_prefix(prefix);
+            }
+            if let Some(webdb) = &shared.options.webdb {
+                activity.watch_webdb(webdb);
+            }
+            // a thread for each client process, by pid
+            let mut watching: HashMap<i32, (oneshot::Sender<()>, JoinHandle<()>)> = HashMap::new();
+            loop {
+                if stop_rx.try_recv().is_ok() {
+                    break;
+                }
+                watching.retain(|_, (_, handle)| !handle.is_finished());
+                if let Ok(processes) = procfs::process::all_processes() {
+                    for process in processes.flatten() {
+                        if watching.contains_key(&process.pid) || !is_wanted(&process, &shared) {
+                            continue;
+                        }
+                        let pid = process.pid;
+                        let (stop, mut stop_rx) = oneshot::channel();
+                        let shared = shared.clone();
+                        let handle = std::thread::spawn(move || {
+                            follow_process(&process, &shared, &mut stop_rx)
+                        });
+                        watching.insert(pid, (stop, handle));
+                    }
+                }
+                for (name, pid) in bus.iter().flat_map(Bus::clients) {
+                    let Some(bus) = bus.clone() else {
+                        break;
                     };
-                    if !executable.ends_with("cloudmusic.exe")
-                        || cmdline.iter().any(|x| x.contains("--type"))
+                    let pid = pid as i32;
+                    if watching.contains_key(&pid)
+                        || shared.options.pid.is_some_and(|x| x as i32 != pid)
                     {
                         continue;
                     }
-                    let Ok(maps) = process.maps() else {
-                        log::warn!(
-                            "Unable to read memory maps of the process {}, skipping.",
-                            process.pid
-                        );
-                        continue;
-                    };
-                    let mut in_cloudmusic_map = false;
-                    let mut is_64_bit = false;
-                    'maps: for map in maps {
-                        use procfs::process::{MMPermissions, MMapPath};
-
-                        match &map.pathname {
-                            MMapPath::Path(p) => {
-                                let filename = p.file_name().unwrap_or_default();
-                                if filename == "cloudmusic.dll" {
-                                    in_cloudmusic_map = true;
-                                    if !in_cloudmusic_map {
-                                        // header map, we can determine the bitness of the process from it.
-                                        if let Err(_) = determine_is_64_bit(process.pid, &map)
-                                            .map(|is_64| is_64_bit = is_64)
-                                        {
-                                            log::warn!("Unable to determine if the process {} is 64-bit, might be unsupported.", process.pid);
-                                        }
-                                    }
-                                } else {
-                                    in_cloudmusic_map = false;
-                                }
-                            }
-                            // the following maps of the same module might be anonymous, so we don't set in_cloudmusic_map to false immediately.
-                            MMapPath::Anonymous => {}
-                            _ => {
-                                in_cloudmusic_map = false;
-                            }
-                        }
+                    let (stop, mut stop_rx) = oneshot::channel();
+                    let shared = shared.clone();
+                    let handle = std::thread::spawn(move || {
+                        native::follow_player(&bus, &name, pid as u32, &shared, &mut stop_rx)
+                    });
+                    watching.insert(pid, (stop, handle));
+                }
+                for instance in shared.registry.subscribe().borrow().iter() {
+                    if let Some(prefix) = &instance.prefix {
+                        activity.watch_prefix(prefix);
+                    }
+                }
+                // looking now and then still finds clients that aren't noticed starting, e.g. in
+                // a sandbox with its own /tmp
+                let sleep_duration = if activity.is_active() {
+                    config::get().watcher.rescan_interval()
+                } else {
+                    config::get().watcher.retry_interval()
+                };
+                // None while attached, clients started later are still found
+                scheduled_find_time_tx
+                    .send(if shared.registry.is_empty() {
+                        Some(Instant::now() + sleep_duration)
+                    } else {
+                        None
+                    })
+                    .unwrap();
+                if activity.wait(sleep_duration, &mut stop_rx) {
+                    break;
+                }
+            }
+            for (stop, handle) in watching.into_values() {
+                let _ = stop.send(());
+                let _ = handle.join();
+            }
+        });
+        self.watch_thread = Some((stop_signal, join_handle));
+    }
+}
 
-                        if !in_cloudmusic_map {
-                            continue;
-                        }
+fn is_wanted(process: &Process, shared: &Shared) -> bool {
+    if shared.options.pid.is_some_and(|x| x as i32 != process.pid) {
+        return false;
+    }
+    process.cmdline().is_ok_and(|x| util::is_client(&x))
+}
 
-                        if !map.perms.contains(MMPermissions::EXECUTE) {
-                            // instructions must be in an executable map, so skip if it's not.
-                            continue;
-                        }
+/// Tries to attach to the process until it exits or the watcher stops, sooner at first as the
+/// client is likely still starting.
+fn follow_process(process: &Process, shared: &Shared, stop_rx: &mut oneshot::Receiver<()>) {
+    // opened first, so it's this process even if the pid is reused
+    let exit = Exit::of(process.pid);
+    let mut delay = QUICK_RETRY;
+    loop {
+        watch_process(process, shared, &exit, stop_rx);
+        shared.registry.remove(process.pid as u32);
+        let deadline = Instant::now() + delay;
+        while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+            // the signal might have been taken while watching
+            if !matches!(stop_rx.try_recv(), Err(TryRecvError::Empty)) {
+                return;
+            }
+            if exit.wait(left.min(Duration::from_millis(100))) || !process.is_alive() {
+                return;
+            }
+        }
+        delay = (delay * 2).min(config::get().watcher.retry_interval());
+    }
+}
 
-                        let Some(addr) =
-                            util::find_movsd_instructions(process.pid, &map, is_64_bit)
-                        else {
-                            continue;
-                        };
+/// Attaches to the process and follows it until it exits or the watcher stops.
+fn watch_process(
+    process: &Process,
+    shared: &Shared,
+    exit: &Exit,
+    stop_rx: &mut oneshot::Receiver<()>,
+) {
+    let Ok(cmdline) = process.cmdline() else {
+        return;
+    };
+    let Some(executable) = cmdline.first() else {
+        return;
+    };
+    let Ok(maps) = process.maps() else {
+        log::warn!(
+            "Unable to read memory maps of the process {}, skipping.",
+            process.pid
+        );
+        return;
+    };
+    let maps = util::cloudmusic_maps(maps);
+    // not loaded yet
+    let Some(header_map) = maps.first() else {
+        return;
+    };
+    let probe = Probe::run(process.pid, header_map.address.0 as usize);
+    match probe.working() {
+        Some(Strategy::Ptrace) => log::warn!(
+            "Reading the memory of the process {} with ptrace, which stops it for every read",
+            process.pid
+        ),
+        Some(strategy) => log::debug!(
+            "Reading the memory of the process {} with {}",
+            process.pid,
+            strategy
+        ),
+        None => {}
+    }
+    let failures = probe.failures();
+    let Some(memory) = probe.memory() else {
+        log::warn!(
+            "Unable to read the memory of the process {}: {}",
+            process.pid,
+            failures
+        );
+        return;
+    };
+    let memory = &*memory;
+    let module = match Module::new(&maps, memory) {
+        Ok(module) => module,
+        // not loaded yet
+        Err(ModuleError::NotMapped) => return,
+        Err(err) => {
+            log::warn!(
+                "Unable to read cloudmusic.dll in the process {}: {}",
+                process.pid,
+                err
+            );
+            return;
+        }
+    };
+    let context = Context::of(process);
+    log::debug!("The process {} is run by {}", process.pid, context.setup);
+    // the path is the one the process sees, which differs in a sandbox
+    let dll_version = signature::version_of(&in_root(&context.root, &module.path));
+    log::debug!(
+        "cloudmusic.dll of the process {} is {}, version {:?}",
+        process.pid,
+        if module.is_64_bit { "64-bit" } else { "32-bit" },
+        dll_version.as_ref().map(|x| x.to_string())
+    );
+    let (selected, player_signatures): (Vec<_>, Vec<_>) = shared
+        .signatures
+        .select(Arch::of(module.is_64_bit), dll_version.as_ref())
+        .into_iter()
+        .partition(|x| x.variable == Variable::Time);
+    let key = pe::fingerprint(&module.header);
+    let cached = key.as_deref().and_then(|key| {
+        shared
+            .offsets
+            .lock()
+            .unwrap()
+            .get(key, module.base, &selected, |addr, len| {
+                memory.read(addr, len).ok()
+            })
+    });
+    // the cached playback time, or the candidates to pick it from
+    let found = match cached {
+        Some(candidate) => Ok(candidate),
+        None => {
+            let probation = Probation::new(module.find_addresses(&selected, memory));
+            if probation.is_empty() {
+                return;
+            }
+            Err(probation)
+        }
+    };
 
-                        log::info!(
-                            "Found Netease Cloud Music process: {} (pid {})",
-                            executable,
-                            process.pid
-                        );
+    let webdb = match webdb::resolve(&context, &shared.options) {
+        Ok(found) => {
+            log::info!(
+                "Using the database file {}, found {}",
+                found.path.display(),
+                found.how
+            );
+            found
+        }
+        Err(err) => {
+            log::warn!(
+                "Unable to find the database file of the process {}: {}",
+                process.pid,
+                err
+            );
+            return;
+        }
+    };
 
-                        scheduled_find_time_tx.send(None).unwrap(); // set None to indicate that we have found the process and won't try to find again until it exits.
+    let db = match Database::open(&webdb.path) {
+        Ok(db) => db,
+        Err(err) => {
+            log::error!("Unable to open {}: {}", webdb.path.display(), err);
+            return;
+        }
+    };
 
-                        let Some((pfx, user)) = process.environ().ok().and_then(|x| {
-                            use std::ffi::OsStr;
+    let channels = Channels::default();
+    // initial update, the duration of the track helps to pick the playback time
+    let read = update_music(&db, &channels.music);
 
-                            let Some(pfx) = x.get(OsStr::new("WINEPREFIX")) else {
-                                return None;
-                            };
+    let Candidate {
+        addr, signature, ..
+    } = match found {
+        Ok(candidate) => {
+            log::debug!("Using the cached playback time at {:#x}", candidate.addr);
+            candidate
+        }
+        Err(mut probation) => {
+            log::debug!(
+                "Watching {} playback time candidate(s) of the process {}",
+                probation.len(),
+                process.pid
+            );
+            let duration = track_duration(&channels.music);
+            if probation.run(|addr| util::read_double(memory, addr), duration, stop_rx) {
+                return;
+            }
+            let Some((candidate, verdict)) = probation.finish() else {
+                log::warn!(
+                    "None of the playback time candidates of the process {} behaved like a playback clock.",
+                    process.pid
+                );
+                return;
+            };
+            // a still one might be the wrong pick, so it's checked again next time
+            if let (Some(key), Verdict::Clock) = (key, verdict) {
+                shared
+                    .offsets
+                    .lock()
+                    .unwrap()
+                    .insert(key, module.base, &candidate);
+            }
+            candidate
+        }
+    };
 
-                            let Some(user) = x.get(OsStr::new("USER")) else {
-                                return None;
-                            };
+    log::info!(
+        event = "attach",
+        pid = process.pid;
+        "Found Netease Cloud Music process: {} (pid {}), using signature {} at {:#x}",
+        executable,
+        process.pid,
+        signature.name,
+        addr
+    );
 
-                            Some((
-                                pfx.to_string_lossy().to_string(),
-                                user.to_string_lossy().to_string(),
-                            ))
-                        }) else {
-                            continue;
-                        };
+    let player = if player_signatures.is_empty() {
+        PlayerReader::default()
+    } else {
+        PlayerReader::new(module.find_addresses(&player_signatures, memory))
+    };
+    for variable in player.variables() {
+        log::info!(
+            "Reading {} at {:#x} with signature {}",
+            variable.signature.variable,
+            variable.addr,
+            variable.signature.name
+        );
+    }
+
+    let mut changes = match Changes::new(&webdb.path) {
+        Ok(changes) => changes,
+        Err(err) => {
+            log::error!("Unable to watch the database for changes: {}", err);
+            return;
+        }
+    };
+    if !read {
+        changes.missed();
+    }
 
-                        let netease_webdb_file = format!(
-                                "{}/drive_c/users/{}/AppData/Local/NetEase/CloudMusic/Library/webdb.dat",
-                                pfx, user
-                            );
+    let pid = process.pid as u32;
+    shared.registry.add(pid, webdb.prefix, &channels);
 
-                        let Ok(conn) = Connection::open(&netease_webdb_file) else {
-                            log::error!("Failed to open the database file.");
-                            continue;
-                        };
+    // TODO: how do we setup CBTProc hook from outside of Wine?
+    // run a helper program in the wine to hook?
 
-                        // initial update
-                        update_music(&conn, &music_tx);
+    let mut last_val = -1.;
+    loop {
+        if stop_rx.try_recv().is_ok() {
+            return;
+        }
 
-                        let Ok((_watcher, notify_rx)) =
-                            create_file_watcher(Path::new(&netease_webdb_file))
-                        else {
-                            log::error!("Failed to create file watcher.");
-                            continue;
-                        };
-
-                        // TODO: how do we setup CBTProc hook from outside of Wine?
-                        // run a helper program in the wine to hook?
-
-                        let mut last_val = -1.;
-                        loop {
-                            if stop_rx.try_recv().is_ok() {
-                                break 'watcher_loop;
-                            }
-
-                            let val = util::read_double_from_addr(process.pid, addr);
-                            if val < 0. {
-                                // unable to read properly
-                                continue 'maps; // keep trying other maps
-                            }
-                            if val != last_val {
-                                if time_tx.send(val).is_ok() {
-                                    last_val = val;
-                                }
-                            }
-
-                            if let Ok(Ok(e)) = notify_rx.try_recv() {
-                                if matches!(e.kind, EventKind::Modify(_)) {
-                                    update_music(&conn, &music_tx);
-                                }
-                            }
-
-                            std::thread::sleep(Duration::from_millis(50));
-                        }
-                    }
-                }
-            }
-            // reset states
-            time_tx.send(-1.).unwrap();
-            music_tx.send(None).unwrap();
-            scheduled_find_time_tx
-                .send(Some(Instant::now() + sleep_duration))
-                .unwrap();
-            if stoppable_sleep(sleep_duration, &mut stop_rx) {
-                break 'watcher_loop;
-            }
-        });
-        self.watch_thread = Some((stop_signal, join_handle));
+        let val = util::read_double(memory, addr);
+        if val < 0. {
+            // unable to read properly, the process exited
+            return;
+        }
+        if val != last_val {
+            channels.time.send_replace(val);
+            last_val = val;
+        }
+        if !player.is_empty() {
+            update_player(
+                &channels.player,
+                player.read(|addr, len| memory.read(addr, len).ok()),
+            );
+        }
+
+        if changes.changed() && !update_music(&db, &channels.music) {
+            changes.missed();
+        }
+        shared.registry.sync(pid);
+
+        if exit.wait(config::get().watcher.poll_interval()) {
+            log::info!(event = "exit", pid = pid; "The process {} exited", pid);
+            return;
+        }
     }
 }
@@ -0,0 +1,317 @@
+use procfs::process::{MemoryMap, Process};
+
+use crate::{
+    doctor::{
+        check_candidates, check_player, check_webdb, load_signatures, Report, SUGGEST_SIGNATURES,
+    },
+    netease::{
+        probation::{Candidate, Probation},
+        unix::{
+            launcher::in_root,
+            mem::{Probe, Strategy},
+            native::Bus,
+            util::{self, Module, ModuleError},
+            webdb::{self, Context},
+        },
+        WatcherOptions,
+    },
+    signature::{self, Arch, SignatureDb, Variable},
+};
+
+const PTRACE_SCOPE: &str = "/proc/sys/kernel/yama/ptrace_scope";
+
+/// The Yama ptrace scope, `None` without Yama.
+fn ptrace_scope() -> Option<u8> {
+    std::fs::read_to_string(PTRACE_SCOPE)
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+fn suggest_ptrace(report: &mut Report, process: &Process) {
+    if let (Ok(uid), Ok(our_uid)) = (process.uid(), Process::myself().and_then(|x| x.uid())) {
+        if uid != our_uid {
+            report.suggest(format!(
+                "Netease Cloud Music runs as uid {} while the watcher runs as uid {}, run the watcher as the same user",
+                uid, our_uid
+            ));
+        }
+    }
+    match ptrace_scope() {
+        Some(1) => report.suggest(
+            "kernel.yama.ptrace_scope is 1, so only parent processes may read the memory. Run `sudo sysctl kernel.yama.ptrace_scope=0`, or `sudo setcap cap_sys_ptrace=eip <path to netease-watcher>`",
+        ),
+        Some(2) => report.suggest(
+            "kernel.yama.ptrace_scope is 2, so reading the memory needs CAP_SYS_PTRACE. Run `sudo setcap cap_sys_ptrace=eip <path to netease-watcher>`",
+        ),
+        Some(3) => report.suggest(
+            "kernel.yama.ptrace_scope is 3, which disables reading the memory of other processes until a reboot. Set it to 0 or 1 in /etc/sysctl.d and reboot",
+        ),
+        _ => report.suggest(
+            "Check that nothing (e.g. a sandbox like Flatpak or a security module) stops the watcher from reading the memory of other processes",
+        ),
+    }
+}
+
+fn diagnose_process(
+    process: &Process,
+    options: &WatcherOptions,
+    db: &SignatureDb,
+    report: &mut Report,
+) {
+    report.section(format!("Process {}", process.pid));
+
+    let context = Context::of(process);
+    report.ok(format!("Started by {}", context.setup));
+
+    match process.maps() {
+        Err(err) => {
+            report.fail(format!(
+                "Unable to read /proc/{}/maps: {}",
+                process.pid, err
+            ));
+            suggest_ptrace(report, process);
+        }
+        Ok(maps) => {
+            report.ok(format!("/proc/{}/maps is readable", process.pid));
+            diagnose_maps(process, &context, util::cloudmusic_maps(maps), db, report);
+        }
+    }
+
+    match webdb::resolve(&context, options) {
+        Ok(found) => {
+            report.ok(format!("Found webdb.dat {}", found.how));
+            check_webdb(report, &found.path);
+        }
+        Err(err) => {
+            report.fail(format!("Unable to find webdb.dat: {}", err));
+            report.suggest(
+                "Pass the Wine prefix Netease Cloud Music runs in with --wineprefix, or the path of webdb.dat with --webdb",
+            );
+        }
+    }
+}
+
+fn diagnose_maps(
+    process: &Process,
+    context: &Context,
+    maps: Vec<MemoryMap>,
+    db: &SignatureDb,
+    report: &mut Report,
+) {
+    for map in &maps {
+        report.detail(format!(
+            "{:#x}-{:#x} {} offset {:#x}",
+            map.address.0,
+            map.address.1,
+            map.perms.as_str(),
+            map.offset
+        ));
+    }
+    let Some(header_map) = maps.first() else {
+        report.fail("cloudmusic.dll is not mapped");
+        report.suggest(
+            "Wait until Netease Cloud Music has finished starting, cloudmusic.dll is loaded after the main window shows",
+        );
+        return;
+    };
+    report.ok("cloudmusic.dll is mapped");
+    let probe = Probe::run(process.pid, header_map.address.0 as usize);
+    for (strategy, result) in &probe.results {
+        if let Err(err) = result {
+            report.detail(format!("{} doesn't work: {}", strategy, err));
+        }
+    }
+    match probe.working() {
+        Some(Strategy::Ptrace) => report.warn(
+            "The memory of the process is only readable with ptrace, which stops it for every read",
+        ),
+        Some(strategy) => report.ok(format!(
+            "The memory of the process is readable with {}",
+            strategy
+        )),
+        None => {
+            report.fail("Unable to read the memory of the process");
+            suggest_ptrace(report, process);
+            return;
+        }
+    }
+    let Some(memory) = probe.memory() else {
+        return;
+    };
+    let memory = &*memory;
+    let module = match Module::new(&maps, memory) {
+        Ok(module) => module,
+        Err(ModuleError::NotMapped) => return,
+        Err(ModuleError::Unreadable(err)) => {
+            report.fail(format!(
+                "Unable to read the PE header of cloudmusic.dll: {}",
+                err
+            ));
+            return;
+        }
+        Err(ModuleError::NotPe) => {
+            report.fail("Unable to read the PE header of cloudmusic.dll");
+            report.detail(format!(
+                "the first map at {:#x} doesn't start with a PE header",
+                maps[0].address.0
+            ));
+            return;
+        }
+    };
+    report.ok(format!("cloudmusic.dll is mapped at {:#x}", module.base));
+    report.ok(format!(
+        "cloudmusic.dll is {}",
+        if module.is_64_bit { "64-bit" } else { "32-bit" }
+    ));
+
+    let version = signature::version_of(&in_root(&context.root, &module.path));
+    match &version {
+        Some(version) => report.ok(format!("cloudmusic.dll is version {}", version)),
+        None => {
+            report.warn(format!(
+                "Unable to read the version of {}, trying every signature",
+                module.path.display()
+            ));
+        }
+    }
+
+    let (signatures, player_signatures): (Vec<_>, Vec<_>) = db
+        .select(Arch::of(module.is_64_bit), version.as_ref())
+        .into_iter()
+        .partition(|x| x.variable == Variable::Time);
+    if signatures.is_empty() {
+        report.fail("No signature applies to this version");
+        report.suggest(SUGGEST_SIGNATURES);
+        return;
+    }
+    let probation = Probation::new(module.find_addresses(&signatures, memory));
+    if probation.is_empty() {
+        let names: Vec<_> = signatures.iter().map(|x| x.name.as_str()).collect();
+        report.fail(format!(
+            "None of the signatures ({}) matched in the executable maps",
+            names.join(", ")
+        ));
+        report.suggest(SUGGEST_SIGNATURES);
+        return;
+    }
+    let Some(Candidate { addr, .. }) =
+        check_candidates(report, probation, |addr| util::read_double(memory, addr))
+    else {
+        return;
+    };
+
+    let time = util::read_double(memory, addr);
+    if time < 0. {
+        report.warn(format!("Unable to read the playback time at {:#x}", addr));
+    } else {
+        report.ok(format!("Playback time is {:.3}s", time));
+    }
+
+    if !player_signatures.is_empty() {
+        check_player(
+            report,
+            &player_signatures,
+            module.find_addresses(&player_signatures, memory),
+            |addr, len| memory.read(addr, len).ok(),
+        );
+    }
+}
+
+/// Lists the players of native clients, returns whether there are any.
+fn diagnose_native(report: &mut Report) -> bool {
+    // the blocking bus starts a runtime of its own, which can't be done on the one of the doctor
+    let clients = std::thread::spawn(|| Bus::connect().map(|bus| bus.clients()))
+        .join()
+        .ok()
+        .flatten();
+    let Some(clients) = clients else {
+        report.detail("no session bus, native clients aren't looked for");
+        return false;
+    };
+    if clients.is_empty() {
+        report.detail("no MPRIS player of a native client");
+        return false;
+    }
+    for (name, pid) in clients {
+        report.ok(format!("Found the player {} of the process {}", name, pid));
+    }
+    true
+}
+
+pub fn diagnose(options: &WatcherOptions, report: &mut Report) {
+    report.section("System");
+    match ptrace_scope() {
+        Some(0) | None => report.ok("Reading the memory of other processes is not restricted"),
+        Some(scope) => report.warn(format!(
+            "{} is {}, reading the memory of other processes is restricted",
+            PTRACE_SCOPE, scope
+        )),
+    }
+
+    report.section("Native clients");
+    let native = diagnose_native(report);
+
+    report.section("Processes");
+    let processes = match procfs::process::all_processes() {
+        Ok(processes) => processes,
+        Err(err) => {
+            report.fail(format!("Unable to list processes: {}", err));
+            return;
+        }
+    };
+    let mut candidates = Vec::new();
+    for process in processes.flatten() {
+        let Ok(cmdline) = process.cmdline() else {
+            continue;
+        };
+        if !cmdline
+            .iter()
+            .any(|x| x.to_ascii_lowercase().ends_with("cloudmusic.exe"))
+        {
+            continue;
+        }
+        report.detail(format!("{}: {}", process.pid, cmdline.join(" ")));
+        if cmdline.iter().any(|x| x.contains("--type")) {
+            report.detail("  a helper process, skipped");
+        } else if !util::is_client(&cmdline) {
+            report.detail("  a launcher starting it, skipped");
+        } else if options.pid.is_some_and(|x| x as i32 != process.pid) {
+            report.detail("  not the one given by --pid, skipped");
+        } else {
+            candidates.push(process);
+        }
+    }
+    if candidates.is_empty() && native {
+        report.ok("No Netease Cloud Music running under Wine");
+        return;
+    }
+    if candidates.is_empty() {
+        report.fail("No Netease Cloud Music process found");
+        report.suggest(match options.pid {
+            Some(pid) => format!(
+                "Check that {} is the pid of the main cloudmusic.exe process, or drop --pid",
+                pid
+            ),
+            None => {
+                "Start Netease Cloud Music, the watcher looks for processes running cloudmusic.exe and for MPRIS players of native clients"
+                    .to_string()
+            }
+        });
+        if let Some(webdb) = &options.webdb {
+            report.section("Database");
+            check_webdb(report, webdb);
+        }
+        return;
+    }
+    report.ok(format!(
+        "Found {} Netease Cloud Music process(es)",
+        candidates.len()
+    ));
+
+    let db = load_signatures(options, report);
+    for process in &candidates {
+        diagnose_process(process, options, &db, report);
+    }
+}
@@ -0,0 +1,256 @@
+//! Tells how the client was started, and where the launcher keeps its Wine prefixes.
+
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+};
+
+const BOTTLES_FLATPAK: &str = "com.usebottles.bottles";
+const LUTRIS_FLATPAK: &str = "net.lutris.Lutris";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Launcher {
+    Wine,
+    Proton,
+    Bottles,
+    Lutris,
+}
+
+impl Display for Launcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Launcher::Wine => write!(f, "Wine"),
+            Launcher::Proton => write!(f, "Proton"),
+            Launcher::Bottles => write!(f, "Bottles"),
+            Launcher::Lutris => write!(f, "Lutris"),
+        }
+    }
+}
+
+/// How the process was started, from its environment.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Setup {
+    pub launcher: Launcher,
+    /// the id of the Flatpak app it runs in
+    pub flatpak: Option<String>,
+}
+
+impl Display for Setup {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.flatpak {
+            Some(id) => write!(f, "{} in Flatpak ({})", self.launcher, id),
+            None => write!(f, "{}", self.launcher),
+        }
+    }
+}
+
+impl Setup {
+    /// `root` is `/proc/<pid>/root` of the process.
+    pub fn detect(environ: &HashMap<OsString, OsString>, root: &Path) -> Self {
+        let env = |name: &str| environ.get(OsStr::new(name)).map(|x| x.to_string_lossy());
+        let flatpak = env("FLATPAK_ID").map(|x| x.to_string()).or_else(|| {
+            // older Flatpak doesn't set FLATPAK_ID
+            let info = fs::read_to_string(root.join(".flatpak-info")).ok()?;
+            info.lines()
+                .find_map(|x| x.strip_prefix("name="))
+                .map(|x| x.trim().to_string())
+        });
+        let launcher = if env("STEAM_COMPAT_DATA_PATH").is_some() {
+            Launcher::Proton
+        } else if flatpak.as_deref() == Some(BOTTLES_FLATPAK)
+            || env("WINEPREFIX").is_some_and(|x| x.contains("/bottles/bottles/"))
+        {
+            Launcher::Bottles
+        } else if flatpak.as_deref() == Some(LUTRIS_FLATPAK) || env("LUTRIS_GAME_UUID").is_some() {
+            Launcher::Lutris
+        } else {
+            Launcher::Wine
+        };
+        Setup { launcher, flatpak }
+    }
+
+    /// The prefixes the launcher keeps by its layout, as the process sees them, with how each
+    /// was found. Directories are listed through `root` when it's readable.
+    pub fn prefixes(
+        &self,
+        environ: &HashMap<OsString, OsString>,
+        root: &Path,
+    ) -> Vec<(PathBuf, String)> {
+        let env = |name: &str| environ.get(OsStr::new(name)).map(PathBuf::from);
+        let home = env("HOME");
+        let mut prefixes = Vec::new();
+        match self.launcher {
+            Launcher::Wine => {}
+            Launcher::Proton => {
+                if let Some(data) = env("STEAM_COMPAT_DATA_PATH") {
+                    prefixes.push((data.join("pfx"), "the Proton prefix".to_string()));
+                }
+            }
+            Launcher::Bottles => {
+                // XDG_DATA_HOME is inside ~/.var/app when it runs in Flatpak
+                let data = env("XDG_DATA_HOME")
+                    .or_else(|| Some(home.as_ref()?.join(".local/share")))
+                    .into_iter()
+                    .chain(
+                        home.iter()
+                            .map(|x| x.join(".var/app").join(BOTTLES_FLATPAK).join("data")),
+                    );
+                for data in data {
+                    for bottle in list(root, &data.join("bottles/bottles")) {
+                        prefixes.push((bottle, "a bottle of Bottles".to_string()));
+                    }
+                }
+            }
+            Launcher::Lutris => {
+                // where Lutris installs games by default
+                for dir in home.iter().flat_map(|x| list(root, &x.join("Games"))) {
+                    prefixes.push((dir, "a game directory of Lutris".to_string()));
+                }
+            }
+        }
+        prefixes
+    }
+}
+
+/// The entries of the directory, sorted, read through `root` if possible.
+fn list(root: &Path, dir: &Path) -> Vec<PathBuf> {
+    let entries = fs::read_dir(in_root(root, dir)).or_else(|_| fs::read_dir(dir));
+    let mut entries: Vec<_> = entries
+        .into_iter()
+        .flatten()
+        .filter_map(|x| Some(dir.join(x.ok()?.file_name())))
+        .collect();
+    entries.sort();
+    entries
+}
+
+/// A path the process sees, as it's seen from here. Falls back to the path itself if it isn't
+/// there, e.g. `root` isn't readable by this user.
+pub fn in_root(root: &Path, path: &Path) -> PathBuf {
+    let Ok(relative) = path.strip_prefix("/") else {
+        return path.to_path_buf();
+    };
+    let translated = root.join(relative);
+    if translated.exists() {
+        translated
+    } else {
+        path.to_path_buf()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn environ(vars: &[(&str, &str)]) -> HashMap<OsString, OsString> {
+        vars.iter()
+            .map(|(k, v)| (OsString::from(k), OsString::from(v)))
+            .collect()
+    }
+
+    #[test]
+    fn detects_launchers() {
+        let root = tempfile::tempdir().unwrap();
+        let detect = |vars: &[(&str, &str)]| Setup::detect(&environ(vars), root.path());
+
+        assert_eq!(detect(&[]).launcher, Launcher::Wine);
+        assert_eq!(
+            detect(&[("STEAM_COMPAT_DATA_PATH", "/steam/compatdata/1")]).launcher,
+            Launcher::Proton
+        );
+        assert_eq!(
+            detect(&[("WINEPREFIX", "/home/a/.local/share/bottles/bottles/Music")]).launcher,
+            Launcher::Bottles
+        );
+        assert_eq!(
+            detect(&[("LUTRIS_GAME_UUID", "1234")]).launcher,
+            Launcher::Lutris
+        );
+        assert_eq!(
+            detect(&[("FLATPAK_ID", BOTTLES_FLATPAK)]),
+            Setup {
+                launcher: Launcher::Bottles,
+                flatpak: Some(BOTTLES_FLATPAK.to_string())
+            }
+        );
+
+        fs::write(
+            root.path().join(".flatpak-info"),
+            "[Application]\nname=net.lutris.Lutris\nruntime=runtime/org.gnome.Platform\n",
+        )
+        .unwrap();
+        assert_eq!(
+            detect(&[]).to_string(),
+            "Lutris in Flatpak (net.lutris.Lutris)"
+        );
+    }
+
+    #[test]
+    fn lists_prefixes_by_layout() {
+        let root = tempfile::tempdir().unwrap();
+        let home = "/home/alice";
+        let bottles = root
+            .path()
+            .join("home/alice/.var/app/com.usebottles.bottles/data/bottles/bottles");
+        fs::create_dir_all(bottles.join("Music")).unwrap();
+        fs::create_dir_all(bottles.join("Games")).unwrap();
+
+        let vars = environ(&[
+            ("HOME", home),
+            ("FLATPAK_ID", BOTTLES_FLATPAK),
+            (
+                "XDG_DATA_HOME",
+                "/home/alice/.var/app/com.usebottles.bottles/data",
+            ),
+        ]);
+        let setup = Setup::detect(&vars, root.path());
+        let prefixes: Vec<_> = setup
+            .prefixes(&vars, root.path())
+            .into_iter()
+            .map(|(pfx, _)| pfx)
+            .collect();
+        // as the process sees them, found once through XDG_DATA_HOME and once by the Flatpak layout
+        let bottle = |name| {
+            Path::new(home)
+                .join(".var/app/com.usebottles.bottles/data/bottles/bottles")
+                .join(name)
+        };
+        assert_eq!(
+            prefixes,
+            [
+                bottle("Games"),
+                bottle("Music"),
+                bottle("Games"),
+                bottle("Music")
+            ]
+        );
+
+        let vars = environ(&[("STEAM_COMPAT_DATA_PATH", "/steam/compatdata/42")]);
+        let setup = Setup::detect(&vars, root.path());
+        assert_eq!(
+            setup.prefixes(&vars, root.path()),
+            [(
+                PathBuf::from("/steam/compatdata/42/pfx"),
+                "the Proton prefix".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn translates_paths() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir_all(root.path().join("sandbox")).unwrap();
+
+        assert_eq!(
+            in_root(root.path(), Path::new("/sandbox")),
+            root.path().join("sandbox")
+        );
+        assert_eq!(
+            in_root(root.path(), Path::new("/elsewhere")),
+            Path::new("/elsewhere")
+        );
+    }
+}
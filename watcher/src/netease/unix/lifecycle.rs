@@ -0,0 +1,241 @@
+//! Notices clients starting and exiting as it happens, so the processes aren't polled for it.
+
+use std::{
+    collections::HashSet,
+    fs, io,
+    os::fd::{AsFd, FromRawFd, OwnedFd, RawFd},
+    path::{Path, PathBuf},
+    sync::mpsc,
+    time::{Duration, Instant},
+};
+
+use nix::{
+    poll::{poll, PollFd, PollFlags, PollTimeout},
+    unistd::getuid,
+};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::oneshot;
+
+/// Where webdb.dat and its journals are in the directory of a Windows user.
+const LIBRARY: &str = "AppData/Local/NetEase/CloudMusic/Library";
+
+/// Events coming right after the first one are taken as the same activity.
+const SETTLE: Duration = Duration::from_millis(200);
+
+/// The exit of a process, through a pidfd.
+pub struct Exit(Option<OwnedFd>);
+
+impl Exit {
+    /// Without pidfd (before Linux 5.3) waiting is only sleeping, and the exit is noticed by the
+    /// memory becoming unreadable.
+    pub fn of(pid: i32) -> Self {
+        // SAFETY: pidfd_open only takes integers, and returns a new fd on success
+        let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+        if fd < 0 {
+            log::debug!(
+                "Unable to open a pidfd of the process {}: {}",
+                pid,
+                io::Error::last_os_error()
+            );
+            return Exit(None);
+        }
+        // SAFETY: the fd was just opened and nothing else owns it
+        Exit(Some(unsafe { OwnedFd::from_raw_fd(fd as RawFd) }))
+    }
+
+    /// Sleeps for `timeout`, returns true early if the process exits.
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let Some(fd) = &self.0 else {
+            std::thread::sleep(timeout);
+            return false;
+        };
+        let mut fds = [PollFd::new(fd.as_fd(), PollFlags::POLLIN)];
+        let timeout = PollTimeout::try_from(timeout).unwrap_or(PollTimeout::MAX);
+        matches!(poll(&mut fds, timeout), Ok(n) if n > 0)
+    }
+}
+
+/// What might mean a client just started.
+pub enum Wake {
+    /// a file was created or changed
+    File(notify::Event),
+    /// an MPRIS player appeared, by its bus name
+    Player(String),
+}
+
+/// Files created by Wine or the client, which mean one might have just started: the socket of a
+/// new Wine server, or the journals of webdb.dat. Players of native clients are told by the bus.
+pub struct Activity {
+    watcher: Option<RecommendedWatcher>,
+    tx: mpsc::Sender<Wake>,
+    rx: mpsc::Receiver<Wake>,
+    /// `/tmp/.wine-<uid>`, with a directory for the server of each prefix
+    servers: PathBuf,
+    watched: HashSet<PathBuf>,
+}
+
+impl Activity {
+    pub fn new() -> Self {
+        let (tx, rx) = mpsc::channel();
+        let files = tx.clone();
+        let watcher = match notify::recommended_watcher(move |event: notify::Result<_>| {
+            if let Ok(event) = event {
+                let _ = files.send(Wake::File(event));
+            }
+        }) {
+            Ok(watcher) => Some(watcher),
+            Err(err) => {
+                log::warn!(
+                    "Unable to watch for Netease Cloud Music starting, looking for it periodically: {}",
+                    err
+                );
+                None
+            }
+        };
+        let mut activity = Activity {
+            watcher,
+            tx,
+            rx,
+            servers: PathBuf::from(format!("/tmp/.wine-{}", getuid())),
+            watched: HashSet::new(),
+        };
+        let servers = activity.servers.clone();
+        if !activity.watch(&servers, RecursiveMode::Recursive) {
+            // until a Wine server creates it
+            activity.watch(Path::new("/tmp"), RecursiveMode::NonRecursive);
+        }
+        activity
+    }
+
+    /// Where others tell about what they noticed.
+    pub fn sender(&self) -> mpsc::Sender<Wake> {
+        self.tx.clone()
+    }
+
+    /// Whether starting clients are noticed, otherwise they are only found by looking.
+    pub fn is_active(&self) -> bool {
+        !self.watched.is_empty()
+    }
+
+    fn watch(&mut self, path: &Path, mode: RecursiveMode) -> bool {
+        let Some(watcher) = &mut self.watcher else {
+            return false;
+        };
+        if self.watched.contains(path) {
+            return true;
+        }
+        if watcher.watch(path, mode).is_err() {
+            return false;
+        }
+        self.watched.insert(path.to_path_buf());
+        true
+    }
+
+    /// Watches the library of every user in the prefix.
+    pub fn watch_prefix(&mut self, prefix: &Path) {
+        let Ok(users) = fs::read_dir(prefix.join("drive_c/users")) else {
+            return;
+        };
+        for user in users.flatten() {
+            let library = user.path().join(LIBRARY);
+            if library.is_dir() {
+                self.watch(&library, RecursiveMode::NonRecursive);
+            }
+        }
+    }
+
+    /// Watches the directory of a database given by the options.
+    pub fn watch_webdb(&mut self, webdb: &Path) {
+        if let Some(library) = webdb.parent().filter(|x| x.is_dir()) {
+            self.watch(library, RecursiveMode::NonRecursive);
+        }
+    }
+
+    /// Whether the event is about something being created that's watched for.
+    fn is_activity(&self, event: &notify::Event) -> bool {
+        if !matches!(event.kind, EventKind::Create(_)) {
+            return false;
+        }
+        // anything else in /tmp doesn't matter
+        event
+            .paths
+            .iter()
+            .any(|x| x.parent() != Some(Path::new("/tmp")) || *x == self.servers)
+    }
+
+    /// Sleeps for `timeout`, returns early once something was created or a player appeared. Returns
+    /// true if stopped.
+    pub fn wait(&mut self, timeout: Duration, stop_signal: &mut oneshot::Receiver<()>) -> bool {
+        let sleep_interval = Duration::from_millis(100);
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            if stop_signal.try_recv().is_ok() {
+                return true;
+            }
+            match self.rx.recv_timeout(sleep_interval) {
+                Ok(Wake::File(event)) => {
+                    if !self.is_activity(&event) {
+                        continue;
+                    }
+                    log::debug!(
+                        "Looking for Netease Cloud Music, {:?} was created",
+                        event.paths
+                    );
+                    if event.paths.contains(&self.servers) {
+                        let servers = self.servers.clone();
+                        self.watch(&servers, RecursiveMode::Recursive);
+                    }
+                }
+                Ok(Wake::Player(name)) => {
+                    log::debug!(
+                        "Looking for Netease Cloud Music, the player {} appeared",
+                        name
+                    );
+                }
+                Err(_) => continue,
+            }
+            // the rest of the burst, e.g. a journal is created and removed for each write
+            std::thread::sleep(SETTLE);
+            while self.rx.try_recv().is_ok() {}
+            return false;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notices_the_exit() {
+        let mut child = std::process::Command::new("sleep")
+            .arg("1")
+            .spawn()
+            .unwrap();
+        let exit = Exit::of(child.id() as i32);
+        let start = Instant::now();
+        assert!(exit.wait(Duration::from_secs(10)));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn wakes_on_activity() {
+        let pfx = tempfile::tempdir().unwrap();
+        let library = pfx.path().join("drive_c/users/alice").join(LIBRARY);
+        fs::create_dir_all(&library).unwrap();
+        let mut activity = Activity::new();
+        activity.watch_prefix(pfx.path());
+        assert!(activity.watched.contains(&library));
+
+        let (_stop, mut stop_rx) = oneshot::channel();
+        let start = Instant::now();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            fs::write(library.join("webdb.dat-journal"), "").unwrap();
+        });
+        assert!(!activity.wait(Duration::from_secs(10), &mut stop_rx));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
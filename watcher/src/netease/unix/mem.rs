@@ -1,74 +1,272 @@
-//! Memory utilities for Unix-like systems (Linux, macOS, etc.).
+//! Reading the memory of another process, with each way Linux allows it.
 
 use std::{
-    fs::OpenOptions,
-    io::{Read, Seek},
+    fmt::Display,
+    fs::{File, OpenOptions},
+    io::IoSliceMut,
+    os::unix::fs::FileExt,
 };
 
-use nix::unistd::Pid;
+use nix::{
+    sys::{
+        ptrace,
+        uio::{process_vm_readv, RemoteIoVec},
+        wait::waitpid,
+    },
+    unistd::Pid,
+};
 
-pub fn read_process_memory(pid: i32, addr: usize, len: usize) -> Result<Vec<u8>, String> {
-    #[cfg(target_os = "linux")]
-    'process_vm_readv: {
-        use nix::{
-            sys::uio::{process_vm_readv, RemoteIoVec},
-            unistd::Pid,
-        };
+pub trait ProcessMemory {
+    /// Reads exactly `len` bytes at `addr`.
+    fn read(&self, addr: usize, len: usize) -> Result<Vec<u8>, String>;
+}
 
-        let mut buf = vec![0u8; len];
-        let slice = std::io::IoSliceMut::new(&mut buf);
+/// Closures reading the memory, mostly for tests.
+impl<F: Fn(usize, usize) -> Result<Vec<u8>, String>> ProcessMemory for F {
+    fn read(&self, addr: usize, len: usize) -> Result<Vec<u8>, String> {
+        self(addr, len)
+    }
+}
 
-        let Ok(read_len) = process_vm_readv(
-            Pid::from_raw(pid),
-            &mut [slice],
-            &[RemoteIoVec { base: addr, len }],
-        ) else {
-            break 'process_vm_readv;
-        };
+/// The ways of reading, in the order they are tried.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Strategy {
+    VmReadv,
+    ProcMem,
+    Ptrace,
+}
 
-        if read_len == 0 {
-            break 'process_vm_readv;
-        }
+impl Strategy {
+    pub const ALL: [Strategy; 3] = [Strategy::VmReadv, Strategy::ProcMem, Strategy::Ptrace];
 
-        return Ok(buf);
+    pub fn open(self, pid: i32) -> Result<Box<dyn ProcessMemory>, String> {
+        Ok(match self {
+            Strategy::VmReadv => Box::new(VmReadv(Pid::from_raw(pid))),
+            Strategy::ProcMem => Box::new(ProcMem::open(pid)?),
+            Strategy::Ptrace => Box::new(Ptrace(Pid::from_raw(pid))),
+        })
     }
+}
 
-    'proc_mem: {
-        if len < 3 {
-            // For small reads, use ptrace to read word by word.
-            break 'proc_mem;
+impl Display for Strategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Strategy::VmReadv => write!(f, "process_vm_readv"),
+            Strategy::ProcMem => write!(f, "/proc/<pid>/mem"),
+            Strategy::Ptrace => write!(f, "ptrace"),
         }
+    }
+}
+
+/// Copies straight from the other process, the cheapest.
+pub struct VmReadv(Pid);
 
-        let Ok(buf) = OpenOptions::new()
+impl ProcessMemory for VmReadv {
+    fn read(&self, addr: usize, len: usize) -> Result<Vec<u8>, String> {
+        let mut buf = vec![0u8; len];
+        let read_len = process_vm_readv(
+            self.0,
+            &mut [IoSliceMut::new(&mut buf)],
+            &[RemoteIoVec { base: addr, len }],
+        )
+        .map_err(|err| err.to_string())?;
+        if read_len != len {
+            return Err(format!("only {} of {} bytes were read", read_len, len));
+        }
+        Ok(buf)
+    }
+}
+
+/// Reads the memory as a file, which some sandboxes allow while forbidding process_vm_readv.
+pub struct ProcMem(File);
+
+impl ProcMem {
+    fn open(pid: i32) -> Result<Self, String> {
+        OpenOptions::new()
             .read(true)
             .open(format!("/proc/{}/mem", pid))
-            .and_then(|mut file| {
-                file.seek(std::io::SeekFrom::Start(addr as u64))?;
-                let mut buf = vec![0u8; len];
-                file.read_exact(&mut buf)?;
-                Ok(buf)
+            .map(ProcMem)
+            .map_err(|err| err.to_string())
+    }
+}
+
+impl ProcessMemory for ProcMem {
+    fn read(&self, addr: usize, len: usize) -> Result<Vec<u8>, String> {
+        let mut buf = vec![0u8; len];
+        self.0
+            .read_exact_at(&mut buf, addr as u64)
+            .map_err(|err| err.to_string())?;
+        Ok(buf)
+    }
+}
+
+/// Stops the process for every read and peeks a word at a time, the last resort.
+pub struct Ptrace(Pid);
+
+impl Ptrace {
+    fn peek(&self, addr: usize, len: usize) -> nix::Result<Vec<u8>> {
+        ptrace::interrupt(self.0)?;
+        waitpid(self.0, None)?;
+        let word = size_of::<std::ffi::c_long>();
+        let mut buf = Vec::with_capacity(len + word);
+        while buf.len() < len {
+            // the last word ends at the end when it can, it might be the end of the map
+            let offset = if len >= word {
+                buf.len().min(len - word)
+            } else {
+                0
+            };
+            let bytes = ptrace::read(self.0, (addr + offset) as ptrace::AddressType)?.to_ne_bytes();
+            buf.truncate(offset);
+            buf.extend_from_slice(&bytes);
+        }
+        buf.truncate(len);
+        Ok(buf)
+    }
+}
+
+impl ProcessMemory for Ptrace {
+    fn read(&self, addr: usize, len: usize) -> Result<Vec<u8>, String> {
+        ptrace::seize(self.0, ptrace::Options::empty()).map_err(|err| err.to_string())?;
+        let result = self.peek(addr, len);
+        let _ = ptrace::detach(self.0, None);
+        result.map_err(|err| err.to_string())
+    }
+}
+
+/// Which ways of reading work on a process.
+pub struct Probe {
+    /// the ones tried, up to the first that worked
+    pub results: Vec<(Strategy, Result<(), String>)>,
+    memory: Option<Box<dyn ProcessMemory>>,
+}
+
+impl Probe {
+    /// Tries reading a few bytes at `addr`, which must be mapped, with each strategy until one
+    /// works.
+    pub fn run(pid: i32, addr: usize) -> Self {
+        let mut results = Vec::new();
+        for strategy in Strategy::ALL {
+            match strategy
+                .open(pid)
+                .and_then(|memory| memory.read(addr, 8).map(|_| memory))
+            {
+                Ok(memory) => {
+                    results.push((strategy, Ok(())));
+                    return Probe {
+                        results,
+                        memory: Some(memory),
+                    };
+                }
+                Err(err) => results.push((strategy, Err(err))),
+            }
+        }
+        Probe {
+            results,
+            memory: None,
+        }
+    }
+
+    pub fn working(&self) -> Option<Strategy> {
+        self.results
+            .iter()
+            .find(|(_, result)| result.is_ok())
+            .map(|(strategy, _)| *strategy)
+    }
+
+    /// The memory read by the strategy that works.
+    pub fn memory(self) -> Option<Box<dyn ProcessMemory>> {
+        self.memory
+    }
+
+    /// Why each strategy failed.
+    pub fn failures(&self) -> String {
+        self.results
+            .iter()
+            .filter_map(|(strategy, result)| {
+                Some(format!("{}: {}", strategy, result.as_ref().err()?))
             })
-        else {
-            break 'proc_mem;
-        };
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Memory dumped from a process, as regions starting at their addresses.
+#[cfg(test)]
+pub struct Image(Vec<(usize, Vec<u8>)>);
 
-        return Ok(buf);
+#[cfg(test)]
+impl Image {
+    pub fn new(regions: impl IntoIterator<Item = (usize, Vec<u8>)>) -> Self {
+        Image(regions.into_iter().collect())
     }
 
-    let mut left_bytes = len;
-    let mut buf = Vec::with_capacity(len);
+    /// A region dumped to a file, e.g. with `dd if=/proc/<pid>/mem skip=<addr>`.
+    pub fn load(addr: usize, path: &std::path::Path) -> std::io::Result<Self> {
+        Ok(Image::new([(addr, std::fs::read(path)?)]))
+    }
+}
 
-    while left_bytes > 0 {
-        use nix::sys::ptrace::read;
+#[cfg(test)]
+impl ProcessMemory for Image {
+    fn read(&self, addr: usize, len: usize) -> Result<Vec<u8>, String> {
+        self.0
+            .iter()
+            .find_map(|(start, data)| data.get(addr.checked_sub(*start)?..addr - start + len))
+            .map(|x| x.to_vec())
+            .ok_or_else(|| format!("{:#x} is not in the image", addr))
+    }
+}
 
-        let word = match read(Pid::from_raw(pid), (addr + (len - left_bytes)) as *mut _) {
-            Ok(word) => word,
-            Err(_) => break,
-        };
+#[cfg(test)]
+mod tests {
+    use std::process::{Command, Stdio};
+
+    use super::*;
 
-        buf.push((word & 0xFF) as u8);
-        left_bytes -= 1;
+    #[test]
+    fn reads_dumped_images() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dump");
+        std::fs::write(&path, [1, 2, 3, 4]).unwrap();
+        let image = Image::load(0x1000, &path).unwrap();
+
+        assert_eq!(image.read(0x1001, 2), Ok(vec![2, 3]));
+        assert!(image.read(0x1003, 2).is_err());
+        assert!(image.read(0xfff, 1).is_err());
     }
 
-    Err(format!("Unsupported platform"))
+    #[test]
+    fn reads_with_every_strategy() {
+        // a child of ours, which may be read even with Yama ptrace_scope 1
+        let mut child = Command::new("sleep")
+            .arg("10")
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+        let pid = child.id() as i32;
+        let maps = procfs::process::Process::new(pid).unwrap().maps().unwrap();
+        let map = maps
+            .iter()
+            .find(|x| x.perms.contains(procfs::process::MMPermissions::READ))
+            .unwrap();
+        let (addr, len) = (map.address.0 as usize, 13);
+
+        let probe = Probe::run(pid, addr);
+        let Some(strategy) = probe.working() else {
+            panic!("no strategy works: {}", probe.failures());
+        };
+        let expected = probe.memory().unwrap().read(addr, len).unwrap();
+        for other in Strategy::ALL {
+            // each one works on its own, or fails like it would without the permission
+            let Ok(memory) = other.open(pid) else {
+                continue;
+            };
+            if let Ok(read) = memory.read(addr, len) {
+                assert_eq!(read, expected, "{} and {} differ", strategy, other);
+            }
+        }
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
 }
@@ -0,0 +1,444 @@
+//! Native Linux clients: the `netease-cloud-music` deb and the Electron-based ones. They tell what
+//! they play over MPRIS instead of being read from memory.
+
+use std::{collections::HashMap, sync::mpsc};
+
+use sha2::{Digest, Sha256};
+
+use tokio::sync::oneshot::{self, error::TryRecvError};
+use zbus::{
+    blocking::{
+        fdo::{DBusProxy, PropertiesProxy},
+        Connection,
+    },
+    names::InterfaceName,
+    zvariant::{OwnedValue, Value},
+};
+
+use crate::{
+    config,
+    netease::{
+        instances::Channels,
+        set_music,
+        unix::{
+            lifecycle::{Exit, Wake},
+            Shared,
+        },
+        update_player,
+    },
+    Music, PlayMode, Player,
+};
+
+const MPRIS_PREFIX: &str = "org.mpris.MediaPlayer2.";
+const MPRIS_PATH: &str = "/org/mpris/MediaPlayer2";
+const ROOT_INTERFACE: &str = "org.mpris.MediaPlayer2";
+const PLAYER_INTERFACE: &str = "org.mpris.MediaPlayer2.Player";
+
+/// What the bus name, identity or desktop entry of a player of a native client contains, in
+/// lowercase.
+const CLIENT_NAMES: [&str; 5] = [
+    "netease",
+    "cloudmusic",
+    "cloud-music",
+    "yesplaymusic",
+    "网易云音乐",
+];
+
+/// The session bus, where the players are.
+#[derive(Clone)]
+pub struct Bus(Connection);
+
+impl Bus {
+    pub fn connect() -> Option<Self> {
+        match Connection::session() {
+            Ok(connection) => Some(Bus(connection)),
+            Err(err) => {
+                log::debug!(
+                    "Unable to connect to the session bus, native clients won't be found: {}",
+                    err
+                );
+                None
+            }
+        }
+    }
+
+    /// The players of native clients, by bus name, with the pid of the process owning it.
+    pub fn clients(&self) -> Vec<(String, u32)> {
+        let Ok(dbus) = DBusProxy::new(&self.0) else {
+            return Vec::new();
+        };
+        let Ok(names) = dbus.list_names() else {
+            return Vec::new();
+        };
+        names
+            .into_iter()
+            .filter(|name| name.starts_with(MPRIS_PREFIX) && self.is_client(name))
+            .filter_map(|name| {
+                let pid = dbus.get_connection_unix_process_id(name.as_ref()).ok()?;
+                Some((name.to_string(), pid))
+            })
+            .collect()
+    }
+
+    fn is_client(&self, name: &str) -> bool {
+        let root = self.properties(name, ROOT_INTERFACE).unwrap_or_default();
+        is_client(
+            name,
+            root.get("Identity").and_then(|x| string(x)).as_deref(),
+            root.get("DesktopEntry").and_then(|x| string(x)).as_deref(),
+        )
+    }
+
+    fn properties(
+        &self,
+        name: &str,
+        interface: &'static str,
+    ) -> Option<HashMap<String, OwnedValue>> {
+        let proxy = PropertiesProxy::builder(&self.0)
+            .destination(name.to_string())
+            .ok()?
+            .path(MPRIS_PATH)
+            .ok()?
+            .build()
+            .ok()?;
+        proxy
+            .get_all(InterfaceName::from_static_str_unchecked(interface))
+            .ok()
+    }
+
+    /// Wakes the manager when a player appears, until it's gone.
+    pub fn notify_players(&self, tx: mpsc::Sender<Wake>) {
+        let Ok(changes) = DBusProxy::new(&self.0).and_then(|x| x.receive_name_owner_changed())
+        else {
+            return;
+        };
+        std::thread::spawn(move || {
+            for signal in changes {
+                let Ok(args) = signal.args() else {
+                    continue;
+                };
+                if !args.name.starts_with(MPRIS_PREFIX) || args.new_owner.is_none() {
+                    continue;
+                }
+                if tx.send(Wake::Player(args.name.to_string())).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+fn is_client(name: &str, identity: Option<&str>, desktop_entry: Option<&str>) -> bool {
+    let name = name.trim_start_matches(MPRIS_PREFIX);
+    [Some(name), identity, desktop_entry]
+        .into_iter()
+        .flatten()
+        .map(str::to_lowercase)
+        .any(|x| CLIENT_NAMES.iter().any(|name| x.contains(name)))
+}
+
+/// Follows the player until it's gone or the watcher stops.
+pub fn follow_player(
+    bus: &Bus,
+    name: &str,
+    pid: u32,
+    shared: &Shared,
+    stop_rx: &mut oneshot::Receiver<()>,
+) {
+    let exit = Exit::of(pid as i32);
+    let read = || bus.properties(name, PLAYER_INTERFACE);
+    let Some(mut properties) = read() else {
+        return;
+    };
+    log::info!(
+        event = "attach",
+        pid = pid;
+        "Found Netease Cloud Music player: {} (pid {}), following it over MPRIS",
+        name,
+        pid
+    );
+    let channels = Channels::default();
+    shared.registry.add(pid, None, &channels);
+    let mut last_val = -1.;
+    loop {
+        if !matches!(stop_rx.try_recv(), Err(TryRecvError::Empty)) {
+            break;
+        }
+        let val = properties
+            .get("Position")
+            .and_then(|x| integer(x))
+            .map_or(0., |x| x as f64 / 1_000_000.);
+        if val != last_val {
+            channels.time.send_replace(val);
+            last_val = val;
+        }
+        set_music(&channels.music, music_of(&properties));
+        update_player(&channels.player, player_of(&properties));
+        shared.registry.sync(pid);
+
+        if exit.wait(config::get().watcher.poll_interval()) {
+            log::info!(event = "exit", pid = pid; "The process {} exited", pid);
+            break;
+        }
+        let Some(next) = read() else {
+            // the player is gone while the process keeps running, e.g. it was turned off
+            break;
+        };
+        properties = next;
+    }
+    shared.registry.remove(pid);
+}
+
+fn string(value: &Value) -> Option<String> {
+    match value {
+        Value::Str(x) => Some(x.to_string()),
+        Value::ObjectPath(x) => Some(x.to_string()),
+        Value::Value(x) => string(x),
+        _ => None,
+    }
+}
+
+fn strings(value: &Value) -> Vec<String> {
+    match value {
+        Value::Array(x) => x.iter().filter_map(string).collect(),
+        Value::Value(x) => strings(x),
+        x => string(x).into_iter().collect(),
+    }
+}
+
+fn integer(value: &Value) -> Option<i64> {
+    match value {
+        Value::I64(x) => Some(*x),
+        Value::U64(x) => i64::try_from(*x).ok(),
+        Value::I32(x) => Some(*x as i64),
+        Value::U32(x) => Some(*x as i64),
+        Value::Value(x) => integer(x),
+        _ => None,
+    }
+}
+
+/// The NetEase id of the track, from the link to its page or the track id, or else one made up
+/// from the track, which is negative not to be taken for a real one. The made up one is the same
+/// across runs and builds, it names the cached art.
+fn id_of(metadata: &HashMap<&str, &Value>, music: &Music) -> i64 {
+    let from_url = metadata
+        .get("xesam:url")
+        .and_then(|x| string(x))
+        .filter(|x| x.contains("163"))
+        .and_then(|url| {
+            let (_, query) = url.split_once('?')?;
+            query
+                .split('&')
+                .find_map(|x| x.strip_prefix("id="))?
+                .parse()
+                .ok()
+        });
+    let from_track_id = || {
+        let track_id = string(metadata.get("mpris:trackid")?)?;
+        track_id.rsplit('/').next()?.parse().ok()
+    };
+    from_url.or_else(from_track_id).unwrap_or_else(|| {
+        let mut hasher = Sha256::new();
+        for field in [&music.name, &music.album]
+            .into_iter()
+            .chain(&music.artists)
+        {
+            hasher.update(field.as_bytes());
+            hasher.update([0]);
+        }
+        let digest = hasher.finalize();
+        let hash = u64::from_le_bytes(digest[..8].try_into().unwrap());
+        -((hash >> 1) as i64) - 1
+    })
+}
+
+/// The playing track, from the properties of the player.
+fn music_of(properties: &HashMap<String, OwnedValue>) -> Option<Music> {
+    let Some(Value::Dict(dict)) = properties.get("Metadata").map(|x| &**x) else {
+        return None;
+    };
+    let metadata: HashMap<&str, &Value> = dict
+        .iter()
+        .filter_map(|(k, v)| match k {
+            Value::Str(k) => Some((k.as_str(), v)),
+            _ => None,
+        })
+        .collect();
+    let name = metadata.get("xesam:title").and_then(|x| string(x))?;
+    let mut music = Music {
+        id: 0,
+        aliases: None,
+        thumbnail: metadata
+            .get("mpris:artUrl")
+            .and_then(|x| string(x))
+            .unwrap_or_default(),
+        album: metadata
+            .get("xesam:album")
+            .and_then(|x| string(x))
+            .unwrap_or_default(),
+        artists: metadata
+            .get("xesam:artist")
+            .map(|x| strings(x))
+            .unwrap_or_default(),
+        // microseconds
+        duration: metadata
+            .get("mpris:length")
+            .and_then(|x| integer(x))
+            .map_or(0, |x| x / 1000),
+        name,
+    };
+    music.id = id_of(&metadata, &music);
+    Some(music)
+}
+
+fn player_of(properties: &HashMap<String, OwnedValue>) -> Player {
+    let get = |name: &str| properties.get(name).map(|x| &**x);
+    let shuffle = matches!(get("Shuffle"), Some(Value::Bool(true)));
+    Player {
+        volume: match get("Volume") {
+            Some(Value::F64(x)) => Some(x.clamp(0., 1.)),
+            _ => None,
+        },
+        // MPRIS has no muting
+        muted: None,
+        play_mode: match get("LoopStatus").and_then(string).as_deref() {
+            _ if shuffle => Some(PlayMode::Shuffle),
+            Some("None") => Some(PlayMode::Order),
+            Some("Playlist") => Some(PlayMode::Loop),
+            Some("Track") => Some(PlayMode::Single),
+            _ => None,
+        },
+        paused: get("PlaybackStatus")
+            .and_then(string)
+            .map(|x| x != "Playing"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use zbus::zvariant::{Dict, ObjectPath, Signature};
+
+    use super::*;
+
+    fn owned(value: Value) -> OwnedValue {
+        value.try_into().unwrap()
+    }
+
+    fn properties(
+        metadata: &[(&str, Value)],
+        others: &[(&str, Value)],
+    ) -> HashMap<String, OwnedValue> {
+        let mut dict = Dict::new(&Signature::Str, &Signature::Variant);
+        for (k, v) in metadata {
+            dict.append(
+                Value::from(*k),
+                Value::Value(Box::new(v.try_clone().unwrap())),
+            )
+            .unwrap();
+        }
+        let mut properties: HashMap<_, _> = others
+            .iter()
+            .map(|(k, v)| (k.to_string(), owned(v.try_clone().unwrap())))
+            .collect();
+        properties.insert("Metadata".to_string(), owned(Value::Dict(dict)));
+        properties
+    }
+
+    #[test]
+    fn recognizes_clients() {
+        assert!(is_client(
+            "org.mpris.MediaPlayer2.netease-cloud-music",
+            None,
+            None
+        ));
+        assert!(is_client(
+            "org.mpris.MediaPlayer2.chromium.instance1234",
+            Some("YesPlayMusic"),
+            None
+        ));
+        assert!(is_client(
+            "org.mpris.MediaPlayer2.electron",
+            Some("网易云音乐"),
+            None
+        ));
+        assert!(!is_client(
+            "org.mpris.MediaPlayer2.chromium.instance1234",
+            Some("Chromium"),
+            Some("chromium")
+        ));
+        assert!(!is_client("org.mpris.MediaPlayer2.vlc", Some("VLC"), None));
+    }
+
+    #[test]
+    fn reads_the_metadata() {
+        let properties = properties(
+            &[
+                ("xesam:title", Value::from("晴天")),
+                ("xesam:artist", Value::from(vec!["周杰伦"])),
+                ("xesam:album", Value::from("叶惠美")),
+                ("mpris:length", Value::I64(269_000_000)),
+                (
+                    "mpris:artUrl",
+                    Value::from("https://p1.music.126.net/a.jpg"),
+                ),
+                (
+                    "mpris:trackid",
+                    Value::ObjectPath(
+                        ObjectPath::try_from("/org/mpris/MediaPlayer2/186016").unwrap(),
+                    ),
+                ),
+            ],
+            &[
+                ("Position", Value::I64(1_500_000)),
+                ("PlaybackStatus", Value::from("Paused")),
+                ("LoopStatus", Value::from("Track")),
+                ("Shuffle", Value::Bool(false)),
+                ("Volume", Value::F64(0.5)),
+            ],
+        );
+        let music = music_of(&properties).unwrap();
+        assert_eq!(music.id, 186016);
+        assert_eq!(music.name, "晴天");
+        assert_eq!(music.artists, ["周杰伦"]);
+        assert_eq!(music.album, "叶惠美");
+        assert_eq!(music.duration, 269_000);
+        assert_eq!(music.thumbnail, "https://p1.music.126.net/a.jpg");
+        assert_eq!(
+            player_of(&properties),
+            Player {
+                volume: Some(0.5),
+                muted: None,
+                play_mode: Some(PlayMode::Single),
+                paused: Some(true),
+            }
+        );
+    }
+
+    /// Made up for the track titled "a", it must not change between builds.
+    const MADE_UP: i64 = -365361951396542454;
+
+    #[test]
+    fn finds_the_id() {
+        fn music(mut metadata: Vec<(&str, Value)>) -> i64 {
+            metadata.push(("xesam:title", Value::from("a")));
+            music_of(&properties(&metadata, &[])).unwrap().id
+        }
+        assert_eq!(
+            music(vec![(
+                "xesam:url",
+                Value::from("https://music.163.com/song?id=42&userid=1")
+            )]),
+            42
+        );
+        // a hash of something else
+        let made_up = music(vec![(
+            "mpris:trackid",
+            Value::ObjectPath(
+                ObjectPath::try_from("/org/chromium/MediaPlayer2/TrackList/TrackABCDEF").unwrap(),
+            ),
+        )]);
+        assert!(made_up < 0);
+        assert_eq!(made_up, music(Vec::new()));
+        assert_eq!(made_up, MADE_UP);
+    }
+}
@@ -1,51 +1,117 @@
-use lightningscanner::Scanner;
-use procfs::process::MemoryMap;
+use std::{fmt::Display, path::PathBuf};
+
+use procfs::process::{MMPermissions, MMapPath, MemoryMap};
 
 use crate::{
-    netease::unix::mem,
-    util::{extract_addr_from_instruction, is_64_bit_dll, MOVSD_PATTERN_32, MOVSD_PATTERN_64},
+    netease::{
+        probation::{find_candidates, Candidate},
+        unix::mem::ProcessMemory,
+    },
+    signature::Signature,
+    util::is_64_bit_dll,
 };
 
-pub fn determine_is_64_bit(pid: i32, map: &MemoryMap) -> Result<bool, ()> {
-    let len = (map.address.1 - map.address.0) as usize;
-
-    let Ok(buf) = mem::read_process_memory(pid, map.address.0 as usize, len) else {
-        return Err(());
+/// Whether the command line is the one of the main process of the client, not a renderer
+/// process of it or a wrapper of a launcher starting it.
+pub fn is_client(cmdline: &[String]) -> bool {
+    let Some(executable) = cmdline.first() else {
+        return false;
     };
-
-    is_64_bit_dll(&buf)
+    // a Windows path, or a Unix one for wrappers
+    let name = executable.rsplit(['\\', '/']).next().unwrap_or_default();
+    name.eq_ignore_ascii_case("cloudmusic.exe") && !cmdline.iter().any(|x| x.contains("--type"))
 }
 
-pub fn find_movsd_instructions(pid: i32, map: &MemoryMap, is_64_bit: bool) -> Option<usize> {
-    let len = (map.address.1 - map.address.0) as usize;
+/// The maps of cloudmusic.dll, including the anonymous ones following it.
+pub fn cloudmusic_maps(maps: impl IntoIterator<Item = MemoryMap>) -> Vec<MemoryMap> {
+    let mut in_cloudmusic_map = false;
+    let mut result = Vec::new();
+    for map in maps {
+        match &map.pathname {
+            MMapPath::Path(p) => {
+                in_cloudmusic_map = p.file_name().unwrap_or_default() == "cloudmusic.dll";
+            }
+            // the following maps of the same module might be anonymous, so we don't set in_cloudmusic_map to false immediately.
+            MMapPath::Anonymous => {}
+            _ => in_cloudmusic_map = false,
+        }
+        if in_cloudmusic_map {
+            result.push(map);
+        }
+    }
+    result
+}
 
-    let Ok(buf) = mem::read_process_memory(pid, map.address.0 as usize, len) else {
-        return None;
-    };
+#[derive(Clone, Debug, PartialEq)]
+pub enum ModuleError {
+    NotMapped,
+    Unreadable(String),
+    NotPe,
+}
 
-    let scanner = Scanner::new(if is_64_bit {
-        MOVSD_PATTERN_64
-    } else {
-        MOVSD_PATTERN_32
-    });
-
-    unsafe {
-        let buf_ptr = buf.as_ptr();
-        let result = scanner.find(None, buf_ptr, len);
-        let addr = result.get_addr() as usize;
-        if addr != 0 {
-            let relative_addr = addr - buf_ptr as usize; // we are doing scanning on our copy of memory, so get the relative offset instead.
-            let offset = extract_addr_from_instruction(&buf, relative_addr);
-
-            return Some(offset as usize);
+impl Display for ModuleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ModuleError::NotMapped => write!(f, "cloudmusic.dll is not mapped"),
+            ModuleError::Unreadable(err) => write!(f, "unable to read the memory: {}", err),
+            ModuleError::NotPe => write!(f, "the first map doesn't start with a PE header"),
         }
     }
+}
+
+/// cloudmusic.dll as mapped by Wine.
+pub struct Module {
+    /// where the header map starts, which is what RVAs are relative to
+    pub base: usize,
+    pub path: PathBuf,
+    pub header: Vec<u8>,
+    pub is_64_bit: bool,
+    code_maps: Vec<(usize, usize)>,
+}
+
+impl Module {
+    /// Reads the PE header from the first of the maps given by [`cloudmusic_maps`].
+    pub fn new(maps: &[MemoryMap], memory: &dyn ProcessMemory) -> Result<Self, ModuleError> {
+        let Some(header_map) = maps.first() else {
+            return Err(ModuleError::NotMapped);
+        };
+        let MMapPath::Path(path) = &header_map.pathname else {
+            unreachable!("the first map of cloudmusic.dll has its path");
+        };
+        let base = header_map.address.0 as usize;
+        let len = (header_map.address.1 - header_map.address.0).min(0x1000) as usize;
+        let header = memory.read(base, len).map_err(ModuleError::Unreadable)?;
+        let is_64_bit = is_64_bit_dll(&header).map_err(|_| ModuleError::NotPe)?;
+        Ok(Module {
+            base,
+            path: path.clone(),
+            header,
+            is_64_bit,
+            code_maps: maps
+                .iter()
+                // instructions must be in an executable map
+                .filter(|x| x.perms.contains(MMPermissions::EXECUTE))
+                .map(|x| (x.address.0 as usize, (x.address.1 - x.address.0) as usize))
+                .collect(),
+        })
+    }
 
-    None
+    /// Finds the addresses the signatures refer to, every match in the executable maps.
+    pub fn find_addresses<'a>(
+        &self,
+        signatures: &[&'a Signature],
+        memory: &dyn ProcessMemory,
+    ) -> Vec<Candidate<'a>> {
+        self.code_maps
+            .iter()
+            .filter_map(|(start, len)| Some((*start, memory.read(*start, *len).ok()?)))
+            .flat_map(|(start, buf)| find_candidates(&buf, start, signatures))
+            .collect()
+    }
 }
 
-pub fn read_double_from_addr(pid: i32, addr: usize) -> f64 {
-    let Ok(buf) = mem::read_process_memory(pid, addr, 8) else {
+pub fn read_double(memory: &dyn ProcessMemory, addr: usize) -> f64 {
+    let Ok(buf) = memory.read(addr, 8) else {
         return -1.;
     };
 
@@ -57,3 +123,207 @@ pub fn read_double_from_addr(pid: i32, addr: usize) -> f64 {
         val
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        netease::unix::mem::Image,
+        pe::synthetic_image,
+        signature::{Arch, SignatureDb},
+    };
+
+    use super::*;
+
+    fn map(address: u64, len: u64, perms: MMPermissions, pathname: MMapPath) -> MemoryMap {
+        MemoryMap {
+            address: (address, address + len),
+            perms,
+            offset: 0,
+            dev: (0, 0),
+            inode: 0,
+            pathname,
+            extension: Default::default(),
+        }
+    }
+
+    /// The memory of a process with the image mapped at `base` the way Wine does: the headers,
+    /// the code and anonymous data following them.
+    struct Memory {
+        image: Image,
+        maps: Vec<MemoryMap>,
+    }
+
+    impl Memory {
+        fn new(base: u64, file: &[u8]) -> Self {
+            let dll = || MMapPath::Path(PathBuf::from("/pfx/drive_c/Program Files/cloudmusic.dll"));
+            let mut data = vec![0; 0x3000];
+            // the headers and .text of synthetic_image, at their RVAs
+            data[..0x200].copy_from_slice(&file[..0x200]);
+            let text = &file[0x200..];
+            data[0x1000..0x1000 + text.len()].copy_from_slice(text);
+            Memory {
+                image: Image::new([(base as usize, data)]),
+                maps: vec![
+                    map(
+                        base - 0x1000,
+                        0x1000,
+                        MMPermissions::READ,
+                        MMapPath::Path(PathBuf::from("/usr/lib/wine/ntdll.so")),
+                    ),
+                    map(base, 0x1000, MMPermissions::READ, dll()),
+                    map(
+                        base + 0x1000,
+                        0x1000,
+                        MMPermissions::READ | MMPermissions::EXECUTE,
+                        dll(),
+                    ),
+                    map(
+                        base + 0x2000,
+                        0x1000,
+                        MMPermissions::READ | MMPermissions::WRITE,
+                        MMapPath::Anonymous,
+                    ),
+                    map(base + 0x3000, 0x1000, MMPermissions::READ, MMapPath::Heap),
+                ],
+            }
+        }
+    }
+
+    fn scan(memory: &Memory) -> (Module, Vec<(usize, usize, String)>) {
+        let maps = cloudmusic_maps(memory.maps.clone());
+        let module = Module::new(&maps, &memory.image).unwrap();
+        let db = SignatureDb::builtin();
+        let candidates = module
+            .find_addresses(&db.select(Arch::of(module.is_64_bit), None), &memory.image)
+            .into_iter()
+            .map(|x| (x.addr, x.instruction, x.signature.name.clone()))
+            .collect();
+        (module, candidates)
+    }
+
+    #[test]
+    fn scans_64_bit_modules() {
+        let base = 0x7f12_3400_0000;
+        let mut code = vec![0x90; 0x10];
+        // movsd [rip+0x100], xmm7; movsd [rip+...], xmm6
+        code.extend_from_slice(&[0xf2, 0x0f, 0x11, 0x3d, 0x00, 0x01, 0x00, 0x00]);
+        code.extend_from_slice(&[0xf2, 0x0f, 0x11, 0x35, 0, 0, 0, 0]);
+        // the image base in the file doesn't matter, the targets are relative to the mapping
+        let memory = Memory::new(base, &synthetic_image(true, 0x1_8000_0000, &code, None));
+
+        let (module, candidates) = scan(&memory);
+        assert!(module.is_64_bit);
+        assert_eq!(module.base, base as usize);
+        assert_eq!(
+            candidates,
+            [(
+                base as usize + 0x1118,
+                base as usize + 0x1010,
+                "time-x64".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn scans_32_bit_modules() {
+        let base = 0x1000_0000;
+        let mut code = vec![0xcc; 4];
+        // movsd [0x10203040], xmm1; push ...
+        code.extend_from_slice(&[0xf2, 0x0f, 0x11, 0x0d, 0x40, 0x30, 0x20, 0x10, 0x68]);
+        let memory = Memory::new(base, &synthetic_image(false, base, &code, None));
+
+        let (module, candidates) = scan(&memory);
+        assert!(!module.is_64_bit);
+        assert_eq!(
+            candidates,
+            [(0x1020_3040, base as usize + 0x1004, "time-x86".to_string())]
+        );
+    }
+
+    #[test]
+    fn reports_unusable_modules() {
+        let memory = Memory::new(0x1000_0000, &synthetic_image(false, 0, &[], None));
+        let maps = cloudmusic_maps(memory.maps.clone());
+
+        assert_eq!(
+            Module::new(&[], &memory.image).err(),
+            Some(ModuleError::NotMapped)
+        );
+        assert_eq!(
+            Module::new(&maps, &|_, _| Err("denied".to_string())).err(),
+            Some(ModuleError::Unreadable("denied".to_string()))
+        );
+        assert_eq!(
+            Module::new(&maps, &|_, len| Ok(vec![0; len])).err(),
+            Some(ModuleError::NotPe)
+        );
+    }
+
+    #[test]
+    fn reads_doubles() {
+        let mut data = 1.5f64.to_le_bytes().to_vec();
+        data.extend_from_slice(&(-1f64).to_le_bytes());
+        let image = Image::new([(0x1000, data)]);
+
+        assert_eq!(read_double(&image, 0x1000), 1.5);
+        // never written yet
+        assert_eq!(read_double(&image, 0x1008), 0.);
+        assert_eq!(read_double(&image, 0x1010), -1.);
+    }
+
+    #[test]
+    fn recognizes_the_client() {
+        let cmdline = |args: &[&str]| args.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+
+        assert!(is_client(&cmdline(&[
+            "C:\\Program Files\\NetEase\\CloudMusic\\cloudmusic.exe"
+        ])));
+        assert!(is_client(&cmdline(&[
+            "Z:\\home\\alice\\Games\\netease\\CloudMusic.exe",
+            "--autostart"
+        ])));
+        // renderers
+        assert!(!is_client(&cmdline(&[
+            "C:\\Program Files\\NetEase\\CloudMusic\\cloudmusic.exe",
+            "--type=renderer"
+        ])));
+        // wrappers of launchers
+        assert!(!is_client(&cmdline(&[
+            "C:\\windows\\system32\\steam.exe",
+            "Z:\\games\\cloudmusic.exe"
+        ])));
+        assert!(!is_client(&cmdline(&[
+            "/usr/bin/python3",
+            "proton",
+            "waitforexitandrun",
+            "/games/cloudmusic.exe"
+        ])));
+        assert!(!is_client(&cmdline(&["/usr/bin/notcloudmusic.exe"])));
+        assert!(!is_client(&[]));
+    }
+
+    #[test]
+    fn collects_cloudmusic_maps() {
+        let dll = || MMapPath::Path(PathBuf::from("/prefix/drive_c/cloudmusic.dll"));
+        let read = MMPermissions::READ;
+        let maps = vec![
+            map(
+                0x1000,
+                0x1000,
+                read,
+                MMapPath::Path("/usr/lib/libc.so".into()),
+            ),
+            map(0x2000, 0x1000, read, MMapPath::Anonymous),
+            map(0x3000, 0x1000, read, dll()),
+            map(0x4000, 0x1000, read, MMapPath::Anonymous),
+            map(0x5000, 0x1000, read, dll()),
+            map(0x6000, 0x1000, read, MMapPath::Heap),
+            map(0x7000, 0x1000, read, MMapPath::Anonymous),
+        ];
+        let found: Vec<_> = cloudmusic_maps(maps)
+            .into_iter()
+            .map(|x| x.address.0)
+            .collect();
+        assert_eq!(found, [0x3000, 0x4000, 0x5000]);
+    }
+}
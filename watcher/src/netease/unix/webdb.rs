@@ -0,0 +1,361 @@
+//! Finds webdb.dat of a process running under Wine.
+
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use procfs::process::Process;
+
+use crate::netease::{unix::launcher::Setup, WatcherOptions};
+
+/// Where webdb.dat is in the directory of a Windows user.
+const WEBDB: &str = "AppData/Local/NetEase/CloudMusic/Library/webdb.dat";
+
+/// What the search knows about the process.
+pub struct Context {
+    pub environ: HashMap<OsString, OsString>,
+    pub cwd: Option<PathBuf>,
+    /// `/proc/<pid>/root`, where its files are if it runs in another mount namespace
+    pub root: PathBuf,
+    pub setup: Setup,
+}
+
+impl Context {
+    pub fn of(process: &Process) -> Self {
+        let environ = process.environ().unwrap_or_default();
+        let root = PathBuf::from(format!("/proc/{}/root", process.pid));
+        Context {
+            setup: Setup::detect(&environ, &root),
+            environ,
+            cwd: process.cwd().ok(),
+            root,
+        }
+    }
+
+    fn env(&self, name: &str) -> Option<&OsStr> {
+        self.environ.get(OsStr::new(name)).map(|x| x.as_os_str())
+    }
+
+    /// The Wine prefixes the process might run in, with how each was found.
+    fn prefixes(&self, wineprefix: Option<&Path>) -> Vec<(PathBuf, String)> {
+        let mut prefixes = Vec::new();
+        match (wineprefix, self.env("WINEPREFIX")) {
+            (Some(pfx), _) => {
+                prefixes.push((pfx.to_path_buf(), "the given Wine prefix".to_string()))
+            }
+            (None, Some(pfx)) => prefixes.push((
+                // relative to where it was started, which is the best guess left
+                self.cwd.as_deref().unwrap_or(Path::new("/")).join(pfx),
+                "WINEPREFIX".to_string(),
+            )),
+            (None, None) => {
+                let home = self.env("HOME").map(PathBuf::from).or_else(dirs::home_dir);
+                if let Some(home) = home {
+                    prefixes.push((home.join(".wine"), "the default Wine prefix".to_string()));
+                }
+            }
+        }
+        prefixes.extend(self.setup.prefixes(&self.environ, &self.root));
+        // Wine starts programs in their own directory, which is inside the prefix
+        if let Some(pfx) = self
+            .cwd
+            .as_deref()
+            .and_then(|cwd| cwd.ancestors().find(|x| x.ends_with("drive_c")))
+            .and_then(Path::parent)
+        {
+            prefixes.push((
+                pfx.to_path_buf(),
+                "the working directory of the process".to_string(),
+            ));
+        }
+        let inside_root = prefixes
+            .iter()
+            .filter_map(|(pfx, how)| {
+                let relative = pfx.strip_prefix("/").ok()?;
+                Some((
+                    self.root.join(relative),
+                    format!("{}, inside the root of the process", how),
+                ))
+            })
+            .collect::<Vec<_>>();
+        prefixes.extend(inside_root);
+        let mut seen = Vec::new();
+        prefixes.retain(|(pfx, _)| {
+            let new = !seen.contains(pfx);
+            seen.push(pfx.clone());
+            new
+        });
+        prefixes
+    }
+}
+
+/// The path of webdb.dat, and how it was found.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Found {
+    pub path: PathBuf,
+    pub how: String,
+    /// the Wine prefix it's in as the process sees it, unless the path was given
+    pub prefix: Option<PathBuf>,
+}
+
+/// Tries the path given by the options, then the user of the process in each Wine prefix it
+/// might run in, then every user of them.
+pub fn resolve(context: &Context, options: &WatcherOptions) -> Result<Found, String> {
+    if let Some(webdb) = &options.webdb {
+        return Ok(Found {
+            path: webdb.clone(),
+            how: "given by the options".to_string(),
+            prefix: None,
+        });
+    }
+    let prefixes = context.prefixes(options.wineprefix.as_deref());
+    let prefix = |pfx: &Path| match pfx.strip_prefix(&context.root) {
+        Ok(relative) => Some(Path::new("/").join(relative)),
+        Err(_) => Some(pfx.to_path_buf()),
+    };
+    if let Some(user) = context.env("USER") {
+        for (pfx, how) in &prefixes {
+            let path = pfx.join("drive_c/users").join(user).join(WEBDB);
+            if path.is_file() {
+                return Ok(Found {
+                    path,
+                    how: format!("in {}", how),
+                    prefix: prefix(pfx),
+                });
+            }
+        }
+    }
+    for (pfx, how) in &prefixes {
+        let Ok(users) = fs::read_dir(pfx.join("drive_c/users")) else {
+            continue;
+        };
+        let mut users: Vec<_> = users.filter_map(|x| Some(x.ok()?.path())).collect();
+        users.sort();
+        for user in users {
+            let path = user.join(WEBDB);
+            if path.is_file() {
+                return Ok(Found {
+                    path,
+                    how: format!(
+                        "in {}, as user {}",
+                        how,
+                        user.file_name().unwrap_or_default().to_string_lossy()
+                    ),
+                    prefix: prefix(pfx),
+                });
+            }
+        }
+    }
+    if prefixes.is_empty() {
+        return Err("no Wine prefix to look in, HOME is not set".to_string());
+    }
+    Err(format!(
+        "not in any of the Wine prefixes tried: {}",
+        prefixes
+            .iter()
+            .map(|(pfx, how)| format!("{} ({})", pfx.display(), how))
+            .collect::<Vec<_>>()
+            .join(", ")
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context(root: &Path, environ: &[(&str, &str)], cwd: Option<&str>) -> Context {
+        let environ = environ
+            .iter()
+            .map(|(k, v)| (OsString::from(k), OsString::from(v)))
+            .collect();
+        Context {
+            setup: Setup::detect(&environ, root),
+            environ,
+            cwd: cwd.map(PathBuf::from),
+            root: root.to_path_buf(),
+        }
+    }
+
+    /// Creates webdb.dat of the user in the prefix.
+    fn create(pfx: &Path, user: &str) -> PathBuf {
+        let path = pfx.join("drive_c/users").join(user).join(WEBDB);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, "").unwrap();
+        path
+    }
+
+    fn resolve_with(context: &Context, wineprefix: Option<&Path>) -> Result<Found, String> {
+        let options = WatcherOptions {
+            wineprefix: wineprefix.map(Path::to_path_buf),
+            ..Default::default()
+        };
+        resolve(context, &options)
+    }
+
+    #[test]
+    fn finds_the_user_in_the_prefix() {
+        let dir = tempfile::tempdir().unwrap();
+        let pfx = dir.path().join("pfx");
+        let path = create(&pfx, "alice");
+        create(&pfx, "bob");
+
+        let found = resolve_with(
+            &context(
+                Path::new("/"),
+                &[("WINEPREFIX", pfx.to_str().unwrap()), ("USER", "alice")],
+                None,
+            ),
+            None,
+        );
+        assert_eq!(
+            found,
+            Ok(Found {
+                path,
+                how: "in WINEPREFIX".to_string(),
+                prefix: Some(pfx),
+            })
+        );
+
+        // without WINEPREFIX, ~/.wine
+        let home = dir.path().join("home");
+        let path = create(&home.join(".wine"), "alice");
+        let found = resolve_with(
+            &context(
+                Path::new("/"),
+                &[("HOME", home.to_str().unwrap()), ("USER", "alice")],
+                None,
+            ),
+            None,
+        )
+        .unwrap();
+        assert_eq!(found.path, path);
+        assert_eq!(found.how, "in the default Wine prefix");
+    }
+
+    #[test]
+    fn finds_the_prefix_of_the_working_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let pfx = dir.path().join("pfx");
+        let path = create(&pfx, "alice");
+        let cwd = pfx.join("drive_c/Program Files/NetEase/CloudMusic");
+
+        let found = resolve_with(
+            &context(
+                Path::new("/"),
+                &[("HOME", "/nonexistent"), ("USER", "alice")],
+                cwd.to_str(),
+            ),
+            None,
+        )
+        .unwrap();
+        assert_eq!(found.path, path);
+        assert_eq!(found.how, "in the working directory of the process");
+    }
+
+    #[test]
+    fn looks_inside_the_root_of_the_process() {
+        let root = tempfile::tempdir().unwrap();
+        let path = create(&root.path().join("pfx"), "alice");
+
+        let found = resolve_with(
+            &context(
+                root.path(),
+                &[("WINEPREFIX", "/pfx"), ("USER", "alice")],
+                None,
+            ),
+            None,
+        )
+        .unwrap();
+        assert_eq!(found.path, path);
+        assert_eq!(found.how, "in WINEPREFIX, inside the root of the process");
+        assert_eq!(found.prefix, Some(PathBuf::from("/pfx")));
+    }
+
+    #[test]
+    fn finds_the_prefix_of_the_launcher() {
+        let root = tempfile::tempdir().unwrap();
+        let path = create(
+            &root
+                .path()
+                .join("home/alice/.steam/steamapps/compatdata/42/pfx"),
+            "steamuser",
+        );
+
+        let found = resolve_with(
+            &context(
+                root.path(),
+                &[
+                    ("HOME", "/home/alice"),
+                    ("USER", "alice"),
+                    (
+                        "STEAM_COMPAT_DATA_PATH",
+                        "/home/alice/.steam/steamapps/compatdata/42",
+                    ),
+                ],
+                None,
+            ),
+            None,
+        )
+        .unwrap();
+        assert_eq!(found.path, path);
+        assert_eq!(
+            found.how,
+            "in the Proton prefix, inside the root of the process, as user steamuser"
+        );
+    }
+
+    #[test]
+    fn tries_every_user() {
+        let dir = tempfile::tempdir().unwrap();
+        let pfx = dir.path().join("pfx");
+        let path = create(&pfx, "steamuser");
+        fs::create_dir_all(pfx.join("drive_c/users/Public")).unwrap();
+
+        // USER doesn't match, or isn't set
+        for environ in [&[("USER", "alice")][..], &[]] {
+            let found = resolve_with(&context(Path::new("/"), environ, None), Some(&pfx)).unwrap();
+            assert_eq!(found.path, path);
+            assert_eq!(found.how, "in the given Wine prefix, as user steamuser");
+        }
+    }
+
+    #[test]
+    fn prefers_the_given_path() {
+        let options = WatcherOptions {
+            webdb: Some(PathBuf::from("/somewhere/webdb.dat")),
+            ..Default::default()
+        };
+        let found = resolve(&context(Path::new("/"), &[], None), &options).unwrap();
+        assert_eq!(found.path, Path::new("/somewhere/webdb.dat"));
+    }
+
+    #[test]
+    fn lists_the_prefixes_tried() {
+        let root = tempfile::tempdir().unwrap();
+        let err = resolve_with(
+            &context(
+                root.path(),
+                &[("WINEPREFIX", "/pfx")],
+                Some("/pfx2/drive_c/x"),
+            ),
+            None,
+        )
+        .unwrap_err();
+        assert!(err.contains("/pfx (WINEPREFIX)"), "{}", err);
+        assert!(
+            err.contains("/pfx2 (the working directory of the process)"),
+            "{}",
+            err
+        );
+        assert!(
+            err.contains(
+                "pfx2 (the working directory of the process, inside the root of the process)"
+            ),
+            "{}",
+            err
+        );
+    }
+}
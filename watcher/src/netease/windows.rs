@@ -1,23 +1,29 @@
 use std::{
+    collections::HashMap,
     ffi::c_void,
     path::Path,
+    sync::{Arc, Mutex},
+    thread::JoinHandle,
     time::{Duration, Instant},
 };
 
-use notify::EventKind;
-use rusqlite::Connection;
-use tokio::sync::{oneshot, watch};
+use tokio::sync::{
+    oneshot::{self, error::TryRecvError},
+    watch,
+};
 use windows::{
     core::{HSTRING, PCWSTR},
     Win32::{
-        Foundation::{HMODULE, MAX_PATH},
+        Foundation::{CloseHandle, HANDLE},
+        Security::{TOKEN_IMPERSONATE, TOKEN_QUERY},
         System::{
+            Com::CoTaskMemFree,
             LibraryLoader::{GetProcAddress, LoadLibraryW},
-            ProcessStatus::{
-                EnumProcessModulesEx, EnumProcesses, GetModuleBaseNameW, GetProcessImageFileNameW,
-                LIST_MODULES_ALL,
+            ProcessStatus::EnumProcesses,
+            Threading::{
+                OpenProcess, OpenProcessToken, PROCESS_QUERY_INFORMATION,
+                PROCESS_QUERY_LIMITED_INFORMATION, PROCESS_VM_READ,
             },
-            Threading::{OpenProcess, PROCESS_QUERY_INFORMATION, PROCESS_VM_READ},
         },
         UI::{
             Shell::{FOLDERID_LocalAppData, SHGetKnownFolderPath, KNOWN_FOLDER_FLAG},
@@ -27,253 +33,448 @@ use windows::{
 };
 
 use crate::{
+    config,
     netease::{
-        create_file_watcher, stoppable_sleep, update_music,
-        windows::process::get_process_thread_ids, FIND_RETRY_SECS,
+        changes::Changes,
+        database::Database,
+        instances::{Channels, Registry},
+        offsets::OffsetCache,
+        player::PlayerReader,
+        probation::{Candidate, Probation, Verdict},
+        track_duration, update_music, update_player,
+        windows::process::get_process_thread_ids,
+        WatcherOptions,
     },
-    Music,
+    pe,
+    signature::{self, Arch, SignatureDb, Variable},
+    util::is_64_bit_dll,
+    Music, Player,
 };
 
+pub(super) mod doctor;
 mod process;
 mod util;
 
 const HOOK_COOLDOWN: u64 = 3;
 
+/// How soon a client that's found but not attached is tried again at first, e.g. cloudmusic.dll
+/// isn't loaded until the main window shows.
+const QUICK_RETRY: Duration = Duration::from_secs(1);
+
 pub struct NeteaseWatcherWindows {
     pub(super) time: (watch::Sender<f64>, watch::Receiver<f64>),
     pub(super) music: (watch::Sender<Option<Music>>, watch::Receiver<Option<Music>>),
+    pub(super) player: (watch::Sender<Player>, watch::Receiver<Player>),
+    pub(super) registry: Arc<Registry>,
     pub(super) scheduled_find_time: (
         watch::Sender<Option<Instant>>,
         watch::Receiver<Option<Instant>>,
     ),
-    pub(super) watch_thread: Option<(oneshot::Sender<()>, std::thread::JoinHandle<()>)>,
-    webdb_file: String,
+    pub(super) watch_thread: Option<(oneshot::Sender<()>, JoinHandle<()>)>,
+    options: WatcherOptions,
+}
+
+/// What the threads watching each process share.
+struct Shared {
+    options: WatcherOptions,
+    signatures: SignatureDb,
+    offsets: Mutex<OffsetCache>,
+    registry: Arc<Registry>,
 }
 
 impl NeteaseWatcherWindows {
-    pub fn new() -> Self {
+    pub fn new(options: WatcherOptions) -> Self {
         let (time_tx, time_rx) = watch::channel(-1.0);
         let (music_tx, music_rx) = watch::channel(None);
+        let (player_tx, player_rx) = watch::channel(Player::default());
         let (scheduled_find_time_tx, scheduled_find_time_rx) = watch::channel(Some(Instant::now()));
-        let netease_library_dir = {
-            let app_data_path = unsafe {
-                let path = SHGetKnownFolderPath(&FOLDERID_LocalAppData, KNOWN_FOLDER_FLAG(0), None)
-                    .expect("Unable to fetch AppData path.");
-                path.to_string().expect("Unable to call Windows API.")
-            };
-            Path::new(&app_data_path)
-                .join("NetEase\\CloudMusic\\Library")
-                .to_str()
-                .expect("Unable to get path of library.")
-                .to_string()
-        };
-        let netease_webdb_file = format!("{}{}", netease_library_dir, "\\webdb.dat");
+        let registry = Registry::new(
+            Channels {
+                time: time_tx.clone(),
+                music: music_tx.clone(),
+                player: player_tx.clone(),
+            },
+            options.primary.clone(),
+        );
         NeteaseWatcherWindows {
             time: (time_tx, time_rx),
             music: (music_tx, music_rx),
+            player: (player_tx, player_rx),
+            registry: Arc::new(registry),
             scheduled_find_time: (scheduled_find_time_tx, scheduled_find_time_rx),
             watch_thread: None,
-            webdb_file: netease_webdb_file,
+            options,
         }
     }
 
+    fn default_webdb_file() -> String {
+        let app_data_path = unsafe {
+            let path = SHGetKnownFolderPath(&FOLDERID_LocalAppData, KNOWN_FOLDER_FLAG(0), None)
+                .expect("Unable to fetch AppData path.");
+            path.to_string().expect("Unable to call Windows API.")
+        };
+        webdb_in(&app_data_path)
+    }
+
     pub fn start(&mut self) {
         let (stop_signal, mut stop_rx) = oneshot::channel();
-        let time_tx = self.time.0.clone();
-        let music_tx = self.music.0.clone();
         let scheduled_find_time_tx = self.scheduled_find_time.0.clone();
         scheduled_find_time_tx.send(Some(Instant::now())).unwrap();
-        let netease_webdb_file = self.webdb_file.clone();
-        let sleep_duration = Duration::from_secs(FIND_RETRY_SECS);
-        let join_handle = std::thread::spawn(move || 'watcher_loop: loop {
-            if stop_rx.try_recv().is_ok() {
-                break 'watcher_loop;
+        let (signatures, errors) = SignatureDb::load(&self.options.signatures);
+        for err in errors {
+            log::warn!("{}", err);
+        }
+        let shared = Arc::new(Shared {
+            options: self.options.clone(),
+            signatures,
+            offsets: Mutex::new(OffsetCache::load(OffsetCache::default_path())),
+            registry: self.registry.clone(),
+        });
+        let join_handle = std::thread::spawn(move || {
+            // a thread for each client process, by pid
+            let mut watching: HashMap<u32, (oneshot::Sender<()>, JoinHandle<()>)> = HashMap::new();
+            loop {
+                if stop_rx.try_recv().is_ok() {
+                    break;
+                }
+                watching.retain(|_, (_, handle)| !handle.is_finished());
+                for pid in client_pids() {
+                    if watching.contains_key(&pid) || shared.options.pid.is_some_and(|x| x != pid) {
+                        continue;
+                    }
+                    let (stop, mut stop_rx) = oneshot::channel();
+                    let shared = shared.clone();
+                    let handle =
+                        std::thread::spawn(move || follow_process(pid, &shared, &mut stop_rx));
+                    watching.insert(pid, (stop, handle));
+                }
+                let sleep_duration = config::get().watcher.retry_interval();
+                // None while attached, clients started later are still found
+                scheduled_find_time_tx
+                    .send(if shared.registry.is_empty() {
+                        Some(Instant::now() + sleep_duration)
+                    } else {
+                        None
+                    })
+                    .unwrap();
+                if stoppable_sleep(sleep_duration, &mut stop_rx) {
+                    break;
+                }
             }
-            'find_process: {
-                unsafe {
-                    let mut process_ids = [0; 8192];
-                    let mut cb_needed: u32 = 0;
-
-                    let Ok(_) = EnumProcesses(
-                        process_ids.as_mut_ptr(),
-                        process_ids.len() as u32,
-                        &mut cb_needed,
-                    ) else {
-                        break 'find_process;
-                    };
-
-                    let count = cb_needed as usize / size_of::<u32>();
-                    'process: for pid in process_ids.iter().take(count) {
-                        let Ok(proc) =
-                            OpenProcess(PROCESS_QUERY_INFORMATION | PROCESS_VM_READ, false, *pid)
-                        else {
-                            continue;
-                        };
-                        let mut file_name = [0; MAX_PATH as usize];
-                        let len = GetProcessImageFileNameW(proc, &mut file_name);
-
-                        if len == 0 {
-                            log::error!(
-                                "Failed to find process name for {} ({})",
-                                pid,
-                                windows::core::Error::from_thread().message()
-                            );
-                            continue;
-                        }
-
-                        let file_name = String::from_utf16_lossy(&file_name[0..(len as usize)]);
-                        if !Path::new(&file_name)
-                            .file_name()
-                            .unwrap_or_default()
-                            .eq_ignore_ascii_case("cloudmusic.exe")
-                        {
-                            continue;
-                        }
-
-                        let mut process_modules = [HMODULE::default(); 512];
-                        let mut cb_needed: u32 = 0;
+            for (stop, handle) in watching.into_values() {
+                let _ = stop.send(());
+                let _ = handle.join();
+            }
+        });
+        self.watch_thread = Some((stop_signal, join_handle));
+    }
+}
 
-                        let Ok(_) = EnumProcessModulesEx(
-                            proc,
-                            process_modules.as_mut_ptr(),
-                            process_modules.len() as u32,
-                            &mut cb_needed,
-                            LIST_MODULES_ALL,
-                        ) else {
-                            continue;
-                        };
+/// The webdb.dat under the local application data directory.
+fn webdb_in(app_data: &str) -> String {
+    Path::new(app_data)
+        .join("NetEase\\CloudMusic\\Library\\webdb.dat")
+        .to_string_lossy()
+        .into_owned()
+}
 
-                        let count = cb_needed as usize / size_of::<HMODULE>();
-                        for hmod in process_modules.iter().take(count) {
-                            let mut base_name = [0; MAX_PATH as usize];
-                            let len = GetModuleBaseNameW(proc, Some(*hmod), &mut base_name);
-                            if len == 0 {
-                                let err = windows::core::Error::from_thread();
-                                if err.code().0 == 6 {
-                                    // process exited
-                                    continue 'process;
-                                }
-                                log::error!(
-                                    "Failed to find module name for {} ({})",
-                                    file_name,
-                                    err.message()
-                                );
-                                continue;
-                            }
+/// The webdb.dat of the user running the process, as each user has their own.
+fn webdb_of(process: HANDLE) -> Option<String> {
+    let mut token = HANDLE::default();
+    unsafe { OpenProcessToken(process, TOKEN_QUERY | TOKEN_IMPERSONATE, &mut token) }.ok()?;
+    let path =
+        unsafe { SHGetKnownFolderPath(&FOLDERID_LocalAppData, KNOWN_FOLDER_FLAG(0), Some(token)) };
+    let _ = unsafe { CloseHandle(token) };
+    let path = path.ok()?;
+    let app_data = unsafe { path.to_string() };
+    unsafe { CoTaskMemFree(Some(path.0 as _)) };
+    Some(webdb_in(&app_data.ok()?))
+}
 
-                            if !String::from_utf16_lossy(&base_name[0..(len as usize)])
-                                .eq_ignore_ascii_case("cloudmusic.dll")
-                            {
-                                continue;
-                            }
+/// The pids of every cloudmusic.exe, the main process among them is told by its modules later.
+fn client_pids() -> Vec<u32> {
+    let mut process_ids = [0; 8192];
+    let mut cb_needed: u32 = 0;
+    let Ok(_) = (unsafe {
+        EnumProcesses(
+            process_ids.as_mut_ptr(),
+            process_ids.len() as u32,
+            &mut cb_needed,
+        )
+    }) else {
+        return Vec::new();
+    };
+    let count = cb_needed as usize / size_of::<u32>();
+    process_ids
+        .iter()
+        .take(count)
+        .copied()
+        .filter(|pid| {
+            // limited rights are enough for the name, even for elevated processes
+            let Ok(process) =
+                (unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, *pid) })
+            else {
+                return false;
+            };
+            let name = util::image_name(process);
+            let _ = unsafe { CloseHandle(process) };
+            name.is_some_and(|name| {
+                Path::new(&name)
+                    .file_name()
+                    .unwrap_or_default()
+                    .eq_ignore_ascii_case("cloudmusic.exe")
+            })
+        })
+        .collect()
+}
 
-                            let Some(addr) = util::find_movsd_instructions(proc, hmod.0 as usize)
-                            else {
-                                continue;
-                            };
+/// Tries to attach to the process until it exits or the watcher stops, sooner at first as the
+/// client is likely still starting.
+fn follow_process(pid: u32, shared: &Shared, stop_rx: &mut oneshot::Receiver<()>) {
+    // held until the end, so it's this process even if the pid is reused
+    let process =
+        match unsafe { OpenProcess(PROCESS_QUERY_INFORMATION | PROCESS_VM_READ, false, pid) } {
+            Ok(process) => process,
+            Err(err) => {
+                log::debug!("Unable to open the process {}: {}", pid, err.message());
+                return;
+            }
+        };
+    let mut delay = QUICK_RETRY;
+    loop {
+        watch_process(pid, process, shared, stop_rx);
+        shared.registry.remove(pid);
+        if stoppable_sleep(delay, stop_rx) || !util::is_alive(process) {
+            break;
+        }
+        delay = (delay * 2).min(config::get().watcher.retry_interval());
+    }
+    let _ = unsafe { CloseHandle(process) };
+}
 
-                            let Ok(conn) = Connection::open(&netease_webdb_file) else {
-                                log::error!("Failed to open the database file.");
-                                continue;
-                            };
+/// Attaches to the process and follows it until it exits or the watcher stops.
+fn watch_process(pid: u32, proc: HANDLE, shared: &Shared, stop_rx: &mut oneshot::Receiver<()>) {
+    let Ok(Some(hmod)) = util::find_cloudmusic_dll(proc) else {
+        return;
+    };
+    let module_base = hmod.0 as usize;
+    let Some(header) = util::read_header(proc, module_base) else {
+        return;
+    };
+    let Ok(is_64_bit) = is_64_bit_dll(&header) else {
+        return;
+    };
+    let version = util::module_path(proc, hmod).and_then(|path| signature::version_of(&path));
+    log::debug!(
+        "Version of cloudmusic.dll: {:?}",
+        version.as_ref().map(|x| x.to_string())
+    );
+    let (selected, player_signatures): (Vec<_>, Vec<_>) = shared
+        .signatures
+        .select(Arch::of(is_64_bit), version.as_ref())
+        .into_iter()
+        .partition(|x| x.variable == Variable::Time);
+    let key = pe::fingerprint(&header);
+    let cached = key.as_deref().and_then(|key| {
+        shared
+            .offsets
+            .lock()
+            .unwrap()
+            .get(key, module_base, &selected, |addr, len| {
+                util::read_memory(proc, addr, len)
+            })
+    });
+    // the cached playback time, or the candidates to pick it from
+    let found = match cached {
+        Some(candidate) => Ok(candidate),
+        None => {
+            let probation = Probation::new(util::find_addresses(proc, module_base, &selected));
+            if probation.is_empty() {
+                return;
+            }
+            Err(probation)
+        }
+    };
 
-                            // initial update
-                            update_music(&conn, &music_tx);
+    let webdb_file = match &shared.options.webdb {
+        Some(webdb) => webdb.to_string_lossy().into_owned(),
+        None => webdb_of(proc).unwrap_or_else(NeteaseWatcherWindows::default_webdb_file),
+    };
+    let db = match Database::open(Path::new(&webdb_file)) {
+        Ok(db) => db,
+        Err(err) => {
+            log::error!("Unable to open {}: {}", webdb_file, err);
+            return;
+        }
+    };
 
-                            let Ok((_watcher, notify_rx)) =
-                                create_file_watcher(Path::new(&netease_webdb_file))
-                            else {
-                                log::error!("Failed to create file watcher.");
-                                continue;
-                            };
+    let channels = Channels::default();
+    // initial update, the duration of the track helps to pick the playback time
+    let read = update_music(&db, &channels.music);
 
-                            std::mem::drop(file_name);
+    let Candidate {
+        addr, signature, ..
+    } = match found {
+        Ok(candidate) => {
+            log::debug!("Using the cached playback time at {:#x}", candidate.addr);
+            candidate
+        }
+        Err(mut probation) => {
+            log::debug!(
+                "Watching {} playback time candidate(s) of the process {}",
+                probation.len(),
+                pid
+            );
+            if probation.run(
+                |addr| util::read_double_from_addr(proc, addr as *mut c_void),
+                track_duration(&channels.music),
+                stop_rx,
+            ) {
+                return;
+            }
+            let Some((candidate, verdict)) = probation.finish() else {
+                log::warn!(
+                    "None of the playback time candidates of the process {} behaved like a playback clock.",
+                    pid
+                );
+                return;
+            };
+            // a still one might be the wrong pick, so it's checked again next time
+            if let (Some(key), Verdict::Clock) = (key, verdict) {
+                shared
+                    .offsets
+                    .lock()
+                    .unwrap()
+                    .insert(key, module_base, &candidate);
+            }
+            candidate
+        }
+    };
+    log::info!(
+        event = "attach",
+        pid = pid;
+        "Found Netease Cloud Music process (pid {}), using signature {} at {:#x}",
+        pid,
+        signature.name,
+        addr
+    );
 
-                            scheduled_find_time_tx.send(None).unwrap(); // stop trying to find process for a while, since we have already found it.
+    let player = if player_signatures.is_empty() {
+        PlayerReader::default()
+    } else {
+        PlayerReader::new(util::find_addresses(proc, module_base, &player_signatures))
+    };
+    for variable in player.variables() {
+        log::info!(
+            "Reading {} at {:#x} with signature {}",
+            variable.signature.variable,
+            variable.addr,
+            variable.signature.name
+        );
+    }
 
-                            let mut hook = Vec::new();
-                            let mut last_hook_attempt = Instant::now()
-                                .checked_sub(Duration::from_secs(HOOK_COOLDOWN))
-                                .unwrap();
-                            let mut last_val = -1.;
-                            loop {
-                                if stop_rx.try_recv().is_ok() {
-                                    break 'watcher_loop;
-                                }
+    let mut changes = match Changes::new(Path::new(&webdb_file)) {
+        Ok(changes) => changes,
+        Err(err) => {
+            log::error!("Unable to watch the database for changes: {}", err);
+            return;
+        }
+    };
+    if !read {
+        changes.missed();
+    }
 
-                                let val = util::read_double_from_addr(proc, addr as *mut c_void);
-                                if val < 0. {
-                                    // unable to read properly
-                                    for hook in hook {
-                                        let _ = UnhookWindowsHookEx(hook);
-                                    }
-                                    continue 'process; // keep trying other processes
-                                }
-                                'hook: {
-                                    // optional, improves the detection of music changing
-                                    if !hook.is_empty()
-                                        || last_hook_attempt.elapsed().as_secs() < HOOK_COOLDOWN
-                                    {
-                                        break 'hook;
-                                    }
-                                    last_hook_attempt = Instant::now();
-                                    let Ok(threads) = get_process_thread_ids(*pid) else {
-                                        break 'hook;
-                                    };
-                                    let hook_lib_name = HSTRING::from("wndhok.dll");
-                                    let Ok(lib) = LoadLibraryW(PCWSTR(hook_lib_name.as_ptr()))
-                                    else {
-                                        break 'hook;
-                                    };
-                                    let Some(proc) = GetProcAddress(
-                                        lib,
-                                        windows::core::PCSTR(c"CBTProc".as_ptr().cast()),
-                                    ) else {
-                                        break 'hook;
-                                    };
-                                    let proc = std::mem::transmute::<unsafe extern "system" fn() -> isize, unsafe extern "system" fn(i32, windows::Win32::Foundation::WPARAM, windows::Win32::Foundation::LPARAM) -> windows::Win32::Foundation::LRESULT>(proc);
-                                    for thread in threads {
-                                        if let Ok(hhook) = SetWindowsHookExW(
-                                            WH_CBT,
-                                            Some(proc),
-                                            Some(lib.into()),
-                                            thread,
-                                        ) {
-                                            hook.push(hhook);
-                                        }
-                                    }
-                                    if !hook.is_empty() {
-                                        log::info!("Successfully hooked into Netease Cloud Music.");
-                                    }
-                                }
-                                if val != last_val {
-                                    if time_tx.send(val).is_ok() {
-                                        last_val = val;
-                                    }
-                                }
+    shared.registry.add(pid, None, &channels);
 
-                                if let Ok(Ok(e)) = notify_rx.try_recv() {
-                                    if matches!(e.kind, EventKind::Modify(_)) {
-                                        update_music(&conn, &music_tx);
-                                    }
-                                }
+    let mut hook = Vec::new();
+    let mut last_hook_attempt = Instant::now()
+        .checked_sub(Duration::from_secs(HOOK_COOLDOWN))
+        .unwrap();
+    let mut last_val = -1.;
+    loop {
+        if stop_rx.try_recv().is_ok() {
+            break;
+        }
 
-                                std::thread::sleep(Duration::from_millis(50));
-                            }
-                        }
-                    }
+        let val = util::read_double_from_addr(proc, addr as *mut c_void);
+        if val < 0. {
+            // unable to read properly
+            break;
+        }
+        'hook: {
+            // optional, improves the detection of music changing
+            if !hook.is_empty() || last_hook_attempt.elapsed().as_secs() < HOOK_COOLDOWN {
+                break 'hook;
+            }
+            last_hook_attempt = Instant::now();
+            let Ok(threads) = get_process_thread_ids(pid) else {
+                break 'hook;
+            };
+            let hook_lib_name = HSTRING::from("wndhok.dll");
+            let Ok(lib) = (unsafe { LoadLibraryW(PCWSTR(hook_lib_name.as_ptr())) }) else {
+                break 'hook;
+            };
+            let Some(proc) =
+                (unsafe { GetProcAddress(lib, windows::core::PCSTR(c"CBTProc".as_ptr().cast())) })
+            else {
+                break 'hook;
+            };
+            let proc = unsafe {
+                std::mem::transmute::<
+                    unsafe extern "system" fn() -> isize,
+                    unsafe extern "system" fn(
+                        i32,
+                        windows::Win32::Foundation::WPARAM,
+                        windows::Win32::Foundation::LPARAM,
+                    )
+                        -> windows::Win32::Foundation::LRESULT,
+                >(proc)
+            };
+            for thread in threads {
+                if let Ok(hhook) =
+                    unsafe { SetWindowsHookExW(WH_CBT, Some(proc), Some(lib.into()), thread) }
+                {
+                    hook.push(hhook);
                 }
             }
-            // reset states
-            time_tx.send(-1.).unwrap();
-            music_tx.send(None).unwrap();
-            scheduled_find_time_tx
-                .send(Some(Instant::now() + sleep_duration))
-                .unwrap();
-            if stoppable_sleep(sleep_duration, &mut stop_rx) {
-                break 'watcher_loop;
+            if !hook.is_empty() {
+                log::info!(event = "hook", pid = pid; "Successfully hooked into Netease Cloud Music.");
             }
-        });
-        self.watch_thread = Some((stop_signal, join_handle));
+        }
+        if val != last_val {
+            channels.time.send_replace(val);
+            last_val = val;
+        }
+        if !player.is_empty() {
+            update_player(
+                &channels.player,
+                player.read(|addr, len| util::read_memory(proc, addr, len)),
+            );
+        }
+
+        if changes.changed() && !update_music(&db, &channels.music) {
+            changes.missed();
+        }
+        shared.registry.sync(pid);
+
+        std::thread::sleep(config::get().watcher.poll_interval());
+    }
+    for hook in hook {
+        let _ = unsafe { UnhookWindowsHookEx(hook) };
+    }
+}
+
+/// Sleeps for the duration, returns true if the watcher stops meanwhile.
+fn stoppable_sleep(duration: Duration, stop_signal: &mut oneshot::Receiver<()>) -> bool {
+    let sleep_interval = Duration::from_millis(100);
+    let mut elapsed = Duration::ZERO;
+    while elapsed < duration {
+        // the signal might have been taken while watching
+        if !matches!(stop_signal.try_recv(), Err(TryRecvError::Empty)) {
+            return true;
+        }
+        std::thread::sleep(sleep_interval);
+        elapsed += sleep_interval;
     }
+    false
 }
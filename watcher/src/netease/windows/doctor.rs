@@ -0,0 +1,198 @@
+use std::{ffi::c_void, path::Path};
+
+use windows::Win32::{
+    Foundation::{CloseHandle, HANDLE},
+    System::{
+        ProcessStatus::EnumProcesses,
+        Threading::{
+            OpenProcess, PROCESS_QUERY_INFORMATION, PROCESS_QUERY_LIMITED_INFORMATION,
+            PROCESS_VM_READ,
+        },
+    },
+};
+
+use crate::{
+    doctor::{
+        check_candidates, check_player, check_webdb, load_signatures, Report, SUGGEST_SIGNATURES,
+    },
+    netease::{
+        probation::{Candidate, Probation},
+        windows::{util, NeteaseWatcherWindows},
+        WatcherOptions,
+    },
+    signature::{self, Arch, SignatureDb, Variable},
+    util::is_64_bit_dll,
+};
+
+/// Returns whether the process has cloudmusic.dll loaded, i.e. is the main process.
+fn diagnose_process(pid: u32, process: HANDLE, db: &SignatureDb, report: &mut Report) -> bool {
+    let module = match util::find_cloudmusic_dll(process) {
+        Ok(Some(module)) => module,
+        Ok(None) => {
+            report.detail(format!("{}: cloudmusic.dll is not loaded, skipped", pid));
+            return false;
+        }
+        Err(err) => {
+            report.section(format!("Process {}", pid));
+            report.fail(format!("Unable to list the modules: {}", err.message()));
+            return true;
+        }
+    };
+    let base = module.0 as usize;
+    report.section(format!("Process {}", pid));
+    report.ok(format!("cloudmusic.dll is loaded at {:#x}", base));
+
+    let Some(header) = util::read_header(process, base) else {
+        report.fail(format!(
+            "Unable to read the memory of the process: {}",
+            windows::core::Error::from_thread().message()
+        ));
+        report.suggest(
+            "Run the watcher as the same user as Netease Cloud Music, or as administrator if Netease Cloud Music runs as administrator",
+        );
+        return true;
+    };
+    report.ok("The memory of the process is readable");
+
+    let Ok(is_64_bit) = is_64_bit_dll(&header) else {
+        report.fail("Unable to read the PE header of cloudmusic.dll");
+        return true;
+    };
+    report.ok(format!(
+        "cloudmusic.dll is {}",
+        if is_64_bit { "64-bit" } else { "32-bit" }
+    ));
+
+    let path = util::module_path(process, module);
+    let version = path.as_deref().and_then(signature::version_of);
+    match &version {
+        Some(version) => report.ok(format!("cloudmusic.dll is version {}", version)),
+        None => report.warn(format!(
+            "Unable to read the version of {}, trying every signature",
+            path.as_ref()
+                .map(|x| x.display().to_string())
+                .unwrap_or("cloudmusic.dll".to_string())
+        )),
+    }
+
+    let (signatures, player_signatures): (Vec<_>, Vec<_>) = db
+        .select(Arch::of(is_64_bit), version.as_ref())
+        .into_iter()
+        .partition(|x| x.variable == Variable::Time);
+    if signatures.is_empty() {
+        report.fail("No signature applies to this version");
+        report.suggest(SUGGEST_SIGNATURES);
+        return true;
+    }
+    let probation = Probation::new(util::find_addresses(process, base, &signatures));
+    if probation.is_empty() {
+        let names: Vec<_> = signatures.iter().map(|x| x.name.as_str()).collect();
+        report.fail(format!(
+            "None of the signatures ({}) matched in cloudmusic.dll",
+            names.join(", ")
+        ));
+        report.suggest(SUGGEST_SIGNATURES);
+        return true;
+    }
+    let Some(Candidate { addr, .. }) = check_candidates(report, probation, |addr| {
+        util::read_double_from_addr(process, addr as *mut c_void)
+    }) else {
+        return true;
+    };
+
+    let time = util::read_double_from_addr(process, addr as *mut c_void);
+    if time < 0. {
+        report.warn(format!("Unable to read the playback time at {:#x}", addr));
+    } else {
+        report.ok(format!("Playback time is {:.3}s", time));
+    }
+
+    if !player_signatures.is_empty() {
+        check_player(
+            report,
+            &player_signatures,
+            util::find_addresses(process, base, &player_signatures),
+            |addr, len| util::read_memory(process, addr, len),
+        );
+    }
+    true
+}
+
+pub fn diagnose(options: &WatcherOptions, report: &mut Report) {
+    report.section("Processes");
+    let mut process_ids = [0; 8192];
+    let mut cb_needed: u32 = 0;
+    if let Err(err) = unsafe {
+        EnumProcesses(
+            process_ids.as_mut_ptr(),
+            process_ids.len() as u32,
+            &mut cb_needed,
+        )
+    } {
+        report.fail(format!("Unable to list processes: {}", err.message()));
+        return;
+    }
+
+    let count = cb_needed as usize / size_of::<u32>();
+    let mut main_found = false;
+    let db = load_signatures(options, report);
+    for pid in process_ids.iter().take(count).copied() {
+        // limited rights are enough for the name, even for elevated processes
+        let Ok(process) = (unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, pid) })
+        else {
+            continue;
+        };
+        let name = util::image_name(process);
+        let _ = unsafe { CloseHandle(process) };
+        let Some(name) = name else {
+            continue;
+        };
+        if !Path::new(&name)
+            .file_name()
+            .unwrap_or_default()
+            .eq_ignore_ascii_case("cloudmusic.exe")
+        {
+            continue;
+        }
+        report.detail(format!("{}: {}", pid, name));
+        if options.pid.is_some_and(|x| x != pid) {
+            report.detail("  not the one given by --pid, skipped");
+            continue;
+        }
+
+        let process = match unsafe {
+            OpenProcess(PROCESS_QUERY_INFORMATION | PROCESS_VM_READ, false, pid)
+        } {
+            Ok(process) => process,
+            Err(err) => {
+                report.section(format!("Process {}", pid));
+                report.fail(format!("Unable to open the process: {}", err.message()));
+                report.suggest(
+                    "Run the watcher as the same user as Netease Cloud Music, or as administrator if Netease Cloud Music runs as administrator",
+                );
+                main_found = true;
+                continue;
+            }
+        };
+        main_found |= diagnose_process(pid, process, &db, report);
+        let _ = unsafe { CloseHandle(process) };
+    }
+
+    if !main_found {
+        report.fail("No Netease Cloud Music process with cloudmusic.dll loaded found");
+        report.suggest(match options.pid {
+            Some(pid) => format!(
+                "Check that {} is the pid of the main cloudmusic.exe process, or drop --pid",
+                pid
+            ),
+            None => "Start Netease Cloud Music and wait until its main window shows".to_string(),
+        });
+    }
+
+    report.section("Database");
+    let webdb = match &options.webdb {
+        Some(webdb) => webdb.clone(),
+        None => NeteaseWatcherWindows::default_webdb_file().into(),
+    };
+    check_webdb(report, &webdb);
+}
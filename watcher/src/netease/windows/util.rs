@@ -1,24 +1,69 @@
-use std::ffi::c_void;
+use std::{ffi::c_void, path::PathBuf};
 
-use lightningscanner::Scanner;
 use windows::Win32::{
-    Foundation::HANDLE,
+    Foundation::{HANDLE, HMODULE, MAX_PATH, STILL_ACTIVE},
     System::{
         Diagnostics::Debug::ReadProcessMemory,
         Memory::{
             VirtualQueryEx, MEMORY_BASIC_INFORMATION, MEM_COMMIT, PAGE_EXECUTE_READ,
             PAGE_PROTECTION_FLAGS,
         },
+        ProcessStatus::{
+            EnumProcessModulesEx, GetModuleBaseNameW, GetModuleFileNameExW,
+            GetProcessImageFileNameW, LIST_MODULES_ALL,
+        },
+        Threading::GetExitCodeProcess,
     },
 };
 
-use crate::util::{
-    extract_addr_from_instruction, is_64_bit_dll, MOVSD_PATTERN_32, MOVSD_PATTERN_64,
+use crate::{
+    netease::probation::{find_candidates, Candidate},
+    signature::Signature,
 };
 
-pub fn find_movsd_instructions(process: HANDLE, module_base: usize) -> Option<usize> {
-    let mut scanner = None;
+/// Reads up to `len` bytes of the memory of the process.
+pub fn read_memory(process: HANDLE, addr: usize, len: usize) -> Option<Vec<u8>> {
+    let mut buffer = vec![0u8; len];
+    let mut bytes_read = 0;
+    unsafe {
+        ReadProcessMemory(
+            process,
+            addr as _,
+            buffer.as_mut_ptr().cast(),
+            buffer.len(),
+            Some(&mut bytes_read),
+        )
+    }
+    .ok()?;
+    buffer.truncate(bytes_read);
+    Some(buffer)
+}
+
+/// Reads the PE header of the module.
+pub fn read_header(process: HANDLE, module_base: usize) -> Option<Vec<u8>> {
+    read_memory(process, module_base, 0x1000)
+}
+
+/// The path of the module, to read its version from.
+pub fn module_path(process: HANDLE, module: HMODULE) -> Option<PathBuf> {
+    let mut file_name = [0; MAX_PATH as usize];
+    let len = unsafe { GetModuleFileNameExW(Some(process), Some(module), &mut file_name) };
+    if len == 0 {
+        return None;
+    }
+    Some(PathBuf::from(String::from_utf16_lossy(
+        &file_name[0..(len as usize)],
+    )))
+}
 
+/// Finds the addresses the signatures refer to, every match in the executable regions of the
+/// module.
+pub fn find_addresses<'a>(
+    process: HANDLE,
+    module_base: usize,
+    signatures: &[&'a Signature],
+) -> Vec<Candidate<'a>> {
+    let mut candidates = Vec::new();
     let mut mbi = MEMORY_BASIC_INFORMATION::default();
     let mut address = module_base;
 
@@ -30,28 +75,9 @@ pub fn find_movsd_instructions(process: HANDLE, module_base: usize) -> Option<us
             std::mem::size_of_val(&mbi),
         ) != 0
         {
-            if scanner.is_none() {
-                let mut buffer = vec![0u8; mbi.RegionSize];
-                let mut bytes_read = 0;
-                if ReadProcessMemory(
-                    process,
-                    address as _,
-                    buffer.as_mut_ptr().cast(),
-                    mbi.RegionSize,
-                    Some(&mut bytes_read),
-                )
-                .is_err()
-                {
-                    continue;
-                }
-                let Ok(is_64_bit) = is_64_bit_dll(&buffer) else {
-                    continue;
-                };
-                scanner = Some(Scanner::new(if is_64_bit {
-                    MOVSD_PATTERN_64
-                } else {
-                    MOVSD_PATTERN_32
-                }));
+            if mbi.AllocationBase as usize != module_base {
+                // past the end of the module
+                break;
             }
             if mbi.State == MEM_COMMIT
                 && (mbi.Protect & PAGE_EXECUTE_READ) != PAGE_PROTECTION_FLAGS(0)
@@ -67,25 +93,14 @@ pub fn find_movsd_instructions(process: HANDLE, module_base: usize) -> Option<us
                 )
                 .is_ok()
                 {
-                    let buf_ptr = buffer.as_ptr();
-                    let result = scanner.as_ref().unwrap().find(None, buf_ptr, bytes_read);
-                    let addr = result.get_addr() as usize;
-                    if addr != 0 {
-                        let relative_addr = addr - buf_ptr as usize; // we are doing scanning on our copy of memory, so get the relative offset instead.
-                        let instruction_addr = address + relative_addr;
-                        let offset = extract_addr_from_instruction(&buffer, relative_addr);
-
-                        let rip = instruction_addr + 8;
-                        let target_addr = rip.wrapping_add(offset as usize);
-
-                        return Some(target_addr);
-                    }
+                    buffer.truncate(bytes_read);
+                    candidates.extend(find_candidates(&buffer, address, signatures));
                 }
             }
             address = mbi.BaseAddress as usize + mbi.RegionSize;
         }
     }
-    None
+    candidates
 }
 
 pub fn read_double_from_addr(process: HANDLE, addr: *mut c_void) -> f64 {
@@ -102,3 +117,46 @@ pub fn read_double_from_addr(process: HANDLE, addr: *mut c_void) -> f64 {
     })
     .unwrap_or(-1.)
 }
+
+/// The path of the executable of the process, in device form.
+pub fn image_name(process: HANDLE) -> Option<String> {
+    let mut file_name = [0; MAX_PATH as usize];
+    let len = unsafe { GetProcessImageFileNameW(process, &mut file_name) };
+    if len == 0 {
+        return None;
+    }
+    Some(String::from_utf16_lossy(&file_name[0..(len as usize)]))
+}
+
+/// The module of cloudmusic.dll, which only the main process loads.
+pub fn find_cloudmusic_dll(process: HANDLE) -> Result<Option<HMODULE>, windows::core::Error> {
+    let mut process_modules = [HMODULE::default(); 512];
+    let mut cb_needed: u32 = 0;
+    unsafe {
+        EnumProcessModulesEx(
+            process,
+            process_modules.as_mut_ptr(),
+            process_modules.len() as u32,
+            &mut cb_needed,
+            LIST_MODULES_ALL,
+        )?;
+    }
+    let count = cb_needed as usize / size_of::<HMODULE>();
+    for hmod in process_modules.iter().take(count) {
+        let mut base_name = [0; MAX_PATH as usize];
+        let len = unsafe { GetModuleBaseNameW(process, Some(*hmod), &mut base_name) };
+        if len != 0
+            && String::from_utf16_lossy(&base_name[0..(len as usize)])
+                .eq_ignore_ascii_case("cloudmusic.dll")
+        {
+            return Ok(Some(*hmod));
+        }
+    }
+    Ok(None)
+}
+
+/// Whether the process is still running, it is if it can't be told.
+pub fn is_alive(process: HANDLE) -> bool {
+    let mut code = 0;
+    unsafe { GetExitCodeProcess(process, &mut code) }.is_err() || code == STILL_ACTIVE.0 as u32
+}
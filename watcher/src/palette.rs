@@ -0,0 +1,302 @@
+//! Color extraction from album art.
+
+use std::sync::Arc;
+
+use serde::Serialize;
+use tokio::sync::watch;
+
+use crate::{config, cover::CoverCache, Music};
+
+/// Number of colors in the palette.
+const PALETTE_SIZE: usize = 5;
+
+/// The size of the art we download for extracting colors, small enough to be fast.
+const SAMPLE_SIZE: u32 = 64;
+
+/// WCAG AA for normal text.
+const MIN_CONTRAST: f64 = 4.5;
+
+#[derive(Clone, Serialize, PartialEq, Debug)]
+pub struct Palette {
+    /// id of the music this palette belongs to
+    id: i64,
+    dominant: String,
+    /// most used colors first
+    colors: Vec<String>,
+    /// suggested background, the dominant color
+    background: String,
+    /// suggested foreground, readable on the background
+    foreground: String,
+}
+
+type Rgb = [u8; 3];
+
+fn to_css(color: Rgb) -> String {
+    format!("#{:02x}{:02x}{:02x}", color[0], color[1], color[2])
+}
+
+fn relative_luminance(color: Rgb) -> f64 {
+    let channel = |x: u8| {
+        let x = x as f64 / 255.;
+        if x <= 0.03928 {
+            x / 12.92
+        } else {
+            ((x + 0.055) / 1.055).powf(2.4)
+        }
+    };
+    0.2126 * channel(color[0]) + 0.7152 * channel(color[1]) + 0.0722 * channel(color[2])
+}
+
+fn contrast_ratio(a: Rgb, b: Rgb) -> f64 {
+    let (a, b) = (relative_luminance(a), relative_luminance(b));
+    let (lighter, darker) = if a > b { (a, b) } else { (b, a) };
+    (lighter + 0.05) / (darker + 0.05)
+}
+
+fn color_distance(a: Rgb, b: Rgb) -> f64 {
+    (0..3)
+        .map(|x| (a[x] as f64 - b[x] as f64).powi(2))
+        .sum::<f64>()
+        .sqrt()
+}
+
+/// Splits the pixels into boxes by the median of the widest channel, until we have enough boxes.
+///
+/// Returns the average color of each box with its pixel count, most used first.
+fn median_cut(pixels: Vec<Rgb>, count: usize) -> Vec<(Rgb, usize)> {
+    let channel_range = |pixels: &[Rgb], channel: usize| {
+        let min = pixels.iter().map(|x| x[channel]).min().unwrap_or(0);
+        let max = pixels.iter().map(|x| x[channel]).max().unwrap_or(0);
+        max - min
+    };
+    let widest_channel = |pixels: &[Rgb]| {
+        (0..3)
+            .max_by_key(|x| channel_range(pixels, *x))
+            .unwrap_or(0)
+    };
+
+    let mut boxes: Vec<Vec<Rgb>> = Vec::new();
+    if !pixels.is_empty() {
+        boxes.push(pixels);
+    }
+    while boxes.len() < count {
+        // split the box with the widest range
+        let Some((idx, _)) = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| x.len() > 1)
+            .map(|(idx, x)| (idx, channel_range(x, widest_channel(x))))
+            .filter(|(_, range)| *range > 0)
+            .max_by_key(|(_, range)| *range)
+        else {
+            break;
+        };
+        let mut pixels = boxes.swap_remove(idx);
+        let channel = widest_channel(&pixels);
+        pixels.sort_unstable_by_key(|x| x[channel]);
+        // split between different values, so a color never ends up in both boxes.
+        let median = pixels[pixels.len() / 2][channel];
+        let mut split = pixels.partition_point(|x| x[channel] < median);
+        if split == 0 {
+            split = pixels.partition_point(|x| x[channel] <= median);
+        }
+        let other = pixels.split_off(split);
+        boxes.push(pixels);
+        boxes.push(other);
+    }
+
+    let mut colors: Vec<(Rgb, usize)> = Vec::with_capacity(boxes.len());
+    for pixels in boxes {
+        let mut sum = [0usize; 3];
+        for pixel in &pixels {
+            for (channel, x) in sum.iter_mut().enumerate() {
+                *x += pixel[channel] as usize;
+            }
+        }
+        let color = sum.map(|x| (x / pixels.len()) as u8);
+        // a large area of a single color can end up in multiple boxes, merge them.
+        if let Some(similar) = colors
+            .iter_mut()
+            .find(|(x, _)| color_distance(*x, color) < 16.)
+        {
+            similar.1 += pixels.len();
+        } else {
+            colors.push((color, pixels.len()));
+        }
+    }
+    colors.sort_by_key(|x| std::cmp::Reverse(x.1));
+    colors
+}
+
+pub fn extract_palette(id: i64, image: &[u8]) -> Result<Palette, image::ImageError> {
+    let image = image::load_from_memory(image)?;
+    let image = image::imageops::thumbnail(&image.to_rgb8(), SAMPLE_SIZE, SAMPLE_SIZE);
+    let pixels: Vec<Rgb> = image.pixels().map(|x| x.0).collect();
+
+    let colors = median_cut(pixels, PALETTE_SIZE);
+    let dominant = colors.first().map(|x| x.0).unwrap_or([0, 0, 0]);
+
+    // prefer a color from the art itself as the foreground, fall back to black or white.
+    let foreground = colors
+        .iter()
+        .skip(1)
+        .map(|x| x.0)
+        .find(|x| contrast_ratio(*x, dominant) >= MIN_CONTRAST)
+        .unwrap_or_else(|| {
+            if contrast_ratio([255, 255, 255], dominant) >= contrast_ratio([0, 0, 0], dominant) {
+                [255, 255, 255]
+            } else {
+                [0, 0, 0]
+            }
+        });
+
+    Ok(Palette {
+        id,
+        dominant: to_css(dominant),
+        colors: colors.into_iter().map(|x| to_css(x.0)).collect(),
+        background: to_css(dominant),
+        foreground: to_css(foreground),
+    })
+}
+
+/// The palette needs the art, so it's disabled along with the cover integration.
+fn is_enabled(config: &config::Config) -> bool {
+    config.integrations.palette && config.integrations.cover
+}
+
+/// Extracts the palette of each music's art as the music changes.
+pub fn spawn_palette_task(
+    covers: Arc<CoverCache>,
+    mut music_rx: watch::Receiver<Option<Music>>,
+) -> watch::Receiver<Option<Palette>> {
+    let (palette_tx, palette_rx) = watch::channel(None);
+    let mut config_rx = config::subscribe();
+    tokio::spawn(async move {
+        loop {
+            let music = music_rx.borrow_and_update().clone();
+            let enabled = is_enabled(&config_rx.borrow_and_update());
+            let palette = match music {
+                Some(music) if enabled && !music.thumbnail.is_empty() => {
+                    covers.remember(&music);
+                    match covers.get(music.id, Some((SAMPLE_SIZE, SAMPLE_SIZE))).await {
+                        Ok(cover) => tokio::task::spawn_blocking(move || {
+                            extract_palette(music.id, &cover.data)
+                        })
+                        .await
+                        .ok()
+                        .and_then(|x| {
+                            x.map_err(|err| log::warn!("Unable to decode the cover: {}", err))
+                                .ok()
+                        }),
+                        Err(err) => {
+                            log::warn!("Unable to get the cover for extracting colors: {:?}", err);
+                            None
+                        }
+                    }
+                }
+                _ => None,
+            };
+            palette_tx.send_if_modified(|x| {
+                if *x == palette {
+                    return false;
+                }
+                *x = palette;
+                true
+            });
+            loop {
+                tokio::select! {
+                    changed = music_rx.changed() => {
+                        if changed.is_err() {
+                            return;
+                        }
+                        break;
+                    }
+                    _ = config_rx.changed() => {
+                        // only redo it when the integration is toggled
+                        if is_enabled(&config_rx.borrow_and_update()) != enabled {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    });
+    palette_rx
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn encode(width: u32, height: u32, pixel: impl Fn(u32, u32) -> Rgb) -> Vec<u8> {
+        let image = image::RgbImage::from_fn(width, height, |x, y| image::Rgb(pixel(x, y)));
+        let mut data = Vec::new();
+        image
+            .write_to(&mut Cursor::new(&mut data), image::ImageFormat::Png)
+            .unwrap();
+        data
+    }
+
+    fn from_css(color: &str) -> Rgb {
+        let value = u32::from_str_radix(color.trim_start_matches('#'), 16).unwrap();
+        [(value >> 16) as u8, (value >> 8) as u8, value as u8]
+    }
+
+    #[test]
+    fn single_color() {
+        let colors = median_cut(vec![[10, 20, 30]; 100], PALETTE_SIZE);
+        assert_eq!(colors, vec![([10, 20, 30], 100)]);
+    }
+
+    #[test]
+    fn empty_input() {
+        assert!(median_cut(Vec::new(), PALETTE_SIZE).is_empty());
+    }
+
+    #[test]
+    fn two_color_split() {
+        let mut pixels = vec![[255, 0, 0]; 30];
+        pixels.extend(vec![[0, 0, 255]; 70]);
+        let colors = median_cut(pixels, PALETTE_SIZE);
+        assert_eq!(colors, vec![([0, 0, 255], 70), ([255, 0, 0], 30)]);
+    }
+
+    #[test]
+    fn extracts_from_image() {
+        // left quarter white, the rest dark blue
+        let image = encode(
+            32,
+            32,
+            |x, _| if x < 8 { [255, 255, 255] } else { [0, 0, 128] },
+        );
+        let palette = extract_palette(1, &image).unwrap();
+        assert_eq!(palette.id, 1);
+        assert_eq!(palette.dominant, "#000080");
+        assert_eq!(palette.background, palette.dominant);
+        assert_eq!(palette.foreground, "#ffffff");
+        assert!(palette.colors.contains(&"#ffffff".to_string()));
+    }
+
+    #[test]
+    fn fallback_foreground_is_readable() {
+        for gray in (0..=255).step_by(15) {
+            let image = encode(8, 8, |_, _| [gray, gray, gray]);
+            let palette = extract_palette(1, &image).unwrap();
+            assert_eq!(palette.colors.len(), 1);
+            let foreground = from_css(&palette.foreground);
+            assert!(foreground == [0, 0, 0] || foreground == [255, 255, 255]);
+            assert!(
+                contrast_ratio(foreground, from_css(&palette.background)) >= MIN_CONTRAST,
+                "gray {}",
+                gray
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_image() {
+        assert!(extract_palette(1, b"not an image").is_err());
+    }
+}
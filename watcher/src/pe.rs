@@ -0,0 +1,442 @@
+//! Just enough of the PE format to find the code of a DLL on disk.
+
+use sha2::{Digest, Sha256};
+
+use crate::util::to_hex;
+
+/// IMAGE_SCN_MEM_EXECUTE
+const SCN_MEM_EXECUTE: u32 = 0x2000_0000;
+/// IMAGE_SCN_CNT_CODE
+const SCN_CNT_CODE: u32 = 0x20;
+
+const SECTION_HEADER_SIZE: usize = 40;
+/// index of the resource table in the data directories
+const RESOURCE_DIRECTORY: usize = 2;
+/// RT_VERSION
+const RESOURCE_VERSION: u32 = 16;
+/// VS_FIXEDFILEINFO.dwSignature
+const FIXED_FILE_INFO_SIGNATURE: u32 = 0xFEEF_04BD;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Section {
+    pub name: String,
+    pub virtual_address: u32,
+    pub virtual_size: u32,
+    pub raw_offset: u32,
+    pub raw_size: u32,
+    pub characteristics: u32,
+}
+
+impl Section {
+    pub fn is_executable(&self) -> bool {
+        self.characteristics & (SCN_MEM_EXECUTE | SCN_CNT_CODE) != 0
+    }
+
+    /// The contents of the section in the file.
+    pub fn data<'a>(&self, file: &'a [u8]) -> &'a [u8] {
+        let start = (self.raw_offset as usize).min(file.len());
+        let end = start.saturating_add(self.raw_size as usize).min(file.len());
+        &file[start..end]
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Image {
+    pub is_64_bit: bool,
+    /// the preferred address the image is loaded at
+    pub image_base: u64,
+    /// RVA and size of the resource table, zero if there is none
+    pub resources: (u32, u32),
+    pub sections: Vec<Section>,
+}
+
+impl Image {
+    /// Where the data at the RVA is in the file.
+    pub fn rva_to_offset(&self, rva: u32) -> Option<usize> {
+        let section = self.sections.iter().find(|x| {
+            rva >= x.virtual_address && rva - x.virtual_address < x.virtual_size.max(x.raw_size)
+        })?;
+        let offset = rva - section.virtual_address;
+        (offset < section.raw_size).then_some(section.raw_offset as usize + offset as usize)
+    }
+
+    /// The file version in the version resource, e.g. `[3, 0, 18, 203152]`.
+    pub fn file_version(&self, file: &[u8]) -> Option<[u16; 4]> {
+        let (rva, size) = self.resources;
+        let start = self.rva_to_offset(rva)?;
+        let table = file.get(start..start.checked_add(size as usize)?)?;
+
+        // type, name and language, taking the first name and language
+        let mut directory = 0;
+        let mut data_entry = None;
+        for level in 0..3 {
+            let named = read_u16(table, directory + 12)? as usize;
+            let ids = read_u16(table, directory + 14)? as usize;
+            let entry = (0..named + ids)
+                .map(|index| directory + 16 + index * 8)
+                .find(|entry| level > 0 || read_u32(table, *entry) == Some(RESOURCE_VERSION))?;
+            let offset = read_u32(table, entry + 4)?;
+            if offset & 0x8000_0000 == 0 {
+                data_entry = Some(offset as usize);
+                break;
+            }
+            directory = (offset & 0x7FFF_FFFF) as usize;
+        }
+        // IMAGE_RESOURCE_DATA_ENTRY
+        let data_entry = data_entry?;
+        let data_rva = read_u32(table, data_entry)?;
+        let data_size = read_u32(table, data_entry + 4)?;
+        let data_start = self.rva_to_offset(data_rva)?;
+        let data = file.get(data_start..data_start.checked_add(data_size as usize)?)?;
+
+        // VS_FIXEDFILEINFO follows the key of VS_VERSIONINFO, aligned to 4 bytes
+        let info = (0..data.len().saturating_sub(16))
+            .step_by(4)
+            .find(|x| read_u32(data, *x) == Some(FIXED_FILE_INFO_SIGNATURE))?;
+        let most = read_u32(data, info + 8)?;
+        let least = read_u32(data, info + 12)?;
+        Some([
+            (most >> 16) as u16,
+            most as u16,
+            (least >> 16) as u16,
+            least as u16,
+        ])
+    }
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        buf.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        buf.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        buf.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+pub fn parse(file: &[u8]) -> Result<Image, String> {
+    let is_64_bit = crate::util::is_64_bit_dll(file).map_err(|_| "Not a PE file")?;
+    let truncated = || "The PE headers are truncated".to_string();
+
+    let pe_offset = read_u32(file, 0x3C).ok_or_else(truncated)? as usize;
+    let section_count = read_u16(file, pe_offset + 6).ok_or_else(truncated)? as usize;
+    let optional_header_size = read_u16(file, pe_offset + 20).ok_or_else(truncated)? as usize;
+    let optional_header = pe_offset + 24;
+    let (image_base, data_directories) =
+        match read_u16(file, optional_header).ok_or_else(truncated)? {
+            // PE32
+            0x10B => (
+                read_u32(file, optional_header + 28).ok_or_else(truncated)? as u64,
+                optional_header + 92,
+            ),
+            // PE32+
+            0x20B => (
+                read_u64(file, optional_header + 24).ok_or_else(truncated)?,
+                optional_header + 108,
+            ),
+            magic => return Err(format!("Unknown optional header magic {:#x}", magic)),
+        };
+    let resources = match read_u32(file, data_directories) {
+        Some(count) if count as usize > RESOURCE_DIRECTORY => {
+            let entry = data_directories + 4 + RESOURCE_DIRECTORY * 8;
+            (
+                read_u32(file, entry).ok_or_else(truncated)?,
+                read_u32(file, entry + 4).ok_or_else(truncated)?,
+            )
+        }
+        _ => (0, 0),
+    };
+
+    let section_table = optional_header + optional_header_size;
+    let sections = (0..section_count)
+        .map(|index| {
+            let header = section_table + index * SECTION_HEADER_SIZE;
+            let name = file.get(header..header + 8)?;
+            Some(Section {
+                name: String::from_utf8_lossy(name)
+                    .trim_end_matches('\0')
+                    .to_string(),
+                virtual_size: read_u32(file, header + 8)?,
+                virtual_address: read_u32(file, header + 12)?,
+                raw_size: read_u32(file, header + 16)?,
+                raw_offset: read_u32(file, header + 20)?,
+                characteristics: read_u32(file, header + 36)?,
+            })
+        })
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| "The section table is truncated".to_string())?;
+
+    Ok(Image {
+        is_64_bit,
+        image_base,
+        resources,
+        sections,
+    })
+}
+
+/// Identifies the build of an image by its headers: the file header with the timestamp, the size
+/// of the image, the checksum and the section table. The image base is left out, as the loader
+/// might rewrite it in memory.
+pub fn fingerprint(header: &[u8]) -> Option<String> {
+    let pe_offset = read_u32(header, 0x3C)? as usize;
+    let section_count = read_u16(header, pe_offset + 6)? as usize;
+    let optional_header_size = read_u16(header, pe_offset + 20)? as usize;
+    let optional_header = pe_offset + 24;
+    let section_table = optional_header + optional_header_size;
+
+    let mut hasher = Sha256::new();
+    hasher.update(header.get(pe_offset + 4..optional_header)?);
+    // SizeOfImage and CheckSum, at the same offsets in PE32 and PE32+
+    hasher.update(header.get(optional_header + 56..optional_header + 60)?);
+    hasher.update(header.get(optional_header + 64..optional_header + 68)?);
+    hasher.update(header.get(section_table..section_table + section_count * SECTION_HEADER_SIZE)?);
+    Some(to_hex(&hasher.finalize()))
+}
+
+/// Builds a minimal DLL with a `.text` section holding the code and, if there is a version, an
+/// `.rsrc` section holding the version resource, for tests.
+#[cfg(test)]
+pub fn synthetic_image(
+    is_64_bit: bool,
+    image_base: u64,
+    code: &[u8],
+    version: Option<[u16; 4]>,
+) -> Vec<u8> {
+    const PE_OFFSET: usize = 0x40;
+    const HEADERS_SIZE: usize = 0x200;
+    const TEXT_RVA: u32 = 0x1000;
+    const RESOURCE_DATA: usize = 0x58;
+
+    fn put(file: &mut [u8], offset: usize, bytes: &[u8]) {
+        file[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+    fn section(
+        file: &mut [u8],
+        header: usize,
+        name: &[u8],
+        rva: u32,
+        data: (usize, usize),
+        flags: u32,
+    ) {
+        put(file, header, name);
+        put(file, header + 8, &(data.1 as u32).to_le_bytes());
+        put(file, header + 12, &rva.to_le_bytes());
+        put(file, header + 16, &(data.1 as u32).to_le_bytes());
+        put(file, header + 20, &(data.0 as u32).to_le_bytes());
+        put(file, header + 36, &flags.to_le_bytes());
+    }
+
+    let resources = version.map(|version| {
+        let mut table = vec![0u8; RESOURCE_DATA];
+        // type, name and language directories with one entry each
+        for (directory, id, next) in [
+            (0x00, RESOURCE_VERSION, 0x8000_0018u32),
+            (0x18, 1, 0x8000_0030),
+            (0x30, 0x409, 0x48),
+        ] {
+            put(&mut table, directory + 14, &1u16.to_le_bytes());
+            put(&mut table, directory + 16, &id.to_le_bytes());
+            put(&mut table, directory + 20, &next.to_le_bytes());
+        }
+        // VS_VERSIONINFO with its key, padded to 4 bytes, then VS_FIXEDFILEINFO
+        let mut info = vec![0u8; 6];
+        info.extend(
+            "VS_VERSION_INFO\0"
+                .encode_utf16()
+                .flat_map(|x| x.to_le_bytes()),
+        );
+        info.extend([0, 0]);
+        info.extend(FIXED_FILE_INFO_SIGNATURE.to_le_bytes());
+        info.extend(0x10000u32.to_le_bytes());
+        let [a, b, c, d] = version.map(u32::from);
+        info.extend(((a << 16) | b).to_le_bytes());
+        info.extend(((c << 16) | d).to_le_bytes());
+        info.resize(info.len() + 36, 0);
+        let len = info.len() as u16;
+        put(&mut info, 0, &len.to_le_bytes());
+        put(&mut info, 2, &52u16.to_le_bytes());
+        table.extend(info);
+        table
+    });
+
+    let optional_header_size: usize = if is_64_bit { 0xF0 } else { 0xE0 };
+    let text_offset = HEADERS_SIZE;
+    let rsrc_offset = text_offset + code.len();
+    let rsrc_rva = TEXT_RVA + (code.len() as u32).next_multiple_of(0x1000).max(0x1000);
+
+    let mut file = vec![0u8; HEADERS_SIZE];
+    put(&mut file, 0, b"MZ");
+    put(&mut file, 0x3C, &(PE_OFFSET as u32).to_le_bytes());
+
+    let pe = PE_OFFSET;
+    put(&mut file, pe, b"PE\0\0");
+    let machine: u16 = if is_64_bit { 0x8664 } else { 0x14C };
+    put(&mut file, pe + 4, &machine.to_le_bytes());
+    let section_count = 1 + resources.is_some() as u16;
+    put(&mut file, pe + 6, &section_count.to_le_bytes());
+    put(
+        &mut file,
+        pe + 20,
+        &(optional_header_size as u16).to_le_bytes(),
+    );
+
+    let optional_header = pe + 24;
+    let data_directories = if is_64_bit {
+        put(&mut file, optional_header, &0x20Bu16.to_le_bytes());
+        put(&mut file, optional_header + 24, &image_base.to_le_bytes());
+        optional_header + 108
+    } else {
+        put(&mut file, optional_header, &0x10Bu16.to_le_bytes());
+        put(
+            &mut file,
+            optional_header + 28,
+            &(image_base as u32).to_le_bytes(),
+        );
+        optional_header + 92
+    };
+    put(&mut file, data_directories, &16u32.to_le_bytes());
+
+    let header = optional_header + optional_header_size;
+    section(
+        &mut file,
+        header,
+        b".text",
+        TEXT_RVA,
+        (text_offset, code.len()),
+        SCN_CNT_CODE | SCN_MEM_EXECUTE,
+    );
+    file.extend_from_slice(code);
+
+    if let Some(mut table) = resources {
+        let entry = data_directories + 4 + RESOURCE_DIRECTORY * 8;
+        put(&mut file, entry, &rsrc_rva.to_le_bytes());
+        put(&mut file, entry + 4, &(table.len() as u32).to_le_bytes());
+        // the data entry points at the version info by RVA
+        let data_rva = rsrc_rva + RESOURCE_DATA as u32;
+        let data_size = (table.len() - RESOURCE_DATA) as u32;
+        put(&mut table, 0x48, &data_rva.to_le_bytes());
+        put(&mut table, 0x4C, &data_size.to_le_bytes());
+        section(
+            &mut file,
+            header + SECTION_HEADER_SIZE,
+            b".rsrc",
+            rsrc_rva,
+            (rsrc_offset, table.len()),
+            0x4000_0040,
+        );
+        file.extend_from_slice(&table);
+    }
+    file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_images() {
+        for (is_64_bit, image_base) in [(true, 0x1_8000_0000), (false, 0x1000_0000)] {
+            let file = synthetic_image(is_64_bit, image_base, &[0x90; 16], None);
+            let image = parse(&file).unwrap();
+            assert_eq!(image.is_64_bit, is_64_bit);
+            assert_eq!(image.image_base, image_base);
+            assert_eq!(image.sections.len(), 1);
+            assert_eq!(image.file_version(&file), None);
+
+            let text = &image.sections[0];
+            assert_eq!(text.name, ".text");
+            assert_eq!(text.virtual_address, 0x1000);
+            assert!(text.is_executable());
+            assert_eq!(text.data(&file), &[0x90; 16]);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_images() {
+        assert!(parse(b"not a dll").is_err());
+
+        let file = synthetic_image(true, 0x1_8000_0000, &[0x90; 16], None);
+        // cut into the section table
+        assert!(parse(&file[..0x150]).is_err());
+
+        let mut file = file;
+        file[0x40 + 24] = 0;
+        assert!(parse(&file).is_err());
+    }
+
+    #[test]
+    fn reads_file_version() {
+        for is_64_bit in [true, false] {
+            let file = synthetic_image(
+                is_64_bit,
+                0x1000_0000,
+                &[0x90; 0x20],
+                Some([3, 0, 18, 2031]),
+            );
+            let image = parse(&file).unwrap();
+            assert_eq!(image.sections[1].name, ".rsrc");
+            assert_eq!(image.file_version(&file), Some([3, 0, 18, 2031]));
+
+            // the resource table points outside of the file
+            let mut truncated = image.clone();
+            truncated.resources.1 = 0x10000;
+            assert_eq!(truncated.file_version(&file), None);
+        }
+    }
+
+    #[test]
+    fn maps_rvas_to_offsets() {
+        let file = synthetic_image(true, 0x1000_0000, &[0x90; 0x20], Some([1, 2, 3, 4]));
+        let image = parse(&file).unwrap();
+        assert_eq!(image.rva_to_offset(0x1000), Some(0x200));
+        assert_eq!(image.rva_to_offset(0x101F), Some(0x21F));
+        assert_eq!(image.rva_to_offset(0x2000), Some(0x220));
+        assert_eq!(image.rva_to_offset(0x500), None);
+        assert_eq!(image.rva_to_offset(0x9000), None);
+    }
+
+    #[test]
+    fn clamps_section_data() {
+        let section = Section {
+            name: ".text".to_string(),
+            virtual_address: 0x1000,
+            virtual_size: 0x100,
+            raw_offset: 4,
+            raw_size: 0x100,
+            characteristics: 0,
+        };
+        assert!(!section.is_executable());
+        assert_eq!(section.data(&[0; 8]).len(), 4);
+        assert!(section.data(&[0; 2]).is_empty());
+    }
+
+    #[test]
+    fn fingerprints_headers() {
+        let file = synthetic_image(true, 0x1_8000_0000, &[0x90; 0x20], None);
+        let key = fingerprint(&file).unwrap();
+        assert_eq!(key.len(), 64);
+
+        // the loader might rewrite the image base
+        let rebased = synthetic_image(true, 0x7ff0_0000_0000, &[0x90; 0x20], None);
+        assert_eq!(fingerprint(&rebased), Some(key.clone()));
+
+        let mut rebuilt = file.clone();
+        let pe_offset = u32::from_le_bytes(file[0x3C..0x40].try_into().unwrap()) as usize;
+        // TimeDateStamp
+        rebuilt[pe_offset + 8] ^= 1;
+        assert_ne!(fingerprint(&rebuilt), Some(key.clone()));
+
+        let resized = synthetic_image(true, 0x1_8000_0000, &[0x90; 0x40], None);
+        assert_ne!(fingerprint(&resized), Some(key));
+
+        assert_eq!(fingerprint(&file[..0x80]), None);
+    }
+}
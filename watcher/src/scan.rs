@@ -0,0 +1,205 @@
+//! The `scan` subcommand, checks whether a cloudmusic.dll matches the signatures without running it.
+
+use std::path::{Path, PathBuf};
+
+use crate::{
+    pe::{self, Image},
+    signature::{Arch, Signature, SignatureDb, Variable, Version},
+};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Match {
+    pub signature: String,
+    pub variable: Variable,
+    pub section: String,
+    pub instruction_rva: u64,
+    /// the address the instruction refers to, when loaded at the preferred image base
+    pub target_address: u64,
+    pub target_rva: u64,
+}
+
+/// Finds the signatures in the executable sections of the image.
+pub fn scan(file: &[u8], image: &Image, signatures: &[&Signature]) -> Vec<Match> {
+    let mut matches = Vec::new();
+    for signature in signatures {
+        for section in image.sections.iter().filter(|x| x.is_executable()) {
+            let data = section.data(file);
+            let base = image.image_base + section.virtual_address as u64;
+            for offset in signature.find(data) {
+                let Some(target_address) = signature.resolve(data, offset, base as usize) else {
+                    continue;
+                };
+                let target_address = target_address as u64;
+                matches.push(Match {
+                    signature: signature.name.clone(),
+                    variable: signature.variable,
+                    section: section.name.clone(),
+                    instruction_rva: section.virtual_address as u64 + offset as u64,
+                    target_address,
+                    target_rva: target_address.wrapping_sub(image.image_base),
+                });
+            }
+        }
+    }
+    matches
+}
+
+pub fn run(path: &Path, signature_files: &[PathBuf]) -> i32 {
+    let file = match std::fs::read(path) {
+        Ok(file) => file,
+        Err(err) => {
+            eprintln!("Unable to read {}: {}", path.display(), err);
+            return 1;
+        }
+    };
+    let image = match pe::parse(&file) {
+        Ok(image) => image,
+        Err(err) => {
+            eprintln!("Unable to parse {}: {}", path.display(), err);
+            return 1;
+        }
+    };
+    let version = image.file_version(&file).map(Version::from);
+
+    println!("File:       {}", path.display());
+    println!(
+        "Version:    {}",
+        version
+            .as_ref()
+            .map(|x| x.to_string())
+            .unwrap_or("unknown".to_string())
+    );
+    println!(
+        "Bitness:    {}",
+        if image.is_64_bit { "64-bit" } else { "32-bit" }
+    );
+    println!("Image base: {:#x}", image.image_base);
+    for section in image.sections.iter().filter(|x| x.is_executable()) {
+        println!(
+            "Section:    {} at RVA {:#x}, {} bytes",
+            section.name,
+            section.virtual_address,
+            section.data(&file).len()
+        );
+    }
+
+    let (db, errors) = SignatureDb::load(signature_files);
+    for err in errors {
+        eprintln!("{}", err);
+    }
+    let signatures = db.select(Arch::of(image.is_64_bit), version.as_ref());
+    if signatures.is_empty() {
+        println!("\nNo signature applies to this version.");
+        return 1;
+    }
+    for signature in &signatures {
+        println!(
+            "Signature:  {} [{}] ({})",
+            signature.name, signature.variable, signature.pattern
+        );
+    }
+
+    let matches = scan(&file, &image, &signatures);
+    println!();
+    for m in &matches {
+        println!(
+            "{} [{}] in {}: instruction at RVA {:#x}, target {:#x} (RVA {:#x})",
+            m.signature, m.variable, m.section, m.instruction_rva, m.target_address, m.target_rva
+        );
+    }
+    // the other variables are optional
+    let time_matches = matches
+        .iter()
+        .filter(|x| x.variable == Variable::Time)
+        .count();
+    if time_matches == 0 {
+        println!("No matches of the playback time, this version is not supported.");
+        return 1;
+    }
+    if time_matches > 1 {
+        println!(
+            "\n{} matches of the playback time, the watcher picks the one that counts like a clock.",
+            time_matches
+        );
+    }
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pe::synthetic_image;
+
+    fn scan_builtin(file: &[u8]) -> Vec<Match> {
+        let image = pe::parse(file).unwrap();
+        let db = SignatureDb::builtin();
+        let version = image.file_version(file).map(Version::from);
+        scan(
+            file,
+            &image,
+            &db.select(Arch::of(image.is_64_bit), version.as_ref()),
+        )
+    }
+
+    #[test]
+    fn scans_64_bit_images() {
+        let mut code = vec![0x90; 0x10];
+        // movsd [rip+0x100], xmm7; movsd [rip+...], xmm6
+        code.extend_from_slice(&[0xf2, 0x0f, 0x11, 0x3d, 0x00, 0x01, 0x00, 0x00]);
+        code.extend_from_slice(&[0xf2, 0x0f, 0x11, 0x35, 0, 0, 0, 0]);
+        // negative displacement
+        code.extend_from_slice(&[0xf2, 0x0f, 0x11, 0x3d, 0xf0, 0xff, 0xff, 0xff]);
+        code.extend_from_slice(&[0xf2, 0x0f, 0x11, 0x35, 0, 0, 0, 0]);
+        let file = synthetic_image(true, 0x1_8000_0000, &code, Some([3, 0, 18, 2031]));
+
+        assert_eq!(
+            scan_builtin(&file),
+            [
+                Match {
+                    signature: "time-x64".to_string(),
+                    variable: Variable::Time,
+                    section: ".text".to_string(),
+                    instruction_rva: 0x1010,
+                    target_address: 0x1_8000_1118,
+                    target_rva: 0x1118,
+                },
+                Match {
+                    signature: "time-x64".to_string(),
+                    variable: Variable::Time,
+                    section: ".text".to_string(),
+                    instruction_rva: 0x1020,
+                    target_address: 0x1_8000_1018,
+                    target_rva: 0x1018,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn scans_32_bit_images() {
+        let mut code = vec![0xcc; 4];
+        // movsd [0x10203040], xmm1; push ...
+        code.extend_from_slice(&[0xf2, 0x0f, 0x11, 0x0d, 0x40, 0x30, 0x20, 0x10, 0x68]);
+        let file = synthetic_image(false, 0x1000_0000, &code, None);
+
+        assert_eq!(
+            scan_builtin(&file),
+            [Match {
+                signature: "time-x86".to_string(),
+                variable: Variable::Time,
+                section: ".text".to_string(),
+                instruction_rva: 0x1004,
+                target_address: 0x1020_3040,
+                target_rva: 0x20_3040,
+            }]
+        );
+    }
+
+    #[test]
+    fn ignores_truncated_matches() {
+        // the push is cut off by the end of the section
+        let code = [0xf2, 0x0f, 0x11, 0x0d, 0x40, 0x30, 0x20, 0x10];
+        let file = synthetic_image(false, 0x1000_0000, &code, None);
+        assert!(scan_builtin(&file).is_empty());
+    }
+}
@@ -1,86 +1,163 @@
 use axum::{
     extract::{
-        ws::{Message, WebSocket, WebSocketUpgrade},
-        State,
+        ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        Query, State,
     },
-    response::IntoResponse,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
     Json,
 };
-use tokio::sync::watch::Receiver;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{mpsc::UnboundedSender, watch::Receiver},
+    task::JoinHandle,
+};
+
+use crate::{config, netease::instances::Instance};
 
-use crate::Music;
+pub mod auth;
+pub mod cors;
+pub mod cover;
+pub mod listener;
+pub mod overlay;
+pub mod tls;
 
 pub async fn http_handler(State(state): State<crate::State>) -> impl IntoResponse {
-    let crate::State(time_rx, music_rx) = state.clone();
-    let current_time = *time_rx.borrow();
-    let current_music = music_rx.borrow().clone();
+    let current_time = *state.time.borrow();
+    let current_music = state.music.borrow().clone();
+    let current_player = state.player.borrow().clone();
+    let current_palette = state.palette.borrow().clone();
     Json(serde_json::json!({
         "time": current_time,
-        "music": current_music
+        "music": current_music,
+        "player": current_player,
+        "palette": current_palette
     }))
 }
 
+/// Every attached client, with its state.
+pub async fn instances_handler(State(state): State<crate::State>) -> impl IntoResponse {
+    let instances: Vec<_> = state
+        .instances
+        .borrow()
+        .iter()
+        .map(|x| {
+            serde_json::json!({
+                "pid": x.pid,
+                "prefix": x.prefix,
+                "primary": x.primary,
+                "time": *x.time.borrow(),
+                "music": x.music.borrow().clone(),
+                "player": x.player.borrow().clone()
+            })
+        })
+        .collect();
+    Json(instances)
+}
+
+#[derive(Deserialize)]
+pub struct WsQuery {
+    /// pid or Wine prefix of the instance to follow, instead of the primary one
+    instance: Option<String>,
+}
+
 pub async fn ws_handler(
     ws: WebSocketUpgrade,
     State(state): State<crate::State>,
-) -> impl IntoResponse {
+    Query(query): Query<WsQuery>,
+    headers: HeaderMap,
+) -> Response {
+    if !cors::is_websocket_allowed(&config::get().server.cors, &headers) {
+        return (StatusCode::FORBIDDEN, "Origin not allowed").into_response();
+    }
+    let instance = match &query.instance {
+        Some(key) => {
+            let instance = state
+                .instances
+                .borrow()
+                .iter()
+                .find(|x| x.matches(key))
+                .cloned();
+            match instance {
+                Some(instance) => Some(instance),
+                None => return (StatusCode::NOT_FOUND, "Unknown instance").into_response(),
+            }
+        }
+        None => None,
+    };
     log::info!("New WebSocket connection.");
-    let crate::State(time_rx, music_rx) = state.clone();
-    ws.on_upgrade(move |socket| handle_socket(socket, time_rx, music_rx))
+    ws.on_upgrade(move |socket| handle_socket(socket, state, instance))
 }
 
-async fn handle_socket(
-    mut socket: WebSocket,
-    mut current_time: Receiver<f64>,
-    mut music: Receiver<Option<Music>>,
-) {
-    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
-
-    let tx_clone = tx.clone();
-    let current_time_task = tokio::spawn(async move {
+/// Sends a message of the given type whenever the value changes.
+fn forward_changes<T: Serialize + Send + Sync + 'static>(
+    mut rx: Receiver<T>,
+    tx: UnboundedSender<Message>,
+    event_type: &'static str,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
         loop {
-            if current_time.changed().await.is_err() {
+            if rx.changed().await.is_err() {
                 break;
             }
-            if let Err(_err) = tx_clone.send(Message::Text(
-                serde_json::json!({
-                    "type": "timechange",
-                    "value": *current_time.borrow()
-                })
-                .to_string()
-                .into(),
-            )) {
+            let msg = serde_json::json!({
+                "type": event_type,
+                "value": *rx.borrow()
+            })
+            .to_string();
+            if let Err(_err) = tx.send(Message::Text(msg.into())) {
                 break;
             }
         }
-    });
+    })
+}
 
-    let music_change_task = tokio::spawn(async move {
-        loop {
-            if music.changed().await.is_err() {
-                break;
+async fn handle_socket(mut socket: WebSocket, state: crate::State, instance: Option<Instance>) {
+    state.connections.send_modify(|x| *x += 1);
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+
+    let tasks = match instance {
+        // the palette is only extracted for the primary instance
+        Some(instance) => vec![
+            forward_changes(instance.time, tx.clone(), "timechange"),
+            forward_changes(instance.music, tx.clone(), "musicchange"),
+            forward_changes(instance.player, tx, "playerchange"),
+        ],
+        None => vec![
+            forward_changes(state.time, tx.clone(), "timechange"),
+            forward_changes(state.music, tx.clone(), "musicchange"),
+            forward_changes(state.player, tx.clone(), "playerchange"),
+            forward_changes(state.palette, tx, "palettechange"),
+        ],
+    };
+
+    let mut shutdown = state.shutdown;
+    loop {
+        tokio::select! {
+            msg = rx.recv() => {
+                let Some(msg) = msg else {
+                    break;
+                };
+                if let Err(_err) = socket.send(msg).await {
+                    break;
+                }
             }
-            if let Err(_err) = tx.send(Message::Text(
-                serde_json::json!({
-                    "type": "musicchange",
-                    "value": *music.borrow()
-                })
-                .to_string()
-                .into(),
-            )) {
+            _ = async { drop(shutdown.wait_for(|x| *x).await) } => {
+                let _ = socket
+                    .send(Message::Close(Some(CloseFrame {
+                        code: close_code::AWAY,
+                        reason: "Server shutting down".into(),
+                    })))
+                    .await;
                 break;
             }
         }
-    });
-
-    while let Some(msg) = rx.recv().await {
-        if let Err(_err) = socket.send(msg).await {
-            rx.close();
-            current_time_task.abort();
-            music_change_task.abort();
-            break;
-        }
+    }
+    rx.close();
+    for task in tasks {
+        task.abort();
     }
 
     log::info!("WebSocket disconnected.");
+    state.connections.send_modify(|x| *x -= 1);
 }
@@ -0,0 +1,209 @@
+//! Token authentication, the token is given as `Authorization: Bearer <token>` or `?token=<token>`.
+//!
+//! Browsers can't set headers on WebSocket connections or `<img>` requests, hence the query.
+
+use std::collections::HashMap;
+
+use axum::{
+    extract::{Query, Request, State},
+    http::{header, HeaderMap, StatusCode, Uri},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+
+use crate::config::{self, AuthConfig};
+
+/// What a token is allowed to do, a higher scope includes the lower ones.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// reading the status, the overlays and the covers
+    #[default]
+    Read,
+    /// controlling the watcher
+    Admin,
+}
+
+/// Compares in constant time, so the token can't be guessed by timing.
+fn secure_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+fn token_of(headers: &HeaderMap, uri: &Uri) -> Option<String> {
+    if let Some(value) = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|x| x.to_str().ok())
+    {
+        if let Some((scheme, token)) = value.split_once(' ') {
+            if scheme.eq_ignore_ascii_case("bearer") {
+                return Some(token.trim().to_string());
+            }
+        }
+    }
+    Query::<HashMap<String, String>>::try_from_uri(uri)
+        .ok()
+        .and_then(|Query(mut query)| query.remove("token"))
+}
+
+#[derive(Debug, PartialEq)]
+enum AuthError {
+    MissingToken,
+    InvalidToken,
+    InsufficientScope,
+}
+
+impl IntoResponse for AuthError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            AuthError::MissingToken => (StatusCode::UNAUTHORIZED, "Missing token"),
+            AuthError::InvalidToken => (StatusCode::UNAUTHORIZED, "Invalid token"),
+            AuthError::InsufficientScope => (StatusCode::FORBIDDEN, "Insufficient scope"),
+        };
+        (status, [(header::WWW_AUTHENTICATE, "Bearer")], message).into_response()
+    }
+}
+
+/// Checks whether the request is allowed to access something requiring the scope.
+fn check(
+    auth: &AuthConfig,
+    required: Scope,
+    headers: &HeaderMap,
+    uri: &Uri,
+) -> Result<(), AuthError> {
+    if auth.tokens.is_empty() {
+        return Ok(());
+    }
+    let Some(token) = token_of(headers, uri) else {
+        return Err(AuthError::MissingToken);
+    };
+    // go through every token, so the time doesn't depend on which one matches
+    let scope = auth
+        .tokens
+        .iter()
+        .filter(|x| secure_eq(x.token.as_bytes(), token.as_bytes()))
+        .map(|x| x.scope)
+        .max();
+    match scope {
+        None => Err(AuthError::InvalidToken),
+        Some(scope) if scope < required => Err(AuthError::InsufficientScope),
+        Some(_) => Ok(()),
+    }
+}
+
+/// Middleware requiring a token of the scope, use with `axum::middleware::from_fn_with_state`.
+pub async fn authorize(State(scope): State<Scope>, request: Request, next: Next) -> Response {
+    let config = config::get();
+    if let Err(err) = check(&config.server.auth, scope, request.headers(), request.uri()) {
+        return err.into_response();
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+    use crate::config::TokenConfig;
+
+    fn auth() -> AuthConfig {
+        AuthConfig {
+            tokens: vec![
+                TokenConfig {
+                    token: "reader".to_string(),
+                    scope: Scope::Read,
+                },
+                TokenConfig {
+                    token: "admin".to_string(),
+                    scope: Scope::Admin,
+                },
+            ],
+        }
+    }
+
+    fn status(auth: &AuthConfig, required: Scope, header: Option<&str>, uri: &str) -> StatusCode {
+        let mut headers = HeaderMap::new();
+        if let Some(header) = header {
+            headers.insert(
+                header::AUTHORIZATION,
+                HeaderValue::from_str(header).unwrap(),
+            );
+        }
+        match check(auth, required, &headers, &uri.parse().unwrap()) {
+            Ok(()) => StatusCode::OK,
+            Err(err) => err.into_response().status(),
+        }
+    }
+
+    #[test]
+    fn open_without_tokens() {
+        let auth = AuthConfig::default();
+        assert_eq!(status(&auth, Scope::Read, None, "/"), StatusCode::OK);
+        assert_eq!(status(&auth, Scope::Admin, None, "/ws"), StatusCode::OK);
+    }
+
+    #[test]
+    fn checks_tokens() {
+        let auth = auth();
+        // (required, authorization header, uri, expected)
+        let cases = [
+            (Scope::Read, None, "/", StatusCode::UNAUTHORIZED),
+            (Scope::Read, Some("Bearer reader"), "/", StatusCode::OK),
+            (Scope::Read, Some("bearer reader"), "/", StatusCode::OK),
+            (Scope::Read, Some("Bearer admin"), "/", StatusCode::OK),
+            (
+                Scope::Read,
+                Some("Bearer wrong"),
+                "/",
+                StatusCode::UNAUTHORIZED,
+            ),
+            (
+                Scope::Read,
+                Some("Basic reader"),
+                "/",
+                StatusCode::UNAUTHORIZED,
+            ),
+            (Scope::Read, None, "/ws?token=reader", StatusCode::OK),
+            (
+                Scope::Read,
+                None,
+                "/ws?token=read",
+                StatusCode::UNAUTHORIZED,
+            ),
+            (
+                Scope::Read,
+                None,
+                "/cover/1?param=64y64&token=admin",
+                StatusCode::OK,
+            ),
+            (
+                Scope::Admin,
+                Some("Bearer reader"),
+                "/",
+                StatusCode::FORBIDDEN,
+            ),
+            (Scope::Admin, None, "/?token=admin", StatusCode::OK),
+        ];
+        for (required, header, uri, expected) in cases {
+            assert_eq!(
+                status(&auth, required, header, uri),
+                expected,
+                "{:?} {}",
+                header,
+                uri
+            );
+        }
+    }
+
+    #[test]
+    fn compares_securely() {
+        assert!(secure_eq(b"token", b"token"));
+        assert!(!secure_eq(b"token", b"tokem"));
+        assert!(!secure_eq(b"token", b"token2"));
+        assert!(secure_eq(b"", b""));
+    }
+}
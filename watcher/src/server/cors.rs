@@ -0,0 +1,113 @@
+//! Cross-origin access, only the origins in `server.cors.allow_origins` may read the responses.
+
+use axum::http::{header, HeaderMap, HeaderValue, Method};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+use crate::config::{self, CorsConfig};
+
+fn is_listed(cors: &CorsConfig, origin: &HeaderValue) -> bool {
+    cors.allow_origins
+        .iter()
+        .any(|x| x == "*" || x.as_bytes() == origin.as_bytes())
+}
+
+/// The layer reads the configuration on every request, so changes take effect right away.
+pub fn layer() -> CorsLayer {
+    CorsLayer::new()
+        .allow_origin(AllowOrigin::predicate(|origin, _| {
+            is_listed(&config::get().server.cors, origin)
+        }))
+        .allow_methods([Method::GET])
+        .allow_headers([header::AUTHORIZATION])
+}
+
+/// WebSockets are not covered by CORS, so check the origin of the upgrade request ourselves.
+///
+/// Pages from ourselves, like the overlays, are always allowed. Anything else is allowed
+/// unless an allow-list is configured.
+pub fn is_websocket_allowed(cors: &CorsConfig, headers: &HeaderMap) -> bool {
+    let Some(origin) = headers.get(header::ORIGIN) else {
+        // not a browser
+        return true;
+    };
+    if cors.allow_origins.is_empty() || is_listed(cors, origin) {
+        return true;
+    }
+    let host = headers.get(header::HOST).and_then(|x| x.to_str().ok());
+    let origin_host = origin
+        .to_str()
+        .ok()
+        .and_then(|x| x.split_once("://"))
+        .map(|x| x.1);
+    host.is_some() && host == origin_host
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(origin: Option<&str>, host: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, HeaderValue::from_str(host).unwrap());
+        if let Some(origin) = origin {
+            headers.insert(header::ORIGIN, HeaderValue::from_str(origin).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn lists_origins() {
+        let cors = CorsConfig {
+            allow_origins: vec!["https://example.com".to_string()],
+        };
+        assert!(is_listed(
+            &cors,
+            &HeaderValue::from_static("https://example.com")
+        ));
+        assert!(!is_listed(
+            &cors,
+            &HeaderValue::from_static("http://example.com")
+        ));
+        assert!(!is_listed(
+            &CorsConfig::default(),
+            &HeaderValue::from_static("https://example.com")
+        ));
+
+        let any = CorsConfig {
+            allow_origins: vec!["*".to_string()],
+        };
+        assert!(is_listed(
+            &any,
+            &HeaderValue::from_static("https://example.com")
+        ));
+    }
+
+    #[test]
+    fn checks_websocket_origin() {
+        let cors = CorsConfig {
+            allow_origins: vec!["https://example.com".to_string()],
+        };
+        let host = "127.0.0.1:3574";
+        assert!(is_websocket_allowed(&cors, &headers(None, host)));
+        assert!(is_websocket_allowed(
+            &cors,
+            &headers(Some("https://example.com"), host)
+        ));
+        assert!(is_websocket_allowed(
+            &cors,
+            &headers(Some("http://127.0.0.1:3574"), host)
+        ));
+        assert!(!is_websocket_allowed(
+            &cors,
+            &headers(Some("https://evil.example"), host)
+        ));
+        assert!(!is_websocket_allowed(&cors, &headers(Some("null"), host)));
+
+        // no allow-list, anything goes
+        let cors = CorsConfig::default();
+        assert!(is_websocket_allowed(
+            &cors,
+            &headers(Some("https://evil.example"), host)
+        ));
+    }
+}
@@ -0,0 +1,175 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+
+use crate::{
+    config,
+    cover::{parse_size_param, CoverError},
+};
+
+#[derive(Deserialize)]
+pub struct CoverQuery {
+    /// NetEase's resizing parameter, e.g. `200y200`
+    param: Option<String>,
+}
+
+pub async fn current_cover_handler(
+    State(state): State<crate::State>,
+    Query(query): Query<CoverQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(id) = state.music.borrow().as_ref().map(|x| x.id) else {
+        return (StatusCode::NOT_FOUND, "No music is playing").into_response();
+    };
+    // the current cover changes with the music, so clients must revalidate every time.
+    serve_cover(&state, id, query, headers, "no-cache").await
+}
+
+pub async fn cover_handler(
+    State(state): State<crate::State>,
+    Path(id): Path<i64>,
+    Query(query): Query<CoverQuery>,
+    headers: HeaderMap,
+) -> Response {
+    serve_cover(&state, id, query, headers, "public, max-age=86400").await
+}
+
+async fn serve_cover(
+    state: &crate::State,
+    id: i64,
+    query: CoverQuery,
+    headers: HeaderMap,
+    cache_control: &'static str,
+) -> Response {
+    if !config::get().integrations.cover {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    let size = match query.param.as_deref() {
+        Some(param) => {
+            let Some(size) = parse_size_param(param) else {
+                return (StatusCode::BAD_REQUEST, "Invalid param").into_response();
+            };
+            Some(size)
+        }
+        None => None,
+    };
+
+    if let Some(music) = state.music.borrow().as_ref().filter(|x| x.id == id) {
+        // the music might have just changed, make sure we know where its art is.
+        state.covers.remember(music);
+    }
+
+    let cover = match state.covers.get(id, size).await {
+        Ok(cover) => cover,
+        Err(CoverError::NotFound) => {
+            return (StatusCode::NOT_FOUND, "Unknown track").into_response();
+        }
+        Err(CoverError::Upstream(err)) => {
+            log::warn!("Unable to fetch the cover of {}: {}", id, err);
+            return (StatusCode::BAD_GATEWAY, "Unable to fetch the cover").into_response();
+        }
+        Err(CoverError::Io(err)) => {
+            log::error!("Unable to cache the cover of {}: {}", id, err);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let not_modified = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|x| x.to_str().ok())
+        .is_some_and(|x| x.split(',').any(|x| x.trim() == cover.etag));
+
+    let cache_headers = [
+        (header::ETAG, cover.etag),
+        (header::CACHE_CONTROL, cache_control.to_string()),
+    ];
+
+    if not_modified {
+        return (StatusCode::NOT_MODIFIED, cache_headers).into_response();
+    }
+
+    (
+        cache_headers,
+        [(header::CONTENT_TYPE, cover.content_type)],
+        cover.data,
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::http::HeaderValue;
+    use tokio::sync::watch;
+
+    use super::*;
+    use crate::cover::CoverCache;
+
+    fn state(dir: &std::path::Path) -> crate::State {
+        crate::State {
+            time: watch::channel(0.0).1,
+            music: watch::channel(None).1,
+            player: watch::channel(Default::default()).1,
+            instances: watch::channel(Vec::new()).1,
+            palette: watch::channel(None).1,
+            covers: Arc::new(CoverCache::new(dir.to_path_buf(), 1024 * 1024)),
+            shutdown: watch::channel(false).1,
+            connections: Arc::new(watch::Sender::new(0)),
+        }
+    }
+
+    async fn request(state: &crate::State, param: &str, etag: Option<&str>) -> Response {
+        let mut headers = HeaderMap::new();
+        if let Some(etag) = etag {
+            headers.insert(header::IF_NONE_MATCH, HeaderValue::from_str(etag).unwrap());
+        }
+        cover_handler(
+            State(state.clone()),
+            Path(42),
+            Query(CoverQuery {
+                param: Some(param.to_string()),
+            }),
+            headers,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn revalidates_with_etag() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("42_64y64"), [0xFF, 0xD8, 0xFF, 0xE0]).unwrap();
+        let state = state(dir.path());
+
+        let response = request(&state, "64y64", None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/jpeg");
+        let etag = response.headers()[header::ETAG]
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let response = request(&state, "64y64", Some(&etag)).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[header::ETAG], etag.as_str());
+
+        let response = request(&state, "64y64", Some(&format!("\"other\", {}", etag))).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        let response = request(&state, "64y64", Some("\"other\"")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_param() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state(dir.path());
+
+        let response = request(&state, "93.75y93.75", None).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
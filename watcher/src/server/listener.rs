@@ -0,0 +1,360 @@
+//! Binding the server to TCP addresses and Unix domain sockets.
+
+use std::{
+    fmt::Display,
+    future::Future,
+    net::{Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+};
+
+use axum::Router;
+use serde::{Deserialize, Deserializer};
+use socket2::{Domain, Socket, Type};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+
+use super::tls::TlsListener;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ListenAddress {
+    /// `host:port`, the host may resolve to multiple addresses.
+    Tcp(String),
+    /// `unix:/path/to/socket`
+    Unix(PathBuf),
+}
+
+impl ListenAddress {
+    /// Joins a host, which may be an IPv6 address with or without brackets, and a port.
+    pub fn from_host_port(host: &str, port: u16) -> Result<Self, String> {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.contains(':') {
+            format!("[{}]:{}", host, port).parse()
+        } else {
+            format!("{}:{}", host, port).parse()
+        }
+    }
+}
+
+impl FromStr for ListenAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err("Missing path of the Unix domain socket".to_string());
+            }
+            return Ok(ListenAddress::Unix(PathBuf::from(path)));
+        }
+        let (host, port) = if let Some(rest) = s.strip_prefix('[') {
+            let Some((host, port)) = rest.split_once("]:") else {
+                return Err(format!("Missing port in address {}", s));
+            };
+            if host.parse::<Ipv6Addr>().is_err() {
+                return Err(format!("Invalid IPv6 address in {}", s));
+            }
+            (host, port)
+        } else {
+            let Some((host, port)) = s.rsplit_once(':') else {
+                return Err(format!("Missing port in address {}", s));
+            };
+            if host.contains(':') {
+                return Err(format!(
+                    "IPv6 addresses must be enclosed in brackets, e.g. [::1]:{}",
+                    port
+                ));
+            }
+            (host, port)
+        };
+        if host.is_empty() {
+            return Err(format!("Missing host in address {}", s));
+        }
+        if port.parse::<u16>().is_err() {
+            return Err(format!("Invalid port in address {}", s));
+        }
+        Ok(ListenAddress::Tcp(s.to_string()))
+    }
+}
+
+impl<'de> Deserialize<'de> for ListenAddress {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl Display for ListenAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenAddress::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddress::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+pub enum BoundListener {
+    Tcp(TcpListener),
+    Tls(TlsListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener, PathBuf),
+}
+
+fn bind_tcp(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        // so that 0.0.0.0 and :: (or 127.0.0.1 and ::1) can be listened at the same time.
+        socket.set_only_v6(true)?;
+    }
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &std::path::Path, mode: u32) -> std::io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+        match std::os::unix::net::UnixStream::connect(path) {
+            Ok(_) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AddrInUse,
+                    format!("{} is in use by another process", path.display()),
+                ));
+            }
+            // left over from a previous run
+            Err(err) if err.kind() == std::io::ErrorKind::ConnectionRefused => {
+                std::fs::remove_file(path)?;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    // bind in a private directory and move the socket into place once its permissions are set,
+    // so it's never accessible with the permissions from the umask.
+    let Some(file_name) = path.file_name() else {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{} is not a file path", path.display()),
+        ));
+    };
+    let private_dir = path.with_file_name(format!(
+        ".{}.{}",
+        file_name.to_string_lossy(),
+        std::process::id()
+    ));
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&private_dir)?;
+    let private_path = private_dir.join(file_name);
+    let result = tokio::net::UnixListener::bind(&private_path).and_then(|listener| {
+        std::fs::set_permissions(&private_path, std::fs::Permissions::from_mode(mode))?;
+        std::fs::rename(&private_path, path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_file(&private_path);
+    let _ = std::fs::remove_dir(&private_dir);
+    result
+}
+
+/// Binds to the address, a host name is bound on every address it resolves to.
+pub async fn bind(
+    address: &ListenAddress,
+    socket_mode: u32,
+) -> std::io::Result<Vec<BoundListener>> {
+    match address {
+        ListenAddress::Tcp(addr) => {
+            let mut listeners = Vec::new();
+            for addr in tokio::net::lookup_host(addr).await? {
+                listeners.push(BoundListener::Tcp(bind_tcp(addr)?));
+            }
+            Ok(listeners)
+        }
+        #[cfg(unix)]
+        ListenAddress::Unix(path) => Ok(vec![BoundListener::Unix(
+            bind_unix(path, socket_mode)?,
+            path.clone(),
+        )]),
+        #[cfg(not(unix))]
+        ListenAddress::Unix(_) => {
+            let _ = socket_mode;
+            Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "Unix domain sockets are not supported on this platform",
+            ))
+        }
+    }
+}
+
+impl BoundListener {
+    /// Terminates TLS on TCP listeners, Unix domain sockets are left as is.
+    pub fn with_tls(self, acceptor: &TlsAcceptor) -> std::io::Result<Self> {
+        match self {
+            BoundListener::Tcp(listener) => Ok(BoundListener::Tls(TlsListener::new(
+                listener,
+                acceptor.clone(),
+            )?)),
+            listener => Ok(listener),
+        }
+    }
+
+    /// Whether only this machine can connect.
+    pub fn is_local(&self) -> bool {
+        match self {
+            BoundListener::Tcp(listener) => {
+                listener.local_addr().is_ok_and(|x| x.ip().is_loopback())
+            }
+            BoundListener::Tls(listener) => {
+                axum::serve::Listener::local_addr(listener).is_ok_and(|x| x.ip().is_loopback())
+            }
+            #[cfg(unix)]
+            BoundListener::Unix(..) => true,
+        }
+    }
+
+    pub fn local_addr(&self) -> String {
+        match self {
+            BoundListener::Tcp(listener) => listener
+                .local_addr()
+                .map(|x| x.to_string())
+                .unwrap_or_default(),
+            BoundListener::Tls(listener) => axum::serve::Listener::local_addr(listener)
+                .map(|x| format!("{} (TLS)", x))
+                .unwrap_or_default(),
+            #[cfg(unix)]
+            BoundListener::Unix(_, path) => format!("unix:{}", path.display()),
+        }
+    }
+
+    /// Serves until the shutdown future completes and the open requests are done.
+    pub async fn serve<F>(self, app: Router, shutdown: F) -> std::io::Result<()>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        match self {
+            BoundListener::Tcp(listener) => {
+                axum::serve(listener, app)
+                    .with_graceful_shutdown(shutdown)
+                    .await
+            }
+            BoundListener::Tls(listener) => {
+                axum::serve(listener, app)
+                    .with_graceful_shutdown(shutdown)
+                    .await
+            }
+            #[cfg(unix)]
+            BoundListener::Unix(listener, path) => {
+                let result = axum::serve(listener, app)
+                    .with_graceful_shutdown(shutdown)
+                    .await;
+                let _ = std::fs::remove_file(path);
+                result
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_addresses() {
+        let tcp = |x: &str| Ok(ListenAddress::Tcp(x.to_string()));
+        assert_eq!("127.0.0.1:3574".parse(), tcp("127.0.0.1:3574"));
+        assert_eq!("localhost:3574".parse(), tcp("localhost:3574"));
+        assert_eq!("[::1]:3574".parse(), tcp("[::1]:3574"));
+        assert_eq!("[::]:0".parse(), tcp("[::]:0"));
+        assert_eq!(
+            "unix:/run/watcher.sock".parse(),
+            Ok(ListenAddress::Unix(PathBuf::from("/run/watcher.sock")))
+        );
+
+        for invalid in [
+            "localhost",
+            "::1:3574",
+            "::1",
+            "[::1]",
+            "[::1]3574",
+            "[localhost]:3574",
+            "[[::1]]:3574",
+            ":3574",
+            "127.0.0.1:",
+            "127.0.0.1:65536",
+            "127.0.0.1:http",
+            "unix:",
+        ] {
+            assert!(invalid.parse::<ListenAddress>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn joins_host_and_port() {
+        let tcp = |x: &str| Ok(ListenAddress::Tcp(x.to_string()));
+        assert_eq!(
+            ListenAddress::from_host_port("127.0.0.1", 1),
+            tcp("127.0.0.1:1")
+        );
+        assert_eq!(ListenAddress::from_host_port("::1", 1), tcp("[::1]:1"));
+        assert_eq!(ListenAddress::from_host_port("[::1]", 1), tcp("[::1]:1"));
+        assert!(ListenAddress::from_host_port("localhost:80", 1).is_err());
+        assert!(ListenAddress::from_host_port("", 1).is_err());
+    }
+
+    #[test]
+    fn displays_addresses() {
+        for address in ["127.0.0.1:3574", "[::1]:3574", "unix:/run/watcher.sock"] {
+            assert_eq!(
+                address.parse::<ListenAddress>().unwrap().to_string(),
+                address
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn binds_unix_socket() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("watcher.sock");
+
+        let listener = bind_unix(&path, 0o600).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // the private directory is cleaned up
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        // a live socket is not taken over
+        let err = bind_unix(&path, 0o600).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AddrInUse);
+
+        // a stale one is replaced
+        drop(listener);
+        let _listener = bind_unix(&path, 0o660).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_to_replace_other_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("watcher.sock");
+        std::fs::write(&path, "").unwrap();
+
+        let err = bind_unix(&path, 0o600).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+        assert!(path.exists());
+    }
+}
@@ -0,0 +1,166 @@
+//! Built-in overlay pages, meant to be used as a browser source in OBS or similar software.
+
+use std::borrow::Cow;
+
+use axum::{
+    extract::{Path, Query},
+    http::StatusCode,
+    response::{Html, IntoResponse},
+};
+use serde::Deserialize;
+
+use crate::config;
+
+const SCRIPT: &str = include_str!("overlay/overlay.js");
+const BASE_STYLE: &str = include_str!("overlay/base.css");
+
+/// (name, page template)
+const THEMES: &[(&str, &str)] = &[
+    ("default", include_str!("overlay/default.html")),
+    ("compact", include_str!("overlay/compact.html")),
+    ("minimal", include_str!("overlay/minimal.html")),
+];
+
+#[derive(Deserialize, Default)]
+pub struct OverlayOptions {
+    /// background color
+    bg: Option<String>,
+    /// text color
+    fg: Option<String>,
+    /// color of the played part of the progress bar
+    accent: Option<String>,
+    /// color of the unplayed part of the progress bar
+    track: Option<String>,
+    /// width of the overlay in px, fills the page if not set
+    width: Option<u32>,
+    /// base font size in px
+    size: Option<u32>,
+    /// corner radius in px
+    radius: Option<u32>,
+    font: Option<String>,
+    /// `row` or `column`
+    layout: Option<String>,
+    /// `left`, `center` or `right`
+    align: Option<String>,
+    cover: Option<bool>,
+    artists: Option<bool>,
+    progress: Option<bool>,
+}
+
+impl OverlayOptions {
+    fn style(&self) -> String {
+        let mut vars = Vec::new();
+        let mut push = |name: &str, value: Option<String>| {
+            if let Some(value) = value {
+                vars.push(format!("--{}: {};", name, value));
+            }
+        };
+        push("bg", self.bg.as_deref().and_then(css_color));
+        push("fg", self.fg.as_deref().and_then(css_color));
+        push("accent", self.accent.as_deref().and_then(css_color));
+        push("track", self.track.as_deref().and_then(css_color));
+        push("width", self.width.map(|x| format!("{}px", x)));
+        push("size", self.size.map(|x| format!("{}px", x)));
+        push("radius", self.radius.map(|x| format!("{}px", x)));
+        push(
+            "font",
+            self.font
+                .as_deref()
+                .filter(|x| is_css_safe(x))
+                .map(|x| format!("\"{}\", sans-serif", x)),
+        );
+        format!(":root {{ {} }}", vars.join(" "))
+    }
+
+    fn body_classes(&self) -> String {
+        let mut classes = Vec::new();
+        match self.layout.as_deref() {
+            Some("column") => classes.push("layout-column"),
+            _ => classes.push("layout-row"),
+        }
+        match self.align.as_deref() {
+            Some("center") => classes.push("align-center"),
+            Some("right") => classes.push("align-right"),
+            _ => classes.push("align-left"),
+        }
+        if self.cover == Some(false) {
+            classes.push("no-cover");
+        }
+        if self.artists == Some(false) {
+            classes.push("no-artists");
+        }
+        if self.progress == Some(false) {
+            classes.push("no-progress");
+        }
+        classes.join(" ")
+    }
+}
+
+fn is_css_safe(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_alphanumeric() || " #(),.%-".contains(c))
+}
+
+/// Accepts CSS colors, and hex colors without the leading `#` since it can't be put in a URL as is.
+fn css_color(value: &str) -> Option<String> {
+    if !is_css_safe(value) {
+        return None;
+    }
+    if matches!(value.len(), 3 | 4 | 6 | 8) && value.chars().all(|c| c.is_ascii_hexdigit()) {
+        Some(format!("#{}", value))
+    } else {
+        Some(value.to_string())
+    }
+}
+
+/// Finds the template of the theme, custom templates take precedence over the built-in ones.
+fn find_template(theme: &str) -> Option<Cow<'static, str>> {
+    if let Some(dir) = config::get().overlay.templates.as_ref() {
+        // theme names must not be able to escape the template directory
+        if theme
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            if let Ok(template) = std::fs::read_to_string(dir.join(format!("{}.html", theme))) {
+                return Some(Cow::Owned(template));
+            }
+        }
+    }
+    THEMES
+        .iter()
+        .find(|(name, _)| *name == theme)
+        .map(|(_, template)| Cow::Borrowed(*template))
+}
+
+pub async fn overlay_handler(
+    Path(theme): Path<String>,
+    Query(options): Query<OverlayOptions>,
+) -> impl IntoResponse {
+    if !config::get().integrations.overlay {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    let Some(template) = find_template(&theme) else {
+        return (
+            StatusCode::NOT_FOUND,
+            format!(
+                "Unknown overlay theme, available built-in themes: {}",
+                THEMES
+                    .iter()
+                    .map(|(name, _)| *name)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        )
+            .into_response();
+    };
+    Html(
+        template
+            .replace("{{BASE_STYLE}}", BASE_STYLE)
+            .replace("{{STYLE}}", &options.style())
+            .replace("{{BODY_CLASS}}", &options.body_classes())
+            .replace("{{SCRIPT}}", SCRIPT),
+    )
+    .into_response()
+}
@@ -0,0 +1,86 @@
+(function () {
+    const $ = (selector) => document.querySelectorAll(selector);
+    const overlay = document.querySelector(".overlay");
+
+    // covers are requested in a few fixed sizes, so they are fetched and cached once for every layout.
+    const COVER_SIZES = [64, 128, 256, 512, 1024];
+
+    // forwarded to every request, as the overlay's own url is the only place to put it
+    const token = new URLSearchParams(location.search).get("token");
+
+    let music = null;
+
+    function withToken(url) {
+        if (!token) {
+            return url;
+        }
+        return url + (url.includes("?") ? "&" : "?") + "token=" + encodeURIComponent(token);
+    }
+
+    function formatTime(secs) {
+        secs = Math.max(0, Math.floor(secs));
+        const minutes = Math.floor(secs / 60);
+        const seconds = secs % 60;
+        return String(minutes).padStart(2, "0") + ":" + String(seconds).padStart(2, "0");
+    }
+
+    function coverSize(el) {
+        const size = Math.ceil(Math.max(el.clientWidth, el.clientHeight, 64) * window.devicePixelRatio);
+        return COVER_SIZES.find((x) => x >= size) || COVER_SIZES[COVER_SIZES.length - 1];
+    }
+
+    function setText(selector, text) {
+        $(selector).forEach((el) => (el.textContent = text));
+    }
+
+    function updateMusic(value) {
+        music = value;
+        overlay.classList.toggle("idle", !music);
+        if (!music) {
+            return;
+        }
+        setText(".name", music.name);
+        setText(".aliases", music.aliases ? music.aliases.join(" / ") : "");
+        setText(".artists", music.artists.join(", "));
+        setText(".duration", formatTime(music.duration / 1000));
+        $(".cover").forEach((el) => {
+            const size = coverSize(el);
+            el.src = withToken("/cover/" + music.id + "?param=" + size + "y" + size);
+        });
+    }
+
+    function updateTime(time) {
+        if (time < 0) {
+            // no process attached
+            updateMusic(null);
+            return;
+        }
+        setText(".time", formatTime(time));
+        const progress = music && music.duration > 0 ? Math.min(1, time / (music.duration / 1000)) : 0;
+        $(".progress-bar").forEach((el) => (el.style.width = progress * 100 + "%"));
+    }
+
+    function connect() {
+        const protocol = location.protocol === "https:" ? "wss:" : "ws:";
+        const socket = new WebSocket(protocol + "//" + location.host + withToken("/ws"));
+        socket.onmessage = (event) => {
+            const data = JSON.parse(event.data);
+            if (data.type === "musicchange") {
+                updateMusic(data.value);
+            } else if (data.type === "timechange") {
+                updateTime(data.value);
+            }
+        };
+        socket.onclose = () => setTimeout(connect, 3000);
+    }
+
+    updateMusic(null);
+    fetch(withToken("/"))
+        .then((res) => res.json())
+        .then((data) => {
+            updateMusic(data.music);
+            updateTime(data.time);
+        })
+        .catch(() => {})
+        .finally(connect);
+})();
@@ -0,0 +1,257 @@
+//! TLS termination for the TCP listeners.
+
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+use tokio_rustls::{
+    rustls::{
+        self,
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    },
+    server::TlsStream,
+    TlsAcceptor,
+};
+
+use crate::config::{self, TlsConfig};
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Names the self-signed certificate is valid for, in addition to the configured ones.
+const SELF_SIGNED_NAMES: &[&str] = &["localhost", "127.0.0.1", "::1"];
+
+pub fn self_signed_paths() -> Option<(PathBuf, PathBuf)> {
+    let dir = config::default_path()?.parent()?.join("tls");
+    Some((dir.join("localhost.crt"), dir.join("localhost.key")))
+}
+
+/// Creates a self-signed certificate, unless one for the same names already exists.
+///
+/// Returns the paths of the certificate and the private key.
+pub fn ensure_self_signed(hostnames: &[String], force: bool) -> Result<(PathBuf, PathBuf), String> {
+    let Some((cert_path, key_path)) = self_signed_paths() else {
+        return Err("Unable to find the configuration directory".to_string());
+    };
+    ensure_self_signed_at(&cert_path, &key_path, hostnames, force)?;
+    Ok((cert_path, key_path))
+}
+
+fn ensure_self_signed_at(
+    cert_path: &Path,
+    key_path: &Path,
+    hostnames: &[String],
+    force: bool,
+) -> Result<(), String> {
+    // the names the certificate was generated for, one per line
+    let names_path = cert_path.with_extension("names");
+
+    let names: Vec<String> = SELF_SIGNED_NAMES
+        .iter()
+        .map(|x| x.to_string())
+        .chain(hostnames.iter().cloned())
+        .collect();
+    let names_content = names.join("\n");
+
+    if !force
+        && cert_path.exists()
+        && key_path.exists()
+        && std::fs::read_to_string(&names_path).is_ok_and(|x| x == names_content)
+    {
+        return Ok(());
+    }
+
+    let certified = rcgen::generate_simple_self_signed(names).map_err(|x| x.to_string())?;
+
+    if let Some(dir) = cert_path.parent() {
+        std::fs::create_dir_all(dir).map_err(|x| x.to_string())?;
+    }
+    write_private(key_path, certified.signing_key.serialize_pem().as_bytes())
+        .map_err(|x| x.to_string())?;
+    std::fs::write(cert_path, certified.cert.pem()).map_err(|x| x.to_string())?;
+    std::fs::write(&names_path, names_content).map_err(|x| x.to_string())?;
+
+    log::info!(
+        "Created a self-signed certificate at {}",
+        cert_path.display()
+    );
+    Ok(())
+}
+
+/// Writes a file only the current user can read.
+fn write_private(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::{io::Write, os::unix::fs::OpenOptionsExt, os::unix::fs::PermissionsExt};
+
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)?;
+        // the mode only applies to new files
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+        file.write_all(content)
+    }
+    #[cfg(not(unix))]
+    {
+        std::fs::write(path, content)
+    }
+}
+
+/// Loads the certificate for TLS, returns `None` if TLS is disabled.
+pub fn load_acceptor(config: &TlsConfig) -> Result<Option<TlsAcceptor>, String> {
+    let (cert_path, key_path) = match (&config.cert, &config.key) {
+        (Some(cert), Some(key)) => (cert.clone(), key.clone()),
+        (Some(_), None) | (None, Some(_)) => {
+            return Err("Both server.tls.cert and server.tls.key must be set".to_string());
+        }
+        (None, None) if config.self_signed => ensure_self_signed(&config.hostnames, false)?,
+        (None, None) => return Ok(None),
+    };
+
+    let certs = CertificateDer::pem_file_iter(&cert_path)
+        .and_then(|x| x.collect::<Result<Vec<_>, _>>())
+        .map_err(|x| format!("Unable to read {}: {}", cert_path.display(), x))?;
+    let key = PrivateKeyDer::from_pem_file(&key_path)
+        .map_err(|x| format!("Unable to read {}: {}", key_path.display(), x))?;
+
+    let mut server_config = rustls::ServerConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .map_err(|x| x.to_string())?
+    .with_no_client_auth()
+    .with_single_cert(certs, key)
+    .map_err(|x| x.to_string())?;
+    server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    Ok(Some(TlsAcceptor::from(Arc::new(server_config))))
+}
+
+/// Accepts TCP connections and does the TLS handshakes in the background,
+/// so that a slow client can't block the others.
+pub struct TlsListener {
+    rx: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+    local_addr: SocketAddr,
+}
+
+impl TlsListener {
+    pub fn new(listener: TcpListener, acceptor: TlsAcceptor) -> std::io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let (tx, rx) = mpsc::channel(64);
+        tokio::spawn(async move {
+            loop {
+                let (stream, addr) = tokio::select! {
+                    _ = tx.closed() => break,
+                    accepted = listener.accept() => match accepted {
+                        Ok(accepted) => accepted,
+                        Err(err) => {
+                            log::debug!("Unable to accept connection: {}", err);
+                            tokio::time::sleep(Duration::from_millis(100)).await;
+                            continue;
+                        }
+                    },
+                };
+                let acceptor = acceptor.clone();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => {
+                            let _ = tx.send((stream, addr)).await;
+                        }
+                        Ok(Err(err)) => log::debug!("TLS handshake with {} failed: {}", addr, err),
+                        Err(_) => log::debug!("TLS handshake with {} timed out", addr),
+                    }
+                });
+            }
+        });
+        Ok(TlsListener { rx, local_addr })
+    }
+}
+
+impl axum::serve::Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.rx.recv().await {
+            Some(accepted) => accepted,
+            // the accepting task never stops while we are alive
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generates_and_reuses_self_signed() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert = dir.path().join("tls").join("localhost.crt");
+        let key = dir.path().join("tls").join("localhost.key");
+
+        ensure_self_signed_at(&cert, &key, &[], false).unwrap();
+        let first = std::fs::read(&cert).unwrap();
+
+        // same names, reused
+        ensure_self_signed_at(&cert, &key, &[], false).unwrap();
+        assert_eq!(std::fs::read(&cert).unwrap(), first);
+
+        // new names, regenerated
+        let hostnames = vec!["my-pc.local".to_string()];
+        ensure_self_signed_at(&cert, &key, &hostnames, false).unwrap();
+        let second = std::fs::read(&cert).unwrap();
+        assert_ne!(second, first);
+
+        ensure_self_signed_at(&cert, &key, &hostnames, true).unwrap();
+        assert_ne!(std::fs::read(&cert).unwrap(), second);
+
+        let acceptor = load_acceptor(&TlsConfig {
+            cert: Some(cert),
+            key: Some(key),
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(acceptor.is_some());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn restricts_key_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let key = dir.path().join("localhost.key");
+        std::fs::write(&key, "").unwrap();
+        std::fs::set_permissions(&key, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        write_private(&key, b"secret").unwrap();
+        let mode = std::fs::metadata(&key).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(std::fs::read(&key).unwrap(), b"secret");
+    }
+
+    #[test]
+    fn loads_nothing_when_disabled() {
+        assert!(load_acceptor(&TlsConfig::default()).unwrap().is_none());
+        assert!(load_acceptor(&TlsConfig {
+            cert: Some(PathBuf::from("cert.pem")),
+            ..Default::default()
+        })
+        .is_err());
+    }
+}
@@ -0,0 +1,521 @@
+//! Signatures locating the variables of Netease Cloud Music in cloudmusic.dll.
+//!
+//! The built-in database can be extended by the files in `watcher.signatures`, whose entries take
+//! precedence, so a new client release can be supported without a new release of the watcher.
+
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    fmt::Display,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use serde::Deserialize;
+
+use crate::{pe, util::find_pattern, PlayMode};
+
+const BUILTIN: &str = include_str!("signature/builtin.toml");
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Arch {
+    X86,
+    X64,
+}
+
+impl Arch {
+    pub fn of(is_64_bit: bool) -> Self {
+        if is_64_bit {
+            Arch::X64
+        } else {
+            Arch::X86
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Addressing {
+    /// the operand is the address
+    Absolute,
+    /// the operand is relative to the end of the instruction
+    Rip,
+}
+
+/// What a signature locates.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Variable {
+    /// the playback time in seconds
+    #[default]
+    Time,
+    Volume,
+    Muted,
+    PlayMode,
+    /// whether the playback is paused
+    Paused,
+}
+
+impl Variable {
+    pub const ALL: [Variable; 5] = [
+        Variable::Time,
+        Variable::Volume,
+        Variable::Muted,
+        Variable::PlayMode,
+        Variable::Paused,
+    ];
+}
+
+impl Display for Variable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Variable::Time => "time",
+            Variable::Volume => "volume",
+            Variable::Muted => "muted",
+            Variable::PlayMode => "play_mode",
+            Variable::Paused => "paused",
+        })
+    }
+}
+
+/// How a variable is stored.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ValueType {
+    #[default]
+    F64,
+    F32,
+    I32,
+    U8,
+}
+
+impl ValueType {
+    pub fn size(self) -> usize {
+        match self {
+            ValueType::F64 => 8,
+            ValueType::F32 | ValueType::I32 => 4,
+            ValueType::U8 => 1,
+        }
+    }
+
+    pub fn decode(self, buf: &[u8]) -> Option<f64> {
+        let buf = buf.get(..self.size())?;
+        Some(match self {
+            ValueType::F64 => f64::from_le_bytes(buf.try_into().ok()?),
+            ValueType::F32 => f32::from_le_bytes(buf.try_into().ok()?) as f64,
+            ValueType::I32 => i32::from_le_bytes(buf.try_into().ok()?) as f64,
+            ValueType::U8 => buf[0] as f64,
+        })
+    }
+}
+
+/// A client version like `3.0.18.203152`, missing parts count as zero.
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct Version(Vec<u32>);
+
+impl Version {
+    fn part(&self, index: usize) -> u32 {
+        self.0.get(index).copied().unwrap_or(0)
+    }
+
+    fn compare(&self, other: &Version, len: usize) -> Ordering {
+        (0..len)
+            .map(|index| self.part(index).cmp(&other.part(index)))
+            .find(|x| x.is_ne())
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+impl FromStr for Version {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split('.')
+            .map(|x| x.parse().map_err(|_| format!("Invalid version {}", s)))
+            .collect::<Result<_, _>>()
+            .map(Version)
+    }
+}
+
+impl TryFrom<String> for Version {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<[u16; 4]> for Version {
+    fn from(value: [u16; 4]) -> Self {
+        Version(value.iter().map(|x| *x as u32).collect())
+    }
+}
+
+impl Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let parts: Vec<_> = self.0.iter().map(|x| x.to_string()).collect();
+        write!(f, "{}", parts.join("."))
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Signature {
+    pub name: String,
+    pub arch: Arch,
+    /// bytes in hex, `??` for any byte
+    pub pattern: String,
+    /// where the operand is, from the start of the match
+    pub operand_offset: usize,
+    pub addressing: Addressing,
+    /// length of the instruction, for RIP-relative addressing
+    #[serde(default)]
+    pub instruction_len: usize,
+    pub min_version: Option<Version>,
+    /// matches every version starting with it, `3.0` includes `3.0.18`
+    pub max_version: Option<Version>,
+    #[serde(default)]
+    pub variable: Variable,
+    #[serde(default, rename = "type")]
+    pub value_type: ValueType,
+    /// the value at full volume, 1 if not given
+    pub volume_max: Option<f64>,
+    /// the value of each play mode
+    #[serde(default)]
+    pub play_modes: HashMap<PlayMode, i64>,
+}
+
+impl Signature {
+    fn validate(&self) -> Result<(), String> {
+        let tokens: Vec<_> = self.pattern.split_whitespace().collect();
+        if tokens.is_empty()
+            || !tokens
+                .iter()
+                .all(|x| *x == "??" || (x.len() == 2 && u8::from_str_radix(x, 16).is_ok()))
+        {
+            return Err(format!("{}: invalid pattern", self.name));
+        }
+        if self.operand_offset + 4 > tokens.len() {
+            return Err(format!(
+                "{}: the operand is outside of the pattern",
+                self.name
+            ));
+        }
+        if self.addressing == Addressing::Rip && self.instruction_len == 0 {
+            return Err(format!(
+                "{}: instruction_len is required for RIP-relative addressing",
+                self.name
+            ));
+        }
+        if self.variable == Variable::Time && self.value_type != ValueType::F64 {
+            return Err(format!("{}: the playback time is a f64", self.name));
+        }
+        if self.volume_max.is_some_and(|x| x <= 0.)
+            || self.volume_max.is_some() && self.variable != Variable::Volume
+        {
+            return Err(format!(
+                "{}: volume_max is only for the volume, and must be positive",
+                self.name
+            ));
+        }
+        if self.play_modes.is_empty() == (self.variable == Variable::PlayMode) {
+            return Err(format!(
+                "{}: play_modes is required for the play mode, and only for it",
+                self.name
+            ));
+        }
+        Ok(())
+    }
+
+    /// Whether the signature applies to the version, all of them do if it's unknown.
+    pub fn supports(&self, version: Option<&Version>) -> bool {
+        let Some(version) = version else {
+            return true;
+        };
+        self.min_version.as_ref().is_none_or(|min| {
+            version
+                .compare(min, version.0.len().max(min.0.len()))
+                .is_ge()
+        }) && self
+            .max_version
+            .as_ref()
+            .is_none_or(|max| version.compare(max, max.0.len()).is_le())
+    }
+
+    /// Number of bytes a match spans.
+    pub fn pattern_len(&self) -> usize {
+        self.pattern.split_whitespace().count()
+    }
+
+    /// Offsets of the matches in the buffer.
+    pub fn find(&self, buf: &[u8]) -> Vec<usize> {
+        find_pattern(&self.pattern, buf)
+    }
+
+    /// The address the match at the offset refers to, `base` being the address of the buffer.
+    pub fn resolve(&self, buf: &[u8], offset: usize, base: usize) -> Option<usize> {
+        let start = offset + self.operand_offset;
+        let operand = i32::from_le_bytes(buf.get(start..start + 4)?.try_into().ok()?);
+        Some(match self.addressing {
+            Addressing::Absolute => operand as u32 as usize,
+            Addressing::Rip => {
+                (base + offset + self.instruction_len).wrapping_add(operand as isize as usize)
+            }
+        })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SignatureFile {
+    #[serde(default)]
+    signatures: Vec<Signature>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SignatureDb {
+    signatures: Vec<Signature>,
+}
+
+impl SignatureDb {
+    fn parse(content: &str) -> Result<Self, String> {
+        let file: SignatureFile = toml::from_str(content).map_err(|err| err.to_string())?;
+        for signature in &file.signatures {
+            signature.validate()?;
+        }
+        Ok(SignatureDb {
+            signatures: file.signatures,
+        })
+    }
+
+    pub fn builtin() -> Self {
+        Self::parse(BUILTIN).expect("the built-in signatures are valid")
+    }
+
+    /// Puts the signatures of the other database before ours, replacing ours of the same name.
+    fn extend(&mut self, other: SignatureDb) {
+        self.signatures
+            .retain(|x| !other.signatures.iter().any(|y| y.name == x.name));
+        let mut signatures = other.signatures;
+        signatures.append(&mut self.signatures);
+        self.signatures = signatures;
+    }
+
+    /// The built-in signatures and the ones in the files, later files taking precedence.
+    ///
+    /// Files that can't be loaded are skipped, and returned with the reason.
+    pub fn load(paths: &[PathBuf]) -> (Self, Vec<String>) {
+        let mut db = Self::builtin();
+        let mut errors = Vec::new();
+        for path in paths {
+            match std::fs::read_to_string(path)
+                .map_err(|err| err.to_string())
+                .and_then(|x| Self::parse(&x))
+            {
+                Ok(file) => db.extend(file),
+                Err(err) => errors.push(format!(
+                    "Unable to load signatures from {}: {}",
+                    path.display(),
+                    err
+                )),
+            }
+        }
+        (db, errors)
+    }
+
+    /// The signatures to try for the client, in order.
+    pub fn select(&self, arch: Arch, version: Option<&Version>) -> Vec<&Signature> {
+        self.signatures
+            .iter()
+            .filter(|x| x.arch == arch && x.supports(version))
+            .collect()
+    }
+}
+
+/// The version of the DLL at the path, from its version resource.
+pub fn version_of(path: &Path) -> Option<Version> {
+    let file = std::fs::read(path)
+        .inspect_err(|err| log::debug!("Unable to read {}: {}", path.display(), err))
+        .ok()?;
+    let image = pe::parse(&file).ok()?;
+    image.file_version(&file).map(Version::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signature(name: &str, arch: Arch, min: Option<&str>, max: Option<&str>) -> Signature {
+        Signature {
+            name: name.to_string(),
+            arch,
+            pattern: "f2 0f 11 3d ?? ?? ?? ??".to_string(),
+            operand_offset: 4,
+            addressing: Addressing::Rip,
+            instruction_len: 8,
+            min_version: min.map(|x| x.parse().unwrap()),
+            max_version: max.map(|x| x.parse().unwrap()),
+            variable: Variable::Time,
+            value_type: ValueType::F64,
+            volume_max: None,
+            play_modes: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn parses_builtin() {
+        let db = SignatureDb::builtin();
+        assert_eq!(db.select(Arch::X64, None).len(), 1);
+        assert_eq!(db.select(Arch::X86, None).len(), 1);
+    }
+
+    #[test]
+    fn rejects_invalid_signatures() {
+        let valid = r#"
+            name = "test"
+            arch = "x64"
+            operand_offset = 4
+            addressing = "rip"
+            instruction_len = 8
+        "#;
+        for entry in [
+            r#"pattern = "f2 0f 11 3d ?? ?? ?? ?? 0""#,
+            r#"pattern = "f2 0f 11 3d ?? ?? ?? ?? zz""#,
+            r#"pattern = "f2 0f 11 3d ?? ?? ??""#,
+            r#"pattern = """#,
+        ] {
+            let content = format!("[[signatures]]\n{}\n{}", valid, entry);
+            assert!(SignatureDb::parse(&content).is_err(), "{}", entry);
+        }
+        let content = format!(
+            "[[signatures]]\n{}\npattern = \"f2 0f 11 3d ?? ?? ?? ??\"",
+            valid
+        );
+        assert!(SignatureDb::parse(&content).is_ok());
+        assert!(SignatureDb::parse(&content.replace("instruction_len = 8", "")).is_err());
+        assert!(SignatureDb::parse(&content.replace("x64", "arm64")).is_err());
+
+        for (extra, valid) in [
+            ("type = \"f32\"", false),
+            (
+                "variable = \"volume\"\ntype = \"f32\"\nvolume_max = 100.0",
+                true,
+            ),
+            ("variable = \"volume\"\nvolume_max = 0.0", false),
+            ("volume_max = 1.0", false),
+            ("variable = \"muted\"\ntype = \"u8\"", true),
+            ("variable = \"play_mode\"\ntype = \"i32\"", false),
+            (
+                "variable = \"play_mode\"\nplay_modes = { order = 0, shuffle = 3 }",
+                true,
+            ),
+            ("variable = \"paused\"\nplay_modes = { order = 0 }", false),
+            ("variable = \"speed\"", false),
+        ] {
+            let content = format!("{}\n{}", content, extra);
+            assert_eq!(SignatureDb::parse(&content).is_ok(), valid, "{}", extra);
+        }
+    }
+
+    #[test]
+    fn decodes_values() {
+        let buf = 1.5f64.to_le_bytes();
+        assert_eq!(ValueType::F64.decode(&buf), Some(1.5));
+        assert_eq!(ValueType::F64.decode(&buf[..4]), None);
+        assert_eq!(ValueType::F32.decode(&0.25f32.to_le_bytes()), Some(0.25));
+        assert_eq!(ValueType::I32.decode(&(-3i32).to_le_bytes()), Some(-3.));
+        assert_eq!(ValueType::U8.decode(&[1, 2]), Some(1.));
+    }
+
+    #[test]
+    fn matches_versions() {
+        let version = |x: &str| x.parse::<Version>().unwrap();
+        let bounded = signature("a", Arch::X64, Some("3.0.10"), Some("3.0"));
+        assert!(bounded.supports(Some(&version("3.0.10"))));
+        assert!(bounded.supports(Some(&version("3.0.18.203152"))));
+        assert!(!bounded.supports(Some(&version("3.0.9.1"))));
+        assert!(!bounded.supports(Some(&version("3.1.0"))));
+        assert!(!bounded.supports(Some(&version("2.10.13"))));
+        assert!(bounded.supports(None));
+
+        let open = signature("b", Arch::X64, None, None);
+        assert!(open.supports(Some(&version("1.0"))));
+
+        assert_eq!(Version::from([3, 0, 18, 2031]).to_string(), "3.0.18.2031");
+        assert!("3.x".parse::<Version>().is_err());
+    }
+
+    #[test]
+    fn overrides_builtin() {
+        let mut db = SignatureDb {
+            signatures: vec![
+                signature("time-x64", Arch::X64, None, None),
+                signature("time-x86", Arch::X86, None, None),
+            ],
+        };
+        let mut user = signature("time-x64", Arch::X64, Some("3.1"), None);
+        user.operand_offset = 0;
+        db.extend(SignatureDb {
+            signatures: vec![signature("new", Arch::X64, None, None), user.clone()],
+        });
+
+        let names: Vec<_> = db.signatures.iter().map(|x| x.name.as_str()).collect();
+        assert_eq!(names, ["new", "time-x64", "time-x86"]);
+        assert_eq!(
+            db.select(Arch::X64, Some(&"3.0".parse().unwrap())),
+            [&db.signatures[0]]
+        );
+        assert_eq!(db.select(Arch::X64, None)[1], &user);
+    }
+
+    #[test]
+    fn loads_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("signatures.toml");
+        std::fs::write(
+            &path,
+            r#"
+            [[signatures]]
+            name = "time-x86"
+            arch = "x86"
+            pattern = "f2 0f 11 05 ?? ?? ?? ??"
+            operand_offset = 4
+            addressing = "absolute"
+            min_version = "3.1"
+            "#,
+        )
+        .unwrap();
+        let (db, errors) = SignatureDb::load(&[path, dir.path().join("missing.toml")]);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            db.select(Arch::X86, None)[0].pattern,
+            "f2 0f 11 05 ?? ?? ?? ??"
+        );
+        assert!(db
+            .select(Arch::X86, Some(&"3.0".parse().unwrap()))
+            .is_empty());
+        assert_eq!(db.select(Arch::X64, None).len(), 1);
+    }
+
+    #[test]
+    fn resolves_operands() {
+        let buf = [
+            0x90, 0xf2, 0x0f, 0x11, 0x3d, 0x00, 0x01, 0x00, 0x00, 0xf2, 0x0f, 0x11, 0x3d, 0xf0,
+            0xff, 0xff, 0xff,
+        ];
+        let rip = signature("rip", Arch::X64, None, None);
+        assert_eq!(rip.find(&buf), [1, 9]);
+        assert_eq!(rip.resolve(&buf, 1, 0x1000), Some(0x1000 + 1 + 8 + 0x100));
+        assert_eq!(rip.resolve(&buf, 9, 0x1000), Some(0x1000 + 9 + 8 - 0x10));
+        // the operand is cut off
+        assert_eq!(rip.resolve(&buf, 14, 0x1000), None);
+
+        let absolute = Signature {
+            addressing: Addressing::Absolute,
+            ..rip
+        };
+        assert_eq!(absolute.resolve(&buf, 9, 0x1000), Some(0xffff_fff0));
+    }
+}
@@ -156,7 +156,7 @@ fn scroll_up(state: &mut State) {
     state.log_scroll_state = state.log_scroll_state.position(state.log_scroll);
 }
 
-pub async fn run(endpoint: String) {
+pub async fn run(endpoint: String, mut shutdown: tokio::sync::watch::Receiver<bool>) {
     let mut state = State {
         endpoint: &endpoint,
         log_scroll_state: Default::default(),
@@ -219,10 +219,8 @@ pub async fn run(endpoint: String) {
                     }
                     Event::Mouse(MouseEvent { kind, column, row, modifiers: _ }) => {
                         match kind {
-                            MouseEventKind::Down(event::MouseButton::Left) => {
-                                if util::in_rect(rendered_state.exit_button_area, column, row) {
-                                    exit_btn_hold = true;
-                                }
+                            MouseEventKind::Down(event::MouseButton::Left) if util::in_rect(rendered_state.exit_button_area, column, row) => {
+                                exit_btn_hold = true;
                             }
                             MouseEventKind::Up(event::MouseButton::Left) => {
                                 if util::in_rect(rendered_state.exit_button_area, column, row) && exit_btn_hold {
@@ -239,16 +237,17 @@ pub async fn run(endpoint: String) {
                             _ => {}
                         }
                     }
-                    Event::Resize(_w, _h) => {
-                        if state.log_scroll >= rendered_state.total_log_lines {
-                            state.log_scroll = rendered_state.total_log_lines.saturating_sub(1);
-                            state.log_scroll_state = state.log_scroll_state.position(state.log_scroll);
-                        }
+                    Event::Resize(_w, _h) if state.log_scroll >= rendered_state.total_log_lines => {
+                        state.log_scroll = rendered_state.total_log_lines.saturating_sub(1);
+                        state.log_scroll_state = state.log_scroll_state.position(state.log_scroll);
                     }
                     _ => {}
                 }
             }
             _ = notify.notified() => {}
+            _ = shutdown.wait_for(|x| *x) => {
+                break;
+            }
         };
     }
     let _ = crossterm::execute!(std::io::stdout(), crossterm::event::DisableMouseCapture);
@@ -40,19 +40,18 @@ pub fn render_header(frame: &mut Frame, endpoint: &String, rect: Rect) {
             Span::raw(" or any other for HTTP"),
         ])
         .light_magenta(),
-        if next_find_time.is_none() {
-            Line::raw("Found Netease Cloud Music").green()
-        } else {
+        if let Some(next_find_time) = next_find_time {
             Line::raw(format!(
                 "Next try to find Cloud Music in {:.1} secs",
                 next_find_time
-                    .unwrap()
                     .checked_duration_since(Instant::now())
                     .unwrap_or(Duration::ZERO)
                     .as_secs_f32()
             ))
             .red()
             .bold()
+        } else {
+            Line::raw("Found Netease Cloud Music").green()
         },
         Line::raw(format!("v{} by YUCLing@GitHub", env!("CARGO_PKG_VERSION"))).black(),
     ])
@@ -1,6 +1,4 @@
-// 32-bit uses absolute addressing, while 64-bit uses RIP-relative addressing.
-pub const MOVSD_PATTERN_64: &str = "f2 0f 11 3d ?? ?? ?? ?? f2 0f 11 35"; // MOVSD [offset], XMM7 & MOVSD [offset], XMM6
-pub const MOVSD_PATTERN_32: &str = "f2 0f 11 0d ?? ?? ?? ?? 68"; // MOVSD [offset], XMM1 & PUSH [offset]
+use lightningscanner::Scanner;
 
 pub fn is_64_bit_dll(dll_header: &[u8]) -> Result<bool, ()> {
     // Check if the DLL is 64-bit by looking at the PE header.
@@ -10,7 +8,7 @@ pub fn is_64_bit_dll(dll_header: &[u8]) -> Result<bool, ()> {
     }
 
     let pe_offset = u32::from_le_bytes(dll_header[0x3C..0x40].try_into().unwrap()) as usize;
-    if pe_offset + 4 > dll_header.len() {
+    if pe_offset + 6 > dll_header.len() {
         return Err(()); // Invalid PE offset
     }
 
@@ -25,14 +23,29 @@ pub fn is_64_bit_dll(dll_header: &[u8]) -> Result<bool, ()> {
     Ok(machine_type == 0x8664) // IMAGE_FILE_MACHINE_AMD64
 }
 
-pub fn extract_addr_from_instruction(buf: &[u8], relative_addr: usize) -> usize {
-    let offset_bytes = &buf[relative_addr + 4..relative_addr + 8];
-    let offset = i32::from_le_bytes([
-        offset_bytes[0],
-        offset_bytes[1],
-        offset_bytes[2],
-        offset_bytes[3],
-    ]) as isize;
+/// Offsets of every complete match of the pattern in the buffer.
+pub fn find_pattern(pattern: &str, buf: &[u8]) -> Vec<usize> {
+    let scanner = Scanner::new(pattern);
+    let pattern_len = pattern.split_whitespace().count();
+    let mut result = Vec::new();
+    let mut start = 0;
+    while start < buf.len() {
+        let rest = &buf[start..];
+        let addr = unsafe { scanner.find(None, rest.as_ptr(), rest.len()) }.get_addr() as usize;
+        if addr == 0 {
+            break;
+        }
+        let offset = start + (addr - rest.as_ptr() as usize);
+        // matches are only checked up to the end of the buffer
+        if offset + pattern_len > buf.len() {
+            break;
+        }
+        result.push(offset);
+        start = offset + 1;
+    }
+    result
+}
 
-    return offset as usize;
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{:02x}", x)).collect()
 }